@@ -1,4 +1,4 @@
-use crate::util::err;
+use crate::util::{as_integer, err};
 use evalexpr::{EvalexprError, EvalexprResult, Value};
 
 /// `index` retrieves tuple values based on the given index.
@@ -8,7 +8,7 @@ use evalexpr::{EvalexprError, EvalexprResult, Value};
 pub(crate) fn index(argument: &Value) -> EvalexprResult<Value> {
     let args = argument.as_tuple()?;
     if let [target, index] = &args[..] {
-        let index = index.as_int()?;
+        let index = as_integer(index)?;
         return target
             .as_tuple()?
             .get(index as usize)
@@ -1,14 +1,15 @@
 mod idx;
+mod registry;
+mod series;
 pub mod util;
 mod vdiv;
 
-use evalexpr::{EvalexprError, EvalexprResult, Value};
+pub use registry::{Builtins, Function, FunctionRegistry, Plugin};
+
+use evalexpr::{EvalexprResult, Value};
 
 /// Matching function for all custom functions provided by the kicad_rs evaluator
+#[deprecated(note = "use `FunctionRegistry::with_builtins` and `FunctionRegistry::call` instead")]
 pub fn call_function(identifier: &str, argument: &Value) -> EvalexprResult<Value> {
-    match identifier {
-        "idx" => idx::index(argument),
-        "vdiv" => vdiv::voltage_divider(argument),
-        other => Err(EvalexprError::FunctionIdentifierNotFound(other.into())),
-    }
+    FunctionRegistry::with_builtins().call(identifier, argument)
 }
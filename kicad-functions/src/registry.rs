@@ -0,0 +1,310 @@
+use crate::idx;
+use crate::util::err;
+use crate::vdiv;
+use evalexpr::{Context, EvalexprError, EvalexprResult, Node, Value};
+use std::collections::HashMap;
+use std::fmt;
+
+/// `Function` is implemented by all functions that can be called from expressions.
+/// Any `Fn(&Value) -> EvalexprResult<Value>` closure or function implements it.
+pub trait Function {
+    fn call(&self, argument: &Value) -> EvalexprResult<Value>;
+}
+
+impl<F> Function for F
+where
+    F: Fn(&Value) -> EvalexprResult<Value>,
+{
+    fn call(&self, argument: &Value) -> EvalexprResult<Value> {
+        self(argument)
+    }
+}
+
+/// `Plugin` is implemented by providers of function sets, e.g. domain-specific
+/// functions living in a separate crate, which register them into a `FunctionRegistry`.
+pub trait Plugin {
+    fn register(&self, registry: &mut FunctionRegistry) -> EvalexprResult<()>;
+}
+
+/// `Builtins` is the plugin providing the functions that are built into kicad_rs
+pub struct Builtins;
+
+impl Plugin for Builtins {
+    fn register(&self, registry: &mut FunctionRegistry) -> EvalexprResult<()> {
+        registry.register("idx", idx::index)?;
+        registry.register("vdiv", vdiv::voltage_divider)?;
+        registry.register("vdiv_top", vdiv::voltage_divider_top)?;
+        Ok(())
+    }
+}
+
+enum Definition {
+    Native(Box<dyn Function>),
+    Macro(ExpressionMacro),
+}
+
+/// `FunctionRegistry` holds all functions available to the evaluator, keyed by their identifier.
+/// Identifiers are unique, registering the same identifier twice is an error.
+#[derive(Default)]
+pub struct FunctionRegistry {
+    functions: HashMap<String, Definition>,
+}
+
+impl FunctionRegistry {
+    /// Creates an empty registry, without any built-in functions
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Creates a registry populated with the built-in functions
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::new();
+        // The built-in functions have unique identifiers, this cannot fail
+        registry.register_plugin(&Builtins).unwrap();
+        registry
+    }
+
+    /// Registers a native function under the given identifier
+    pub fn register<F>(&mut self, identifier: &str, function: F) -> EvalexprResult<()>
+    where
+        F: Function + 'static,
+    {
+        self.insert(identifier, Definition::Native(Box::new(function)))
+    }
+
+    /// Registers all functions provided by the given plugin
+    pub fn register_plugin<P: Plugin>(&mut self, plugin: &P) -> EvalexprResult<()> {
+        plugin.register(self)
+    }
+
+    /// Registers an expression macro given its definition, see `ExpressionMacro::parse`.
+    /// Macros may only call functions that have been registered before them, which
+    /// rules out (mutually) recursive definitions.
+    pub fn register_macro(&mut self, definition: &str) -> EvalexprResult<()> {
+        let m = ExpressionMacro::parse(definition)?;
+        for f in m.body.iter_function_identifiers() {
+            if !self.functions.contains_key(f) && !is_builtin(f) {
+                return err(&format!("{}: unknown function: {}", m.name, f));
+            }
+        }
+
+        self.insert(&m.name.clone(), Definition::Macro(m))
+    }
+
+    /// Returns true if a function with the given identifier has been registered
+    pub fn contains(&self, identifier: &str) -> bool {
+        self.functions.contains_key(identifier)
+    }
+
+    /// Returns the identifiers of all registered functions in sorted order
+    pub fn identifiers(&self) -> Vec<&str> {
+        let mut identifiers: Vec<&str> = self.functions.keys().map(|s| s.as_str()).collect();
+        identifiers.sort_unstable();
+        identifiers
+    }
+
+    /// Calls the function registered under the given identifier
+    pub fn call(&self, identifier: &str, argument: &Value) -> EvalexprResult<Value> {
+        match self.functions.get(identifier) {
+            Some(Definition::Native(f)) => f.call(argument),
+            Some(Definition::Macro(m)) => m.call(self, argument),
+            None => Err(EvalexprError::FunctionIdentifierNotFound(identifier.into())),
+        }
+    }
+
+    fn insert(&mut self, identifier: &str, definition: Definition) -> EvalexprResult<()> {
+        if !is_identifier(identifier) {
+            return err(&format!("invalid function identifier: {}", identifier));
+        }
+
+        if self.functions.contains_key(identifier) {
+            return err(&format!("duplicate function definition: {}", identifier));
+        }
+
+        self.functions.insert(identifier.into(), definition);
+        Ok(())
+    }
+}
+
+impl fmt::Debug for FunctionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.identifiers()).finish()
+    }
+}
+
+/// `ExpressionMacro` is a function defined through an expression over its parameters.
+/// - Syntax: fn \<name\>(\<param 1\>, \<param 2\>, ...) = \<expression\>
+/// - Example: fn led_r(vcc, vf, i) = (vcc - vf) / i
+///
+/// The expression may only refer to the parameters of the macro, and call other functions.
+struct ExpressionMacro {
+    name: String,
+    parameters: Vec<String>,
+    body: Node,
+}
+
+impl ExpressionMacro {
+    fn parse(definition: &str) -> EvalexprResult<Self> {
+        let invalid = || err(&format!("invalid macro definition: {}", definition));
+
+        let definition = definition.trim();
+        let signature = match definition.strip_prefix("fn ") {
+            Some(s) => s,
+            None => return invalid(),
+        };
+        let (signature, body) = match signature.split_once('=') {
+            Some(s) => s,
+            None => return invalid(),
+        };
+        let (name, parameters) = match signature.trim().strip_suffix(')') {
+            Some(s) => match s.split_once('(') {
+                Some(s) => s,
+                None => return invalid(),
+            },
+            None => return invalid(),
+        };
+
+        let name = name.trim().to_string();
+        let parameters: Vec<String> = parameters
+            .split(',')
+            .map(|p| p.trim().to_string())
+            .filter(|p| !p.is_empty())
+            .collect();
+        if let Some(p) = parameters.iter().find(|p| !is_identifier(p)) {
+            return err(&format!("{}: invalid parameter name: {}", name, p));
+        }
+
+        let body = evalexpr::build_operator_tree(body.trim())?;
+        for v in body.iter_variable_identifiers() {
+            if !parameters.iter().any(|p| p == v) {
+                return err(&format!("{}: unknown parameter: {}", name, v));
+            }
+        }
+
+        Ok(Self {
+            name,
+            parameters,
+            body,
+        })
+    }
+
+    fn call(&self, functions: &FunctionRegistry, argument: &Value) -> EvalexprResult<Value> {
+        // Single-parameter macros receive their argument as-is, others take a tuple
+        let arguments = match self.parameters.len() {
+            0 => vec![],
+            1 => vec![argument.clone()],
+            _ => argument.as_tuple()?,
+        };
+        if arguments.len() != self.parameters.len() {
+            return err(&format!(
+                "{}: expected {} arguments, got {}",
+                self.name,
+                self.parameters.len(),
+                arguments.len()
+            ));
+        }
+
+        let context = MacroContext {
+            arguments: self.parameters.iter().cloned().zip(arguments).collect(),
+            functions,
+        };
+        self.body.eval_with_context(&context)
+    }
+}
+
+// MacroContext is the evaluation context for the body of an expression macro
+struct MacroContext<'a> {
+    arguments: HashMap<String, Value>,
+    functions: &'a FunctionRegistry,
+}
+
+impl<'a> Context for MacroContext<'a> {
+    fn get_value(&self, identifier: &str) -> Option<&Value> {
+        self.arguments.get(identifier)
+    }
+
+    fn call_function(&self, identifier: &str, argument: &Value) -> EvalexprResult<Value> {
+        self.functions.call(identifier, argument)
+    }
+}
+
+// The functions built into evalexpr itself (as of evalexpr 6), which macros may call as well
+const EVALEXPR_BUILTINS: &[&str] = &[
+    "min",
+    "max",
+    "floor",
+    "round",
+    "ceil",
+    "if",
+    "len",
+    "is_nan",
+    "is_finite",
+    "is_infinite",
+    "is_normal",
+    "str::regex_matches",
+    "str::regex_replace",
+    "str::to_lowercase",
+    "str::to_uppercase",
+    "str::trim",
+    "str::from",
+    "bitand",
+    "bitor",
+    "bitxor",
+    "bitnot",
+    "shl",
+    "shr",
+];
+
+// is_builtin returns true for the functions built into evalexpr itself
+fn is_builtin(identifier: &str) -> bool {
+    EVALEXPR_BUILTINS.contains(&identifier)
+}
+
+// is_identifier returns true if the given string is a valid (non-dotted) identifier
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn macro_call() {
+        let mut registry = FunctionRegistry::with_builtins();
+        registry
+            .register_macro("fn led_r(vcc, vf, i) = (vcc - vf) / i")
+            .unwrap();
+        registry.register_macro("fn twice(x) = 2 * x").unwrap();
+        registry
+            .register_macro("fn twice_led_r(vcc, vf, i) = twice(led_r(vcc, vf, i))")
+            .unwrap();
+
+        let argument = Value::from(vec![5.0.into(), 2.0.into(), 0.01.into()]);
+        assert_eq!(registry.call("led_r", &argument), Ok(Value::from(300.0)));
+        assert_eq!(registry.call("twice", &Value::from(3)), Ok(Value::from(6)));
+        assert_eq!(
+            registry.call("twice_led_r", &argument),
+            Ok(Value::from(600.0))
+        );
+        assert!(registry.call("led_r", &Value::from(3)).is_err());
+    }
+
+    #[test]
+    fn macro_definition_errors() {
+        let mut registry = FunctionRegistry::with_builtins();
+        assert!(registry.register_macro("led_r(a) = a").is_err());
+        assert!(registry.register_macro("fn led_r(a) = a * b").is_err());
+        assert!(registry.register_macro("fn led_r(a) = unknown(a)").is_err());
+        assert!(registry.register_macro("fn idx(a) = a").is_err());
+        assert!(registry.register_macro("fn rec(a) = rec(a)").is_err());
+        assert!(registry.register_macro("fn m(a) = max(a, 1)").is_ok());
+        assert!(registry.register_macro("fn r(a) = round(a)").is_ok());
+        assert!(registry.register_macro("fn t(a) = str::trim(a)").is_ok());
+    }
+}
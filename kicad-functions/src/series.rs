@@ -0,0 +1,64 @@
+use crate::util::err;
+use evalexpr::EvalexprResult;
+
+// The decades of resistor values available, i.e. from 1 Ω up to 10 MΩ (exclusive)
+const DECADES: i32 = 7;
+
+// The IEC 60063 E-series, as the values within a decade in hundredths
+const E3: &[u16] = &[100, 220, 470];
+const E6: &[u16] = &[100, 150, 220, 330, 470, 680];
+const E12: &[u16] = &[100, 120, 150, 180, 220, 270, 330, 390, 470, 560, 680, 820];
+const E24: &[u16] = &[
+    100, 110, 120, 130, 150, 160, 180, 200, 220, 240, 270, 300, 330, 360, 390, 430, 470, 510, 560,
+    620, 680, 750, 820, 910,
+];
+const E48: &[u16] = &[
+    100, 105, 110, 115, 121, 127, 133, 140, 147, 154, 162, 169, 178, 187, 196, 205, 215, 226, 237,
+    249, 261, 274, 287, 301, 316, 332, 348, 365, 383, 402, 422, 442, 464, 487, 511, 536, 562, 590,
+    619, 649, 681, 715, 750, 787, 825, 866, 909, 953,
+];
+const E96: &[u16] = &[
+    100, 102, 105, 107, 110, 113, 115, 118, 121, 124, 127, 130, 133, 137, 140, 143, 147, 150, 154,
+    158, 162, 165, 169, 174, 178, 182, 187, 191, 196, 200, 205, 210, 215, 221, 226, 232, 237, 243,
+    249, 255, 261, 267, 274, 280, 287, 294, 301, 309, 316, 324, 332, 340, 348, 357, 365, 374, 383,
+    392, 402, 412, 422, 432, 442, 453, 464, 475, 487, 499, 511, 523, 536, 549, 562, 576, 590, 604,
+    619, 634, 649, 665, 681, 698, 715, 732, 750, 768, 787, 806, 825, 845, 866, 887, 909, 931, 953,
+    976,
+];
+
+// series_values returns all the resistances of the given E-series in increasing order
+pub(crate) fn series_values(name: &str) -> EvalexprResult<Vec<f64>> {
+    let decade = match name.trim() {
+        "E3" => E3,
+        "E6" => E6,
+        "E12" => E12,
+        "E24" => E24,
+        "E48" => E48,
+        "E96" => E96,
+        _ => return err(&format!("unknown resistor series: {}", name)),
+    };
+
+    Ok((0..DECADES)
+        .flat_map(|d| {
+            decade
+                .iter()
+                .map(move |v| f64::from(*v) * 10f64.powi(d) / 100.0)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn series_lengths() {
+        for (name, len) in [("E3", 3), ("E12", 12), ("E48", 48), ("E96", 96)].iter() {
+            let values = series_values(name).unwrap();
+            assert_eq!(values.len(), len * DECADES as usize);
+            assert!(values.windows(2).all(|w| w[0] < w[1]));
+        }
+        assert_eq!(series_values("E24").unwrap()[24 + 17], 51.0);
+        assert!(series_values("E192").is_err());
+    }
+}
@@ -1,6 +1,15 @@
-use evalexpr::{EvalexprError, EvalexprResult};
+use evalexpr::{EvalexprError, EvalexprResult, Value};
 
 /// Returns an `EvalexprResult` with a `EvalexprError::CustomMessage` error
 pub fn err<T>(msg: &str) -> EvalexprResult<T> {
     Err(EvalexprError::CustomMessage(msg.into()))
 }
+
+/// Returns the integer value of the given value. Integral floats are accepted as well,
+/// since all numeric literals are floats when evaluating with float-only arithmetic.
+pub fn as_integer(value: &Value) -> EvalexprResult<i64> {
+    match value {
+        Value::Float(f) if f.fract() == 0.0 => Ok(*f as i64),
+        other => other.as_int(),
+    }
+}
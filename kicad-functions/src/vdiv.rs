@@ -1,35 +1,30 @@
-use crate::util::err;
+use crate::series::series_values;
+use crate::util::{as_integer, err};
 use evalexpr::{
-    ContextWithMutableVariables, EvalexprError, EvalexprResult, HashMapContext, Node, Value,
+    ContextWithMutableVariables, EvalexprError, EvalexprResult, HashMapContext, Node, Operator,
+    Value,
 };
-use regex::Regex;
-use resistor_calc::{RCalc, RRes, RSeries};
-use std::cell::RefCell;
-use std::collections::HashSet;
-use std::ops::Deref;
-use std::panic::panic_any;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
-fn parse_series(str: &str) -> EvalexprResult<&'static RSeries> {
-    match str.trim() {
-        "E3" => Ok(&resistor_calc::E3),
-        "E6" => Ok(&resistor_calc::E6),
-        "E12" => Ok(&resistor_calc::E12),
-        "E24" => Ok(&resistor_calc::E24),
-        "E48" => Ok(&resistor_calc::E48),
-        "E96" => Ok(&resistor_calc::E96),
-        _ => err(&format!("unknown resistor series: {}", str)),
+// Errors are compared as fixed point numbers with a resolution of one part per million of
+// the target voltage, far below the tolerances of the resistors. Candidates whose errors
+// differ by less than that are considered equally good.
+const ERROR_SCALE: f64 = 1e6;
+
+// resistor_index returns n for identifiers of the form "Rn", where n >= 1
+fn resistor_index(identifier: &str) -> Option<usize> {
+    let digits = identifier.strip_prefix('R')?;
+    if digits.starts_with('0') || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
     }
+    digits.parse().ok()
 }
 
 fn resistor_identifiers(e: &Node) -> usize {
-    let re = Regex::new(r"^R[1-9][0-9]*$").unwrap();
-    let mut set = HashSet::new();
     e.iter_variable_identifiers()
-        .filter(|i| re.is_match(i)) // Match only R? identifiers
-        .for_each(|i| {
-            set.insert(i);
-        });
-    set.len()
+        .filter(|i| resistor_index(i).is_some()) // Match only R? identifiers
+        .collect::<HashSet<_>>()
+        .len()
 }
 
 // Helper for parsing of potentially single-element tuples
@@ -45,7 +40,7 @@ struct VoltageDividerConfig {
     target: f64,
     expression: Node,
     count: usize,
-    series: &'static RSeries,
+    values: Vec<f64>,
     resistance_min: Option<f64>,
     resistance_max: Option<f64>,
     extra_parameters: Option<Vec<Value>>,
@@ -58,16 +53,14 @@ impl VoltageDividerConfig {
         let resistance = tuple.get(3).map(|v| v.as_tuple()).transpose()?;
         let resistance_min = resistance
             .as_ref()
-            .map(|r| r.get(0).map(|v| v.as_number()))
-            .flatten()
+            .and_then(|r| r.first().map(|v| v.as_number()))
             .transpose()?;
         let resistance_max = resistance
             .as_ref()
-            .map(|r| r.get(1).map(|v| v.as_number()))
-            .flatten()
+            .and_then(|r| r.get(1).map(|v| v.as_number()))
             .transpose()?;
 
-        let extra_parameters = tuple.get(4).map(|v| parse_tuple(v));
+        let extra_parameters = tuple.get(4).map(parse_tuple);
 
         if let [target, expression, series] = &tuple[..3] {
             let expression = evalexpr::build_operator_tree(&expression.as_string()?)?;
@@ -77,7 +70,7 @@ impl VoltageDividerConfig {
                 target: target.as_number()?,
                 expression,
                 count,
-                series: parse_series(&series.as_string()?)?,
+                values: series_values(&series.as_string()?)?,
                 resistance_min,
                 resistance_max,
                 extra_parameters,
@@ -86,61 +79,621 @@ impl VoltageDividerConfig {
             err(&format!("unsupported argument count: {}", tuple.len()))
         }
     }
+
+    // The extra parameters, as they are named in the divider expression
+    fn extras(&self) -> Vec<(String, Value)> {
+        self.extra_parameters
+            .iter()
+            .flatten()
+            .enumerate()
+            .map(|(i, p)| (format!("E{}", i + 1), p.clone()))
+            .collect()
+    }
 }
 
-// TODO: Support u, k, M, G, etc. suffixes. Now the evaluator treats them as a variable.
-//  This can also be used to work around lacking support for negative exponents.
-fn calculate(config: &VoltageDividerConfig) -> Option<RRes> {
-    let calc = RCalc::new(vec![config.series; config.count]);
+// Expr is a divider expression compiled into plain floating point arithmetic, with the
+// extra parameters substituted. Resistors are referenced by their zero-based index.
+enum Expr {
+    Const(f64),
+    Resistor(usize),
+    Neg(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+    Exp(Box<Expr>, Box<Expr>),
+}
 
-    let mut context = HashMapContext::new();
-    if let Some(v) = &config.extra_parameters {
-        for (i, p) in v.iter().enumerate() {
-            context
-                .set_value(format!("E{}", i + 1).into(), p.clone())
-                .unwrap();
+impl Expr {
+    // Compile the expression tree, or return None if it uses anything but arithmetic
+    fn compile(node: &Node, count: usize, extras: &HashMap<String, f64>) -> Option<Self> {
+        let children = node.children();
+        let compile = |i: usize| Self::compile(&children[i], count, extras).map(Box::new);
+        match node.operator() {
+            Operator::RootNode if children.len() == 1 => compile(0).map(|e| *e),
+            Operator::Const { value } => value.as_number().ok().map(Self::Const),
+            Operator::Neg if children.len() == 1 => compile(0).map(Self::Neg),
+            Operator::Add if children.len() == 2 => Some(Self::Add(compile(0)?, compile(1)?)),
+            Operator::Sub if children.len() == 2 => Some(Self::Sub(compile(0)?, compile(1)?)),
+            Operator::Mul if children.len() == 2 => Some(Self::Mul(compile(0)?, compile(1)?)),
+            Operator::Div if children.len() == 2 => Some(Self::Div(compile(0)?, compile(1)?)),
+            Operator::Exp if children.len() == 2 => Some(Self::Exp(compile(0)?, compile(1)?)),
+            // Leaves that are not constants are variables, displayed as their identifier
+            _ if children.is_empty() => {
+                let identifier = node.operator().to_string();
+                match resistor_index(&identifier) {
+                    Some(i) if i <= count => Some(Self::Resistor(i - 1)),
+                    _ => extras.get(&identifier).copied().map(Self::Const),
+                }
+            }
+            _ => None,
         }
     }
 
-    let context_rc = RefCell::new(context);
-    calc.calc(|set| {
-        if let Some(true) = config.resistance_min.map(|r| set.sum() < r) {
-            return None; // Sum of resistance less than minimum
+    fn eval(&self, resistances: &[f64]) -> f64 {
+        match self {
+            Self::Const(c) => *c,
+            Self::Resistor(i) => resistances[*i],
+            Self::Neg(a) => -a.eval(resistances),
+            Self::Add(a, b) => a.eval(resistances) + b.eval(resistances),
+            Self::Sub(a, b) => a.eval(resistances) - b.eval(resistances),
+            Self::Mul(a, b) => a.eval(resistances) * b.eval(resistances),
+            Self::Div(a, b) => a.eval(resistances) / b.eval(resistances),
+            Self::Exp(a, b) => a.eval(resistances).powf(b.eval(resistances)),
         }
+    }
 
-        if let Some(true) = config.resistance_max.map(|r| set.sum() > r) {
-            return None; // Sum of resistance larger than maximum
+    // Analyze how the value of the expression changes with each of the resistors
+    fn monotonicity(&self, count: usize) -> Monotonicity {
+        match self {
+            Self::Const(c) => Monotonicity {
+                sign: Sign::of(*c),
+                forms: vec![Form::CONSTANT; count],
+            },
+            Self::Resistor(i) => {
+                let mut forms = vec![Form::CONSTANT; count];
+                forms[*i] = Form {
+                    kind: Kind::Affine,
+                    direction: Direction::Increasing,
+                };
+                Monotonicity {
+                    sign: Sign::Positive,
+                    forms,
+                }
+            }
+            Self::Neg(a) => a.monotonicity(count).negate(),
+            Self::Add(a, b) => a.monotonicity(count).add(b.monotonicity(count)),
+            Self::Sub(a, b) => a.monotonicity(count).add(b.monotonicity(count).negate()),
+            Self::Mul(a, b) => a.monotonicity(count).mul(b.monotonicity(count)),
+            Self::Div(a, b) => a.monotonicity(count).div(b.monotonicity(count)),
+            Self::Exp(a, b) => {
+                let (base, exponent) = (a.monotonicity(count), b.monotonicity(count));
+                if exponent.forms.iter().all(|f| f.is_constant()) {
+                    // The value of a constant exponent doesn't depend on the resistances
+                    base.powf(b.eval(&vec![1.0; count]))
+                } else {
+                    base.combine(exponent, |_, _| Form::ARBITRARY, Sign::Unknown)
+                }
+            }
         }
+    }
+}
 
-        for i in 1..=config.count {
-            context_rc
-                .borrow_mut()
-                .set_value(format!("R{}", i).into(), Value::Float(set.r(i)))
-                .unwrap();
+// Sign is what's known about the sign of a value for all positive resistances
+#[derive(Clone, Copy, PartialEq)]
+enum Sign {
+    Positive,
+    Negative,
+    Unknown,
+}
+
+impl Sign {
+    fn of(value: f64) -> Self {
+        if value > 0.0 {
+            Self::Positive
+        } else if value < 0.0 {
+            Self::Negative
+        } else {
+            Self::Unknown
         }
+    }
 
-        match config
-            .expression
-            .eval_with_context(context_rc.borrow().deref())
+    fn negate(self) -> Self {
+        match self {
+            Self::Positive => Self::Negative,
+            Self::Negative => Self::Positive,
+            Self::Unknown => Self::Unknown,
+        }
+    }
+
+    fn mul(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::Unknown, _) | (_, Self::Unknown) => Self::Unknown,
+            (a, b) if a == b => Self::Positive,
+            _ => Self::Negative,
+        }
+    }
+}
+
+// Direction is how a value changes when a single resistance increases. Unknown means
+// that the value is monotonic, but the direction depends on the other resistances.
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Constant,
+    Increasing,
+    Decreasing,
+    Unknown,
+}
+
+impl Direction {
+    fn flip(self) -> Self {
+        match self {
+            Self::Increasing => Self::Decreasing,
+            Self::Decreasing => Self::Increasing,
+            d => d,
+        }
+    }
+
+    fn is_known(self) -> bool {
+        matches!(self, Self::Increasing | Self::Decreasing)
+    }
+}
+
+// Kind is the shape of a value as a function of a single resistance x, with the others
+// fixed: affine (a + b*x), linear-fractional ((a + b*x) / (c + d*x) without a pole for
+// positive resistances), otherwise monotonic, or arbitrary. All but the last are monotonic.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Affine,
+    Fractional,
+    Monotonic,
+    Arbitrary,
+}
+
+// Form is the kind and direction of a value in a single resistance
+#[derive(Clone, Copy, PartialEq)]
+struct Form {
+    kind: Kind,
+    direction: Direction,
+}
+
+impl Form {
+    const CONSTANT: Form = Form {
+        kind: Kind::Affine,
+        direction: Direction::Constant,
+    };
+    const ARBITRARY: Form = Form {
+        kind: Kind::Arbitrary,
+        direction: Direction::Unknown,
+    };
+
+    fn is_constant(self) -> bool {
+        self.direction == Direction::Constant
+    }
+
+    fn flip(self) -> Self {
+        Self {
+            kind: self.kind,
+            direction: self.direction.flip(),
+        }
+    }
+
+    fn add(self, other: Self) -> Self {
+        if self.is_constant() {
+            return other;
+        }
+        if other.is_constant() {
+            return self;
+        }
+
+        let direction = if self.direction == other.direction {
+            self.direction
+        } else {
+            Direction::Unknown
+        };
+        match (self.kind, other.kind) {
+            (Kind::Affine, Kind::Affine) => Self {
+                kind: Kind::Affine,
+                direction,
+            },
+            (Kind::Arbitrary, _) | (_, Kind::Arbitrary) => Self::ARBITRARY,
+            // The sum of values changing in the same direction changes in that direction
+            _ if direction.is_known() => Self {
+                kind: Kind::Monotonic,
+                direction,
+            },
+            _ => Self::ARBITRARY,
+        }
+    }
+
+    // Multiply by a factor with the given sign that doesn't depend on the resistance
+    fn scale(self, sign: Sign) -> Self {
+        match sign {
+            Sign::Positive => self,
+            Sign::Negative => self.flip(),
+            Sign::Unknown if self.is_constant() => self,
+            Sign::Unknown => Self {
+                kind: self.kind,
+                direction: Direction::Unknown,
+            },
+        }
+    }
+
+    fn mul(self, sign: Sign, other: Self, other_sign: Sign) -> Self {
+        if self.is_constant() {
+            return other.scale(sign);
+        }
+        if other.is_constant() {
+            return self.scale(other_sign);
+        }
+
+        // The product of positive values changing in the same direction changes in that
+        // direction. Negative factors are negated first, and the product negated back.
+        match (sign, other_sign) {
+            (Sign::Unknown, _) | (_, Sign::Unknown) => Self::ARBITRARY,
+            (Sign::Negative, _) => self.flip().mul(Sign::Positive, other, other_sign).flip(),
+            (_, Sign::Negative) => self.mul(sign, other.flip(), Sign::Positive).flip(),
+            (Sign::Positive, Sign::Positive) => match self.add(other) {
+                f if f.direction.is_known() && f.kind != Kind::Arbitrary => Self {
+                    kind: Kind::Monotonic,
+                    direction: f.direction,
+                },
+                _ => Self::ARBITRARY,
+            },
+        }
+    }
+
+    // The reciprocal of a value with the given sign. A value that may be zero has a pole.
+    fn reciprocal(self, sign: Sign) -> Self {
+        match (self.kind, sign) {
+            _ if self.is_constant() => self,
+            (_, Sign::Unknown) | (Kind::Arbitrary, _) => Self::ARBITRARY,
+            (Kind::Affine, _) | (Kind::Fractional, _) => Self {
+                kind: Kind::Fractional,
+                direction: self.direction.flip(),
+            },
+            (Kind::Monotonic, _) => self.flip(),
+        }
+    }
+
+    fn div(self, sign: Sign, other: Self, other_sign: Sign) -> Self {
+        // The ratio of affine values is linear-fractional, which is monotonic
+        // as long as the denominator can't be zero
+        if !self.is_constant()
+            && !other.is_constant()
+            && self.kind == Kind::Affine
+            && other.kind == Kind::Affine
+            && other_sign != Sign::Unknown
         {
-            Ok(v) => Some((config.target - v.as_number().unwrap()).abs()),
-            Err(e) => match &e {
-                EvalexprError::DivisionError { divisor: d, .. } => {
-                    if let Ok(n) = d.as_number() {
-                        if n == 0.0 {
-                            // This soft-catch may be a bit redundant. Based on some testing the
-                            // internal conversions in evalexpr cause zero values to deviate
-                            // slightly from zero, thus avoiding division by zero even if you
-                            // explicitly write a zero division into the voltage divider equation.
-                            return None;
-                        }
-                    }
-                    panic_any(e.to_string()) // No graceful way to handle this from the closure
+            return Self {
+                kind: Kind::Fractional,
+                direction: Direction::Unknown,
+            };
+        }
+
+        self.mul(sign, other.reciprocal(other_sign), other_sign)
+    }
+
+    // Raise a positive base to a constant power, which is monotonic in the base
+    fn powf(self, exponent: f64) -> Self {
+        match Sign::of(exponent) {
+            _ if self.is_constant() || exponent == 1.0 => self,
+            _ if self.kind == Kind::Arbitrary => Self::ARBITRARY,
+            Sign::Positive => Self {
+                kind: Kind::Monotonic,
+                direction: self.direction,
+            },
+            Sign::Negative => Self {
+                kind: Kind::Monotonic,
+                direction: self.direction.flip(),
+            },
+            Sign::Unknown => Self::CONSTANT, // x^0 = 1
+        }
+    }
+}
+
+// Monotonicity is the sign of an expression, and its form in each of the resistors. This is
+// a conservative analysis: an expression that is monotonic in every resistor may still be
+// reported as arbitrary in some, but one reported as monotonic always is.
+struct Monotonicity {
+    sign: Sign,
+    forms: Vec<Form>,
+}
+
+impl Monotonicity {
+    // Returns true if the expression is monotonic in each resistor, with the others fixed
+    fn is_monotonic(&self) -> bool {
+        self.forms.iter().all(|f| f.kind != Kind::Arbitrary)
+    }
+
+    fn negate(self) -> Self {
+        Self {
+            sign: self.sign.negate(),
+            forms: self.forms.into_iter().map(Form::flip).collect(),
+        }
+    }
+
+    fn combine(self, other: Self, f: impl Fn(Form, Form) -> Form, sign: Sign) -> Self {
+        let forms = self
+            .forms
+            .iter()
+            .zip(other.forms.iter())
+            .map(|(a, b)| {
+                if a.is_constant() && b.is_constant() {
+                    Form::CONSTANT
+                } else {
+                    f(*a, *b)
                 }
-                _ => panic_any(e.to_string()),
+            })
+            .collect();
+        Self { sign, forms }
+    }
+
+    fn add(self, other: Self) -> Self {
+        let sign = if self.sign == other.sign {
+            self.sign
+        } else {
+            Sign::Unknown
+        };
+        self.combine(other, Form::add, sign)
+    }
+
+    fn mul(self, other: Self) -> Self {
+        let (a, b) = (self.sign, other.sign);
+        self.combine(other, |x, y| x.mul(a, y, b), a.mul(b))
+    }
+
+    fn div(self, other: Self) -> Self {
+        // The reciprocal has the same sign as the value
+        let (a, b) = (self.sign, other.sign);
+        self.combine(other, |x, y| x.div(a, y, b), a.mul(b))
+    }
+
+    fn powf(self, exponent: f64) -> Self {
+        match self.sign {
+            Sign::Positive => Self {
+                sign: Sign::Positive,
+                forms: self.forms.into_iter().map(|f| f.powf(exponent)).collect(),
             },
+            _ => {
+                let forms = self
+                    .forms
+                    .into_iter()
+                    .map(|f| if f.is_constant() { f } else { Form::ARBITRARY })
+                    .collect();
+                Self {
+                    sign: Sign::Unknown,
+                    forms,
+                }
+            }
+        }
+    }
+}
+
+// Candidate is a set of resistances, as indices into the values of the series, with the
+// error of the resulting voltage. Candidates are ordered by increasing error first, and
+// then by increasing resistances in the order of the resistors.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Candidate {
+    error: u64,
+    indices: Vec<usize>,
+}
+
+// Search is a branch-and-bound search for the best candidates. The space of candidates
+// is recursively split into boxes given by a range of values for each resistor. A box
+// is pruned if its resistances can't satisfy the limits on the sum of resistance, or if
+// the voltages it can produce are no better than the worst of the best candidates so far,
+// and equally good ones would have no lower resistances than it (see Candidate).
+// The latter is only known for expressions that are monotonic in each of the resistors
+// (see bound), the boxes of other expressions are searched exhaustively.
+struct Search<'c> {
+    config: &'c VoltageDividerConfig,
+    compiled: Option<Expr>,
+    monotonic: bool,
+    context: HashMapContext,
+    limit: usize,
+    best: BTreeSet<Candidate>,
+}
+
+impl<'c> Search<'c> {
+    fn new(config: &'c VoltageDividerConfig, limit: usize) -> EvalexprResult<Self> {
+        let extras = config.extras();
+        let mut context = HashMapContext::new();
+        for (name, value) in extras.iter() {
+            context.set_value(name.into(), value.clone())?;
+        }
+
+        // Expressions that can't be compiled (e.g. ones calling functions) are
+        // evaluated through evalexpr instead, which is a lot slower
+        let extras = extras
+            .into_iter()
+            .filter_map(|(name, value)| value.as_number().ok().map(|v| (name, v)))
+            .collect();
+        let compiled = Expr::compile(&config.expression, config.count, &extras);
+        let monotonic = compiled
+            .as_ref()
+            .map(|e| e.monotonicity(config.count).is_monotonic())
+            == Some(true);
+
+        Ok(Self {
+            config,
+            compiled,
+            monotonic,
+            context,
+            limit,
+            best: BTreeSet::new(),
+        })
+    }
+
+    fn run(&mut self) -> EvalexprResult<()> {
+        let mut ranges = vec![(0, self.config.values.len() - 1); self.config.count];
+        if self.infeasible(&ranges) {
+            return Ok(());
+        }
+        self.search(&mut ranges)
+    }
+
+    // Evaluate the divider expression with the given resistances. Returns None if
+    // the voltage isn't defined, e.g. because of a division by zero.
+    fn evaluate(&mut self, resistances: &[f64]) -> EvalexprResult<Option<f64>> {
+        if let Some(expr) = self.compiled.as_ref() {
+            let v = expr.eval(resistances);
+            return Ok(Some(v).filter(|v| v.is_finite()));
+        }
+
+        for (i, r) in resistances.iter().enumerate() {
+            self.context
+                .set_value(format!("R{}", i + 1), Value::Float(*r))?;
+        }
+        match self.config.expression.eval_with_context(&self.context) {
+            Ok(v) => Ok(Some(v.as_number()?).filter(|v| v.is_finite())),
+            Err(EvalexprError::DivisionError { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // The lowest error any candidate in the box could have, and the error at the center of
+    // the box for choosing which box to search first. The lower bound is only valid if the
+    // divider expression is monotonic in each of the resistors, as it is for dividers made
+    // up of sums, products and ratios of resistances. The extremes of the voltage are then
+    // found at the corners of the box, i.e. with each resistor at either end of its range.
+    fn bound(&mut self, ranges: &[(usize, usize)]) -> EvalexprResult<(u64, u64)> {
+        let target = self.config.target;
+        let (mut min, mut max) = (f64::INFINITY, f64::NEG_INFINITY);
+        let mut resistances = vec![0.0; ranges.len()];
+        for corner in 0..1 << ranges.len() {
+            for (i, (lo, hi)) in ranges.iter().enumerate() {
+                let index = if corner & (1 << i) == 0 { lo } else { hi };
+                resistances[i] = self.config.values[*index];
+            }
+            match self.evaluate(&resistances)? {
+                Some(v) => {
+                    min = min.min(v);
+                    max = max.max(v);
+                }
+                None => return Ok((0, u64::MAX)), // The voltage is undefined somewhere in the box
+            }
+        }
+        let lower = to_fixed((min - target).max(target - max).max(0.0), target);
+
+        for (i, (lo, hi)) in ranges.iter().enumerate() {
+            resistances[i] = self.config.values[lo + (hi - lo) / 2];
+        }
+        let center = match self.evaluate(&resistances)? {
+            Some(v) => to_fixed((v - target).abs(), target),
+            None => u64::MAX,
+        };
+
+        Ok((lower, center))
+    }
+
+    // Returns true if the resistances of the box can't satisfy the limits on their sum
+    fn infeasible(&self, ranges: &[(usize, usize)]) -> bool {
+        let values = &self.config.values;
+        let sum_min: f64 = ranges.iter().map(|(lo, _)| values[*lo]).sum();
+        let sum_max: f64 = ranges.iter().map(|(_, hi)| values[*hi]).sum();
+        let too_large = self.config.resistance_max.map(|r| sum_min > r);
+        let too_small = self.config.resistance_min.map(|r| sum_max < r);
+        too_large == Some(true) || too_small == Some(true)
+    }
+
+    // Returns true if no candidate in the box can be among the best ones, given the lowest
+    // error in the box. The candidate made up of the lowest resistances of the box orders
+    // before all others in it with the same error, so equal errors only prune the box if
+    // that candidate doesn't order before the worst of the best candidates either.
+    fn prune(&self, error: u64, ranges: &[(usize, usize)]) -> bool {
+        let lowest = Candidate {
+            error,
+            indices: ranges.iter().map(|(lo, _)| *lo).collect(),
+        };
+        self.best.len() == self.limit && self.best.iter().next_back() <= Some(&lowest)
+    }
+
+    fn search(&mut self, ranges: &mut Vec<(usize, usize)>) -> EvalexprResult<()> {
+        // Split the widest range of resistances in half, or evaluate the candidate
+        let widest = (0..ranges.len())
+            .filter(|i| ranges[*i].0 < ranges[*i].1)
+            .max_by_key(|i| (ranges[*i].1 - ranges[*i].0, usize::MAX - i));
+        let i = match widest {
+            Some(i) => i,
+            None => return self.visit(ranges),
+        };
+
+        let (lo, hi) = ranges[i];
+        let mid = lo + (hi - lo) / 2;
+        let mut halves = Vec::new();
+        for half in [(lo, mid), (mid + 1, hi)].iter() {
+            ranges[i] = *half;
+            if !self.infeasible(ranges) {
+                // Boxes of non-monotonic expressions are never pruned, so they need no bounds
+                let bound = if self.monotonic {
+                    self.bound(ranges)?
+                } else {
+                    (0, 0)
+                };
+                halves.push((bound, *half));
+            }
+        }
+
+        // Search the more promising half first, the other one might get pruned after that
+        halves.sort_unstable();
+        for (bound, half) in halves {
+            ranges[i] = half;
+            if !self.monotonic || !self.prune(bound.0, ranges) {
+                self.search(ranges)?;
+            }
+        }
+        ranges[i] = (lo, hi);
+        Ok(())
+    }
+
+    fn visit(&mut self, ranges: &[(usize, usize)]) -> EvalexprResult<()> {
+        let indices: Vec<usize> = ranges.iter().map(|(i, _)| *i).collect();
+        let resistances: Vec<f64> = indices.iter().map(|i| self.config.values[*i]).collect();
+        if let Some(v) = self.evaluate(&resistances)? {
+            self.best.insert(Candidate {
+                error: to_fixed((self.config.target - v).abs(), self.config.target),
+                indices,
+            });
+            if self.best.len() > self.limit {
+                if let Some(worst) = self.best.iter().next_back().cloned() {
+                    self.best.remove(&worst);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The best candidates as (<voltage>, <R1 value>, <R2 value>, ...) tuples
+    fn results(&mut self) -> EvalexprResult<Vec<Value>> {
+        let best: Vec<Vec<usize>> = self.best.iter().map(|c| c.indices.clone()).collect();
+        let mut results = Vec::new();
+        for indices in best {
+            let resistances: Vec<f64> = indices.iter().map(|i| self.config.values[*i]).collect();
+            if let Some(voltage) = self.evaluate(&resistances)? {
+                let mut tuple = vec![Value::from(voltage)];
+                tuple.extend(resistances.into_iter().map(Value::from));
+                results.push(Value::from(tuple));
+            }
         }
-    })
+        Ok(results)
+    }
+}
+
+// to_fixed converts an error into fixed point, relative to the target voltage if it's nonzero
+fn to_fixed(error: f64, target: f64) -> u64 {
+    let reference = if target == 0.0 { 1.0 } else { target.abs() };
+    (error / reference * ERROR_SCALE).round() as u64
+}
+
+fn calculate(config: &VoltageDividerConfig, limit: usize) -> EvalexprResult<Vec<Value>> {
+    if config.count == 0 {
+        return err("no resistors in the divider expression");
+    }
+
+    let mut search = Search::new(config, limit)?;
+    search.run()?;
+    search.results()
 }
 
 /// `voltage_divider` computes values for resistor-based voltage dividers.
@@ -153,26 +706,143 @@ fn calculate(config: &VoltageDividerConfig) -> Option<RRes> {
 /// resistance pair is an optional parameter, and the limits only consider the sum of resistance of
 /// all resistors defined in the expression. The "extra" parameters are optional external inputs for
 /// the divider expression, and will be made available as "E1", "E2", etc. in order.
+/// Resistances range from 1 Ω to 10 MΩ, and solutions within one part per million of each other
+/// are considered equally good. The search is deterministic, the same arguments always give the
+/// same solution. It's fast for expressions that are monotonic in each resistor, like the ratios
+/// of sums of resistances of typical dividers. Other expressions, and ones calling functions,
+/// are searched exhaustively, which is slow for three or more resistors.
 pub(crate) fn voltage_divider(argument: &Value) -> EvalexprResult<Value> {
     let config = VoltageDividerConfig::parse(argument)?;
-    if let Some(res) = calculate(&config) {
-        // Take the first result, these are ordered by increasing error
-        if let Some((v, set)) = res.iter().next() {
-            let voltage = config.target + fixed_to_floating(*v);
-            let mut tuple = vec![Value::from(voltage)];
-            for i in 1..=config.count {
-                tuple.push(Value::from(set.r(i)));
-            }
-            return Ok(Value::from(tuple));
-        }
+    match calculate(&config, 1)?.into_iter().next() {
+        Some(result) => Ok(result),
+        None => err(&format!("no solution found: {}", argument)),
     }
+}
+
+/// `voltage_divider_top` computes the best N candidates for a resistor-based voltage divider.
+/// - Usage: vdiv_top(\<N\>, \<target voltage\>, \<divider expression\>, \<resistor series\>,
+///                   {(\<min resistance\>, \<max resistance\>)}, ({extra 1}, {extra 2}, ...))
+/// - Example: vdiv_top(3, 5.1, "(R1+R2)/R2*E1", "E96", (500e3, 700e3), (0.8))
+/// - Output: ((\<voltage\>, \<R1 value\>, \<R2 value\>, ...), ...)
+/// The arguments following N are the same as for `vdiv`. The candidates are ordered from the best
+/// to the worst, and equally good ones by their resistances in the order of the resistors. Fewer
+/// than N candidates are returned if there aren't enough of them.
+pub(crate) fn voltage_divider_top(argument: &Value) -> EvalexprResult<Value> {
+    let tuple = argument.as_tuple()?;
+    let limit = match tuple.first().map(as_integer).transpose()? {
+        Some(n) if n > 0 => n as usize,
+        _ => return err(&format!("invalid candidate count: {}", argument)),
+    };
 
-    err(&format!("no solution found: {}", argument))
+    let config = VoltageDividerConfig::parse(&Value::from(tuple[1..].to_vec()))?;
+    let results = calculate(&config, limit)?;
+    if results.is_empty() {
+        return err(&format!("no solution found: {}", argument));
+    }
+    Ok(Value::from(results))
 }
 
-// resistor_calc outputs error quantities as "fixed point" numbers by multiplying
-// a float by 1e9, rounding the result and converting to u64. We need to undo
-// that procedure here.
-fn fixed_to_floating(value: u64) -> f64 {
-    (value as f64) / 1e9
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(f: fn(&Value) -> EvalexprResult<Value>, argument: &str) -> Vec<Value> {
+        let value = f(&evalexpr::eval(argument).unwrap()).unwrap();
+        value.as_tuple().unwrap()
+    }
+
+    #[test]
+    fn two_resistors() {
+        let result = call(
+            voltage_divider,
+            r#"(2.5, "(R1+R2)/R2*E1", "E12", (1e3, 1e4), (1))"#,
+        );
+        assert_eq!(
+            result,
+            vec![Value::from(2.5), Value::from(1500.0), Value::from(1000.0)]
+        );
+    }
+
+    #[test]
+    fn tied_candidates() {
+        // 1500/1000, 1800/1200, 2700/1800 and 3300/2200 are all exact, the lowest ones win
+        let results = call(
+            voltage_divider_top,
+            r#"(2, 2.5, "(R1+R2)/R2*E1", "E12", (1e3, 1e4), (1))"#,
+        );
+        let results: Vec<Vec<Value>> = results.iter().map(|r| r.as_tuple().unwrap()).collect();
+        assert_eq!(
+            results,
+            vec![
+                vec![2.5.into(), 1500.0.into(), 1000.0.into()],
+                vec![2.5.into(), 1800.0.into(), 1200.0.into()],
+            ]
+        );
+    }
+
+    #[test]
+    fn top_candidates() {
+        let results = call(
+            voltage_divider_top,
+            r#"(3, 0.5, "R2/(R1+R2)", "E3", (10, 100))"#,
+        );
+        let results: Vec<Vec<Value>> = results.iter().map(|r| r.as_tuple().unwrap()).collect();
+        assert_eq!(
+            results,
+            vec![
+                vec![0.5.into(), 10.0.into(), 10.0.into()],
+                vec![0.5.into(), 22.0.into(), 22.0.into()],
+                vec![0.5.into(), 47.0.into(), 47.0.into()],
+            ]
+        );
+    }
+
+    #[test]
+    fn many_resistors() {
+        // Four resistors of E96 give about 2e11 candidates without pruning
+        let result = call(
+            voltage_divider,
+            r#"(3.3, "(R1+R2+R3+R4)/(R3+R4)*E1", "E96", (90e3, 110e3), (0.6))"#,
+        );
+        let voltage = result[0].as_number().unwrap();
+        assert!((voltage - 3.3).abs() < 1e-3, "{}", voltage);
+        let sum: f64 = result[1..].iter().map(|r| r.as_number().unwrap()).sum();
+        assert!((90e3..=110e3).contains(&sum), "{}", sum);
+    }
+
+    #[test]
+    fn non_monotonic_expression() {
+        // The minimum of the expression is at R1 = 10, which the corners of no box reveal
+        let result = call(voltage_divider, r#"(20, "R1 + 100/R1", "E3")"#);
+        assert_eq!(result, vec![Value::from(20.0), Value::from(10.0)]);
+    }
+
+    #[test]
+    fn monotonicity() {
+        let monotonic = |expression: &str| {
+            let node = evalexpr::build_operator_tree(expression).unwrap();
+            let count = resistor_identifiers(&node);
+            let extras = vec![("E1".to_string(), 0.8)].into_iter().collect();
+            Expr::compile(&node, count, &extras)
+                .unwrap()
+                .monotonicity(count)
+                .is_monotonic()
+        };
+        assert!(monotonic("(R1+R2)/R2*E1"));
+        assert!(monotonic("R2/(R1+R2)"));
+        assert!(monotonic("(R1+R2+R3)/(R2+R3)*E1 - 1"));
+        assert!(monotonic("E1 * (1 + R1/R2)^2"));
+        assert!(!monotonic("R1 + 100/R1"));
+        assert!(!monotonic("R1 * (10 - R1)"));
+        assert!(!monotonic("R1^R2"));
+    }
+
+    #[test]
+    fn uncompiled_expression() {
+        let result = call(
+            voltage_divider,
+            r#"(1.5, "(R1+R2)/min(R2, 1e9)", "E3", (1, 10))"#,
+        );
+        assert_eq!(result[1..], [Value::from(2.2), Value::from(4.7)]);
+    }
 }
@@ -0,0 +1,109 @@
+// Benchmark of the evaluation engine on a synthetic hierarchy of 50 sheets with
+// 100 components each. Every component depends on the previous one in its sheet,
+// and on the Offset global of its sheet, which is a constant.
+// Run with `cargo bench --bench=evaluate`.
+use kicad_functions::FunctionRegistry;
+use kicad_rs::eval::{self, Arithmetic, Engine};
+use kicad_rs::types::Schematic;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+const SHEETS: usize = 50;
+const COMPONENTS: usize = 100;
+const ROUNDS: u32 = 10;
+
+fn synthetic_schematic() -> String {
+    let labels =
+        "{reference: R, footprintName: R, footprintLibrary: R, symbolName: R, symbolLibrary: R}";
+    let mut yaml = String::from(
+        "meta: {}
+subSchematics:
+",
+    );
+    for s in 0..SHEETS {
+        writeln!(yaml, "  S{}:", s).unwrap();
+        writeln!(yaml, "    meta: {{}}").unwrap();
+        writeln!(yaml, "    globals:").unwrap();
+        writeln!(
+            yaml,
+            "      Offset: {{type: String, value: '', expression: '{}', unit: Ohm}}",
+            s
+        )
+        .unwrap();
+        writeln!(yaml, "    components:").unwrap();
+        for c in 0..COMPONENTS {
+            let expression = match c {
+                0 => "Globals.Offset + 1".to_string(),
+                _ => format!("max(R{}, Globals.Offset) + 1", c - 1),
+            };
+            writeln!(yaml, "      R{}:", c).unwrap();
+            writeln!(
+                yaml,
+                "        labels: {}",
+                labels.replace("R,", &format!("R{},", c))
+            )
+            .unwrap();
+            writeln!(yaml, "        attributes:").unwrap();
+            writeln!(
+                yaml,
+                "          Value: {{type: String, value: '', expression: '{}', unit: Ohm}}",
+                expression
+            )
+            .unwrap();
+        }
+    }
+    yaml
+}
+
+fn report(name: &str, total: Duration) {
+    println!(
+        "{:<32} {:>10.3} ms/iter",
+        name,
+        total.as_secs_f64() * 1000.0 / ROUNDS as f64
+    );
+}
+
+fn main() {
+    let functions = FunctionRegistry::with_builtins();
+    let yaml = synthetic_schematic();
+    println!(
+        "{} sheets x {} components, {} rounds",
+        SHEETS, COMPONENTS, ROUNDS
+    );
+
+    let mut total = Duration::default();
+    for _ in 0..ROUNDS {
+        let mut sch: Schematic = serde_yaml::from_str(&yaml).unwrap();
+        let start = Instant::now();
+        let mut index = eval::index_schematic(&mut sch, &functions).unwrap();
+        eval::evaluate_schematic(&mut index, Arithmetic::Float).unwrap();
+        total += start.elapsed();
+    }
+    report("index + evaluate", total);
+
+    let mut sch: Schematic = serde_yaml::from_str(&yaml).unwrap();
+    let mut index = eval::index_schematic(&mut sch, &functions).unwrap();
+    let mut engine = Engine::compile(&mut index, Arithmetic::Float).unwrap();
+    engine.evaluate().unwrap();
+
+    // Changing the last component of a sheet only re-evaluates that component
+    let mut total = Duration::default();
+    for i in 0..ROUNDS {
+        let start = Instant::now();
+        let path = format!("S0.R{}.Value", COMPONENTS - 1);
+        engine.set_expression(&path, &format!("{}", i)).unwrap();
+        total += start.elapsed();
+    }
+    report("set_expression (leaf)", total);
+
+    // Changing the globals of a sheet re-evaluates the whole sheet
+    let mut total = Duration::default();
+    for i in 0..ROUNDS {
+        let start = Instant::now();
+        engine
+            .set_expression("S0.Globals.Offset", &format!("{}", i))
+            .unwrap();
+        total += start.elapsed();
+    }
+    report("set_expression (sheet globals)", total);
+}
@@ -0,0 +1,50 @@
+use clap::{App, Arg};
+use kicad_rs::codec;
+use kicad_rs::error::DynamicResult;
+use kicad_rs::parser::SchematicTree;
+use kicad_rs::types::Schematic;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+// Get crate version information from Cargo
+const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+
+// Main function, can return different kinds of errors
+fn main() -> DynamicResult<()> {
+    let matches = App::new("KiCad apply")
+        .about(
+            "Applies an edited schematic YAML (as output by the parser) back into KiCad schematics",
+        )
+        .author("The Racklet Project")
+        .version(VERSION.unwrap_or("unknown"))
+        .version_short("v")
+        .arg(
+            Arg::with_name("SCHEMATIC")
+                .help("Path to the schematic file to update in place")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("YAML")
+                .help("Path to the schematic YAML to apply, read from stdin if not given"),
+        )
+        .get_matches();
+
+    // Calling .unwrap() is safe here because "SCHEMATIC" is required
+    let path = Path::new(matches.value_of("SCHEMATIC").unwrap());
+
+    // Read the edited Schematic YAML from the given file or stdin
+    let sch: Schematic = match matches.value_of("YAML") {
+        Some(p) => codec::unmarshal_yaml(File::open(p)?)?,
+        None => codec::unmarshal_yaml(io::stdin())?,
+    };
+    sch.check_type_meta()?;
+
+    // Load the hierarchical schematic tree, update the fields of the components
+    // that differ from the given YAML, and write the schematics back to their files
+    let mut tree = SchematicTree::load(path)?;
+    tree.update(&sch)?;
+    tree.write()?;
+
+    Ok(())
+}
@@ -1,18 +1,19 @@
 use clap::{App, Arg};
+use kicad_rs::cache::Cache;
 use kicad_rs::codec;
 use kicad_rs::error::DynamicResult;
 use kicad_rs::policy;
 use kicad_rs::types::Schematic;
 use std::io;
 use std::path::Path;
-use std::process::Command;
 
 // Get crate version information from Cargo
 const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
 
 fn main() -> DynamicResult<()> {
     // Read the Schematic YAML from stdin
-    let sch: Schematic = codec::unmarshal_yaml(io::stdin())?;
+    let mut sch: Schematic = codec::unmarshal_yaml(io::stdin())?;
+    sch.check_type_meta()?;
 
     let matches = App::new("KiCad classifier")
         .about("Classifies components in schematics based on policy expressed in CUE")
@@ -30,6 +31,17 @@ fn main() -> DynamicResult<()> {
                 .env("CUE_BIN")
                 .help("Path to the cue binary. Download from cuelang.org."),
         )
+        .arg(Arg::with_name("no-cache").long("no-cache").help(
+            "Always run cue, instead of reusing the results of earlier runs with the same inputs, \
+            which are cached in $KICAD_RS_CACHE_DIR or ~/.cache/kicad-rs by default",
+        ))
+        .arg(
+            Arg::with_name("variant")
+                .long("variant")
+                .takes_value(true)
+                .value_name("NAME")
+                .help("Select the given assembly variant, leaving out DNP parts"),
+        )
         .get_matches();
 
     // Calling .unwrap() is safe here because "CUE_POLICY" is required (if "CUE_POLICY"
@@ -37,18 +49,18 @@ fn main() -> DynamicResult<()> {
     let policy_path = Path::new(matches.value_of("CUE_POLICY").unwrap());
     let cue_path = Path::new(matches.value_of("CUE_BIN").unwrap());
 
-    // Check if the cue binary can be executed from the given path
-    Command::new(cue_path).output().expect(
-        format!(
-            "Could not execute cue with the invocation: '{}'. \
-            Install cue before attempting to run this program.",
-            cue_path.display()
-        )
-        .as_str(),
-    );
+    // Narrow the schematic down to the requested assembly variant
+    if let Some(variant) = matches.value_of("variant") {
+        sch.apply_variant(variant)?;
+    }
 
-    // Apply the policy in the given file
-    let processed_sch = policy::apply(&policy_path, &cue_path, sch)?;
+    // Apply the policy in the given file, reusing the results of earlier runs if possible
+    let cache = if matches.is_present("no-cache") {
+        None
+    } else {
+        Cache::default_dir().map(Cache::new)
+    };
+    let processed_sch = policy::apply(&policy_path, &cue_path, sch, cache.as_ref())?;
 
     // Marshal the resulting schematic as YAML
     codec::marshal_yaml(&processed_sch, io::stdout())?;
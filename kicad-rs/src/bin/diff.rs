@@ -0,0 +1,67 @@
+use clap::{App, Arg};
+use kicad_rs::diff;
+use kicad_rs::error::DynamicResult;
+use std::io;
+use std::path::Path;
+
+// Get crate version information from Cargo
+const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+
+// Main function, can return different kinds of errors
+fn main() -> DynamicResult<()> {
+    let matches = App::new("KiCad diff")
+        .about("Reports semantic changes between two schematic revisions")
+        .author("The Racklet Project")
+        .version(VERSION.unwrap_or("unknown"))
+        .version_short("v")
+        .arg(
+            Arg::with_name("OLD")
+                .help("Path to the old schematic (or parsed YAML)")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("NEW")
+                .help("Path to the new schematic (or parsed YAML), defaults to the old path"),
+        )
+        .arg(
+            Arg::with_name("old-rev")
+                .long("old-rev")
+                .takes_value(true)
+                .value_name("REV")
+                .help("Git revision to read the old schematic at, defaults to the working tree"),
+        )
+        .arg(
+            Arg::with_name("new-rev")
+                .long("new-rev")
+                .takes_value(true)
+                .value_name("REV")
+                .help("Git revision to read the new schematic at, defaults to the working tree"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .possible_values(&["text", "markdown"])
+                .default_value("text")
+                .help("Output format, markdown is suitable for pull request comments"),
+        )
+        .get_matches();
+
+    // Calling .unwrap() is safe here because "OLD" is required, and "format" has a default
+    let old_path = Path::new(matches.value_of("OLD").unwrap());
+    let new_path = matches.value_of("NEW").map_or(old_path, Path::new);
+    let format = match matches.value_of("format").unwrap() {
+        "markdown" => diff::Format::Markdown,
+        _ => diff::Format::Text,
+    };
+
+    // Load both revisions of the schematic
+    let old = diff::load_schematic(old_path, matches.value_of("old-rev"))?;
+    let new = diff::load_schematic(new_path, matches.value_of("new-rev"))?;
+
+    // Compare the schematics and write the changes to stdout
+    let sheets = diff::diff(&old, &new);
+    diff::write_diff(&sheets, format, &mut io::stdout())?;
+
+    Ok(())
+}
@@ -1,7 +1,10 @@
 use clap::{App, Arg};
-use kicad_rs::error::DynamicResult;
-use kicad_rs::eval;
-use kicad_rs::parser::SchematicTree;
+use kicad_rs::codec;
+use kicad_rs::error::{errorf, DynamicResult};
+use kicad_rs::pipeline;
+use kicad_rs::project::PROJECT_FILE_NAME;
+use std::io;
+use std::path::Path;
 
 // Get crate version information from Cargo
 const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
@@ -18,27 +21,88 @@ fn main() -> DynamicResult<()> {
                 .help("Path to the schematic file to process")
                 .required(true),
         )
+        .arg(
+            Arg::with_name("project")
+                .short("p")
+                .long("project")
+                .takes_value(true)
+                .help(&format!(
+                    "Path to the project definitions file [default: {} next to SCHEMATIC]",
+                    PROJECT_FILE_NAME
+                )),
+        )
+        .arg(
+            Arg::with_name("checks")
+                .long("checks")
+                .takes_value(true)
+                .possible_values(&["error", "warn"])
+                .default_value("error")
+                .help("Whether failing design checks are errors or only warnings"),
+        )
+        .arg(
+            Arg::with_name("explain")
+                .long("explain")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Print how the value at the given path (e.g. R5.Value) is computed, without writing the schematic"),
+        )
+        .arg(
+            Arg::with_name("variant")
+                .long("variant")
+                .takes_value(true)
+                .value_name("NAME")
+                .help("Evaluate the given assembly variant, and write it as YAML to stdout instead of into the schematic"),
+        )
         .get_matches();
 
     // Calling .unwrap() is safe here because "SCHEMATIC" is required (if "SCHEMATIC"
     // wasn't required we could have used an 'if let' to conditionally get the value)
-    let path = std::path::Path::new(matches.value_of("SCHEMATIC").unwrap());
-
-    // Load the hierarchical schematic tree and parse it
-    let mut tree = SchematicTree::load(path)?;
-    let mut schematic = tree.parse()?;
-
-    // Index the parsed schematic and use the index to evaluate it. The
-    // index links to the schematic using mutable references, so that's
-    // why the schematic itself needs to be passed in as mutable here.
-    let mut index = eval::index_schematic(&mut schematic)?;
-    eval::evaluate_schematic(&mut index)?;
-
-    // Update the fields of the components in the schematic tree based
-    // on the newly computed values and write the updated schematics
-    // back into the respective files
-    tree.update(&schematic)?;
-    tree.write()?;
+    let path = Path::new(matches.value_of("SCHEMATIC").unwrap());
+
+    // Load the project definitions, either from the given file or from next
+    // to the schematic. Not having a project file at all is fine.
+    let project = pipeline::load_project(path, matches.value_of("project").map(Path::new))?;
+
+    // Load the hierarchical schematic tree and parse it, merging
+    // the project globals into the globals of the root schematic
+    let (mut tree, mut schematic) = pipeline::load(path, &project)?;
+    let variant = matches.value_of("variant");
+
+    // Only explain the derivation of the requested value if asked to
+    if let Some(explain_path) = matches.value_of("explain") {
+        let explanation = pipeline::explain(&mut schematic, &project, variant, explain_path)?;
+        print!("{}", explanation);
+        return Ok(());
+    }
+
+    // Evaluate the parsed schematic, using the built-ins and the functions
+    // defined in the project, and report all design checks that don't hold
+    let failed_checks = match variant {
+        Some(variant) => pipeline::evaluate_variant(&mut schematic, &project, variant)?,
+        None => pipeline::evaluate(&mut schematic, &project)?,
+    };
+    for check in failed_checks.iter() {
+        eprintln!("check failed: {}", check);
+    }
+
+    if variant.is_some() {
+        // The evaluated variant differs from the schematic, so it's written to stdout instead
+        codec::marshal_yaml(&schematic, io::stdout())?;
+    } else {
+        // Update the fields of the components in the schematic tree based
+        // on the newly computed values and write the updated schematics
+        // back into the respective files
+        tree.update(&schematic)?;
+        tree.write()?;
+    }
+
+    // The results of the checks are written above, and fail the run if requested
+    if !failed_checks.is_empty() && matches.value_of("checks") == Some("error") {
+        return Err(errorf(&format!(
+            "{} design check(s) failed",
+            failed_checks.len()
+        )));
+    }
 
     Ok(())
 }
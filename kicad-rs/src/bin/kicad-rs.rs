@@ -0,0 +1,512 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use kicad_rs::cache::Cache;
+use kicad_rs::codec;
+use kicad_rs::diff;
+use kicad_rs::error::{errorf, DynamicResult};
+use kicad_rs::eval;
+use kicad_rs::lint;
+use kicad_rs::parser::SchematicTree;
+use kicad_rs::pipeline;
+use kicad_rs::project::{Project, PROJECT_FILE_NAME};
+use kicad_rs::report;
+use kicad_rs::types::Schematic;
+use kicad_rs::watch::{self, Snapshot};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Get crate version information from Cargo
+const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+
+// Main function, can return different kinds of errors
+fn main() -> DynamicResult<()> {
+    let schematic_arg = || {
+        Arg::with_name("SCHEMATIC")
+            .help("Path to the schematic file to process")
+            .required(true)
+    };
+    let policy_arg = || {
+        Arg::with_name("CUE_POLICY")
+            .help("Path to the CUE policy to process")
+            .required(true)
+    };
+
+    let project_help = format!(
+        "Path to the project definitions file [default: {} next to SCHEMATIC]",
+        PROJECT_FILE_NAME
+    );
+
+    let matches = App::new("kicad-rs")
+        .about("Declarative electronics for KiCad Eeschema schematics")
+        .author("The Racklet Project")
+        .version(VERSION.unwrap_or("unknown"))
+        .version_short("v")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("parse")
+                .about("Parses a schematic into its YAML (or JSON) representation")
+                .arg(schematic_arg())
+                .arg(project_arg(&project_help))
+                .arg(variant_arg())
+                .args(&output_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("eval")
+                .about("Evaluates expressions in a schematic, updating it in place")
+                .arg(schematic_arg())
+                .arg(project_arg(&project_help))
+                .arg(checks_arg())
+                .arg(watch_arg())
+                .arg(variant_arg().help(
+                    "Evaluate the given assembly variant, and write it to the output instead of into the schematic",
+                ))
+                .args(&output_args())
+                .arg(
+                    Arg::with_name("explain")
+                        .long("explain")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .conflicts_with("watch")
+                        .help("Print how the value at the given path (e.g. R5.Value) is computed, without writing the schematic"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("classify")
+                .about("Classifies components and applies the policy expressed in CUE")
+                .arg(policy_arg())
+                .arg(cue_bin_arg())
+                .arg(no_cache_arg())
+                .arg(input_arg())
+                .arg(variant_arg())
+                .args(&output_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Evaluates a schematic without writing it, and reports failing design checks")
+                .arg(schematic_arg())
+                .arg(project_arg(&project_help))
+                .arg(variant_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("graph")
+                .about("Writes the dependency graph of the evaluated attributes")
+                .arg(schematic_arg())
+                .arg(project_arg(&project_help))
+                .arg(output_arg())
+                .arg(
+                    Arg::with_name("format")
+                        .short("f")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["dot", "mermaid"])
+                        .default_value("dot")
+                        .help("Graph language, Graphviz DOT or Mermaid"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("lint")
+                .about("Reports all problems with the expressions in a schematic at once")
+                .arg(schematic_arg())
+                .arg(project_arg(&project_help)),
+        )
+        .subcommand(
+            SubCommand::with_name("report")
+                .about("Writes a Markdown bill of materials for a parsed or classified schematic")
+                .arg(input_arg())
+                .arg(variant_arg())
+                .arg(output_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("pipeline")
+                .about("Runs load, evaluate, classify and validate in one go, without intermediate files")
+                .arg(schematic_arg())
+                .arg(policy_arg())
+                .arg(cue_bin_arg())
+                .arg(no_cache_arg())
+                .arg(project_arg(&project_help))
+                .arg(checks_arg())
+                .arg(
+                    Arg::with_name("write")
+                        .long("write")
+                        .conflicts_with("variant")
+                        .help("Also write the evaluated values back into the schematic files"),
+                )
+                .arg(
+                    Arg::with_name("write-defaults")
+                        .long("write-defaults")
+                        .conflicts_with("variant")
+                        .help("Also write the evaluated values and the attributes and labels defaulted by the policy back into the schematic files"),
+                )
+                .arg(watch_arg())
+                .arg(variant_arg())
+                .args(&output_args()),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        ("parse", Some(m)) => parse(m),
+        ("eval", Some(m)) => eval(m),
+        ("classify", Some(m)) => classify(m),
+        ("check", Some(m)) => check(m),
+        ("graph", Some(m)) => graph(m),
+        ("lint", Some(m)) => lint(m),
+        ("report", Some(m)) => report(m),
+        ("pipeline", Some(m)) => pipeline(m),
+        _ => unreachable!("a subcommand is required"),
+    }
+}
+
+fn parse(m: &ArgMatches) -> DynamicResult<()> {
+    let path = schematic_path(m);
+    let project = pipeline::load_project(path, m.value_of("project").map(Path::new))?;
+    let (_, mut schematic) = pipeline::load(path, &project)?;
+    if let Some(variant) = m.value_of("variant") {
+        schematic.apply_variant(variant)?;
+    }
+    write_schematic(m, &schematic)
+}
+
+fn eval(m: &ArgMatches) -> DynamicResult<()> {
+    if let Some(explain_path) = m.value_of("explain") {
+        let path = schematic_path(m);
+        let project = pipeline::load_project(path, m.value_of("project").map(Path::new))?;
+        let (_, mut schematic) = pipeline::load(path, &project)?;
+        let variant = m.value_of("variant");
+        let explanation = pipeline::explain(&mut schematic, &project, variant, explain_path)?;
+        print!("{}", explanation);
+        return Ok(());
+    }
+
+    // Variants aren't written back into the schematic, but to the output instead
+    let write = !m.is_present("variant");
+    if m.is_present("watch") {
+        return watch_runs(m, write, false);
+    }
+
+    // The evaluated values are written back into the schematic files before
+    // reporting the checks, so that failing checks can be inspected in KiCad
+    let (_, _, failed_checks) = run(m, write, false)?;
+    report_checks(m, &failed_checks)
+}
+
+fn classify(m: &ArgMatches) -> DynamicResult<()> {
+    let mut schematic = read_schematic(m)?;
+    if let Some(variant) = m.value_of("variant") {
+        schematic.apply_variant(variant)?;
+    }
+    let schematic = pipeline::classify(schematic, policy_path(m), cue_bin(m), cache(m).as_ref())?;
+    write_schematic(m, &schematic)
+}
+
+fn check(m: &ArgMatches) -> DynamicResult<()> {
+    let path = schematic_path(m);
+    let project = pipeline::load_project(path, m.value_of("project").map(Path::new))?;
+    let (_, mut schematic) = pipeline::load(path, &project)?;
+    let failed_checks = evaluate(m, &mut schematic, &project)?;
+    if failed_checks.is_empty() {
+        println!("all design checks passed");
+    }
+    report_checks(m, &failed_checks)
+}
+
+fn graph(m: &ArgMatches) -> DynamicResult<()> {
+    let path = schematic_path(m);
+    let project = pipeline::load_project(path, m.value_of("project").map(Path::new))?;
+    let (_, mut schematic) = pipeline::load(path, &project)?;
+
+    // Evaluate the schematic in memory, so that the graph contains the computed values
+    let functions = project.function_registry()?;
+    let formats = project.formats()?;
+    let mut index = eval::index_schematic(&mut schematic, &functions)?;
+    index.set_default_formats(&formats);
+    eval::evaluate_schematic(&mut index, project.arithmetic)?;
+
+    let format = match m.value_of("format").unwrap() {
+        "mermaid" => eval::GraphFormat::Mermaid,
+        _ => eval::GraphFormat::Dot,
+    };
+    eval::write_graph(&index, format, &mut output(m)?)
+}
+
+fn lint(m: &ArgMatches) -> DynamicResult<()> {
+    let path = schematic_path(m);
+    let project = pipeline::load_project(path, m.value_of("project").map(Path::new))?;
+    let (tree, mut schematic) = pipeline::load(path, &project)?;
+    let findings = lint::lint(&tree, &mut schematic, &project.function_registry()?)?;
+    for finding in findings.iter() {
+        println!("{}", finding);
+    }
+
+    if !findings.is_empty() {
+        return Err(errorf(&format!("{} problem(s) found", findings.len())));
+    }
+    Ok(())
+}
+
+fn report(m: &ArgMatches) -> DynamicResult<()> {
+    let mut schematic = read_schematic(m)?;
+    if let Some(variant) = m.value_of("variant") {
+        schematic.apply_variant(variant)?;
+    }
+    report::write_report(&schematic, &mut output(m)?)
+}
+
+fn pipeline(m: &ArgMatches) -> DynamicResult<()> {
+    let write = m.is_present("write") || m.is_present("write-defaults");
+    if m.is_present("watch") {
+        return watch_runs(m, write, true);
+    }
+
+    let (_, _, failed_checks) = run(m, write, true)?;
+    report_checks(m, &failed_checks)
+}
+
+// run loads and evaluates the schematic, optionally writing the values back into the
+// schematic files, and optionally classifies and validates it against the policy and
+// writes the result. The result is also written when evaluating an assembly variant.
+// With --write-defaults, the values are written together with the attributes and labels
+// the policy defaulted, once the schematic has been classified. If the policy fails, the
+// values are still written like with --write. It returns the schematic files read, the
+// resulting schematic and the design checks that failed.
+fn run(
+    m: &ArgMatches,
+    write: bool,
+    classify: bool,
+) -> DynamicResult<(Vec<PathBuf>, Schematic, Vec<String>)> {
+    let path = schematic_path(m);
+    let project = pipeline::load_project(path, m.value_of("project").map(Path::new))?;
+
+    // Load and evaluate
+    let (mut tree, mut schematic) = pipeline::load(path, &project)?;
+    let files = tree.files();
+    let loaded = Snapshot::take(&files);
+    let failed_checks = evaluate(m, &mut schematic, &project)?;
+    let write_defaults = classify && m.is_present("write-defaults");
+    if write {
+        tree.update(&schematic)?;
+        if !write_defaults {
+            write_tree(&tree, &files, &loaded)?;
+        }
+    }
+
+    // Classify and validate against the policy
+    if classify {
+        let classified =
+            pipeline::classify(schematic, policy_path(m), cue_bin(m), cache(m).as_ref());
+        if write_defaults {
+            if let Ok(classified) = classified.as_ref() {
+                tree.update(classified)?;
+            }
+            write_tree(&tree, &files, &loaded)?;
+        }
+        schematic = classified?;
+        write_schematic(m, &schematic)?;
+    } else if m.is_present("variant") {
+        write_schematic(m, &schematic)?;
+    }
+
+    Ok((files, schematic, failed_checks))
+}
+
+// write_tree writes the updated tree into the schematic files, unless the files have
+// changed (e.g. saved by KiCad) since they were loaded
+fn write_tree(tree: &SchematicTree, files: &[PathBuf], loaded: &Snapshot) -> DynamicResult<()> {
+    if &Snapshot::take(files) != loaded {
+        return Err(errorf(
+            "the schematic changed during evaluation, not writing it",
+        ));
+    }
+    tree.write()
+}
+
+// watch_runs runs the evaluation again each time the schematic files, the project file or
+// the policy change, and prints a summary of the changes in the result compared to the
+// previous run. Failing design checks are reported, but don't stop watching.
+fn watch_runs(m: &ArgMatches, write: bool, classify: bool) -> DynamicResult<()> {
+    let path = schematic_path(m);
+    // The root schematic is watched also when loading it fails
+    let mut extra_paths = vec![
+        path.to_path_buf(),
+        m.value_of("project")
+            .map_or_else(|| Project::default_path(path), PathBuf::from),
+    ];
+    if classify {
+        extra_paths.push(policy_path(m).into());
+    }
+
+    let mut previous: Option<Schematic> = None;
+    watch::watch(&extra_paths, || {
+        let (files, schematic, failed_checks) = run(m, write, classify)?;
+        match previous.as_ref() {
+            Some(p) => diff::write_diff(
+                &diff::diff(p, &schematic),
+                diff::Format::Text,
+                &mut io::stderr(),
+            )?,
+            None => eprintln!("evaluated {}", path.display()),
+        }
+        for check in failed_checks.iter() {
+            eprintln!("check failed: {}", check);
+        }
+
+        previous = Some(schematic);
+        Ok(files)
+    })
+}
+
+// evaluate evaluates the schematic, or the assembly variant of it if one was requested
+fn evaluate(
+    m: &ArgMatches,
+    schematic: &mut Schematic,
+    project: &Project,
+) -> DynamicResult<Vec<String>> {
+    match m.value_of("variant") {
+        Some(variant) => pipeline::evaluate_variant(schematic, project, variant),
+        None => pipeline::evaluate(schematic, project),
+    }
+}
+
+// report_checks writes the failed design checks to stderr, and fails if requested
+fn report_checks(m: &ArgMatches, failed_checks: &[String]) -> DynamicResult<()> {
+    for check in failed_checks.iter() {
+        eprintln!("check failed: {}", check);
+    }
+
+    // The "check" subcommand has no flag, failing checks are always errors there
+    if !failed_checks.is_empty() && m.value_of("checks").unwrap_or("error") == "error" {
+        return Err(errorf(&format!(
+            "{} design check(s) failed",
+            failed_checks.len()
+        )));
+    }
+
+    Ok(())
+}
+
+// Calling .unwrap() is safe in the accessors below because the arguments are
+// either required or have default values in the subcommands that use them
+
+fn schematic_path<'a>(m: &'a ArgMatches) -> &'a Path {
+    Path::new(m.value_of("SCHEMATIC").unwrap())
+}
+
+fn policy_path<'a>(m: &'a ArgMatches) -> &'a Path {
+    Path::new(m.value_of("CUE_POLICY").unwrap())
+}
+
+// cue_bin returns the path of the cue binary. It's only executed if the results of cue
+// aren't cached, so a missing binary is reported by the policy step instead of here.
+fn cue_bin<'a>(m: &'a ArgMatches) -> &'a Path {
+    Path::new(m.value_of("cue-bin").unwrap())
+}
+
+// cache returns the cache for the results of cue, unless caching is disabled or
+// there's no directory to keep it in
+fn cache(m: &ArgMatches) -> Option<Cache> {
+    if m.is_present("no-cache") {
+        return None;
+    }
+    Cache::default_dir().map(Cache::new)
+}
+
+// read_schematic reads the Schematic YAML (or JSON) from the input file or stdin
+fn read_schematic(m: &ArgMatches) -> DynamicResult<Schematic> {
+    let schematic: Schematic = match m.value_of("input") {
+        Some(p) => codec::unmarshal_yaml(File::open(p)?)?,
+        None => codec::unmarshal_yaml(io::stdin())?,
+    };
+    schematic.check_type_meta()?;
+    Ok(schematic)
+}
+
+// write_schematic writes the Schematic in the requested format to the output file or stdout
+fn write_schematic(m: &ArgMatches, schematic: &Schematic) -> DynamicResult<()> {
+    let format = match m.value_of("format").unwrap() {
+        "json" => codec::Format::Json,
+        _ => codec::Format::Yaml,
+    };
+    codec::marshal(format, schematic, output(m)?)
+}
+
+fn output(m: &ArgMatches) -> DynamicResult<Box<dyn Write>> {
+    Ok(match m.value_of("output") {
+        Some(p) => Box::new(File::create(p)?),
+        None => Box::new(io::stdout()),
+    })
+}
+
+fn project_arg<'a, 'b>(help: &'b str) -> Arg<'a, 'b> {
+    Arg::with_name("project")
+        .short("p")
+        .long("project")
+        .takes_value(true)
+        .help(help)
+}
+
+fn checks_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("checks")
+        .long("checks")
+        .takes_value(true)
+        .possible_values(&["error", "warn"])
+        .default_value("error")
+        .help("Whether failing design checks are errors or only warnings")
+}
+
+fn variant_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("variant")
+        .long("variant")
+        .takes_value(true)
+        .value_name("NAME")
+        .help("Select the given assembly variant, leaving out DNP parts (\"default\" only leaves out DNP parts)")
+}
+
+fn watch_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("watch")
+        .short("w")
+        .long("watch")
+        .help("Keep running, and re-evaluate each time the schematic or policy is saved")
+}
+
+fn cue_bin_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("cue-bin")
+        .long("cue-bin")
+        .takes_value(true)
+        .default_value("cue")
+        .env("CUE_BIN")
+        .help("Path to the cue binary. Download from cuelang.org.")
+}
+
+fn no_cache_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("no-cache")
+        .long("no-cache")
+        .help("Always run cue, instead of reusing the results of earlier runs with the same inputs, which are cached in $KICAD_RS_CACHE_DIR or ~/.cache/kicad-rs by default")
+}
+
+fn input_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("input")
+        .short("i")
+        .long("input")
+        .takes_value(true)
+        .help("Path to the schematic YAML or JSON to read, read from stdin if not given")
+}
+
+fn output_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("output")
+        .short("o")
+        .long("output")
+        .takes_value(true)
+        .help("Path to the file to write, written to stdout if not given")
+}
+
+fn output_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        output_arg(),
+        Arg::with_name("format")
+            .short("f")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["yaml", "json"])
+            .default_value("yaml")
+            .help("Output format of the schematic"),
+    ]
+}
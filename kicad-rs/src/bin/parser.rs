@@ -1,22 +1,51 @@
+use clap::{App, Arg};
 use kicad_rs::codec;
 use kicad_rs::error::DynamicResult;
 use kicad_rs::parser::SchematicTree;
-use std::env;
+use kicad_rs::project::Project;
 use std::io;
 use std::path::Path;
 
+// Get crate version information from Cargo
+const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+
 // Main function, can return different kinds of errors
 fn main() -> DynamicResult<()> {
-    // Read the first argument as the path to the .sch file
-    let args: Vec<String> = env::args().collect();
-    let p = Path::new(
-        args.get(1)
-            .ok_or("expected KiCad schematic file as first argument")?,
-    );
+    let matches = App::new("KiCad parser")
+        .about("Parses KiCad Eeschema schematics into YAML")
+        .author("The Racklet Project")
+        .version(VERSION.unwrap_or("unknown"))
+        .version_short("v")
+        .arg(
+            Arg::with_name("SCHEMATIC")
+                .help("Path to the KiCad schematic file to parse")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("variant")
+                .long("variant")
+                .takes_value(true)
+                .value_name("NAME")
+                .help("Select the given assembly variant, leaving out DNP parts"),
+        )
+        .get_matches();
+
+    // Calling .unwrap() is safe here because "SCHEMATIC" is required
+    let p = Path::new(matches.value_of("SCHEMATIC").unwrap());
 
     // Parse the schematic file
-    let tree = SchematicTree::load(&p)?;
-    let sch = tree.parse()?;
+    let tree = SchematicTree::load(p)?;
+    let mut sch = tree.parse()?;
+
+    // Include the globals of the project file next to the schematic, if any
+    if let Some(project) = Project::discover(p)? {
+        project.merge_globals(&mut sch)?;
+    }
+
+    // Narrow the schematic down to the requested assembly variant
+    if let Some(variant) = matches.value_of("variant") {
+        sch.apply_variant(variant)?;
+    }
 
     // Marshal as YAML
     codec::marshal_yaml(&sch, io::stdout())?;
@@ -0,0 +1,12 @@
+use kicad_rs::error::DynamicResult;
+use kicad_rs::schema;
+use std::io::{self, Write};
+
+// Main function, can return different kinds of errors
+fn main() -> DynamicResult<()> {
+    // Write the JSON Schema of the parser and classifier output to stdout
+    let mut stdout = io::stdout();
+    serde_json::to_writer_pretty(&mut stdout, &schema::schematic_schema())?;
+    writeln!(stdout)?;
+    Ok(())
+}
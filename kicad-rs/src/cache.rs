@@ -0,0 +1,155 @@
+use crate::error::DynamicResult;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+use tempfile::NamedTempFile;
+
+// The environment variable overriding the directory of the cache
+pub const CACHE_DIR_ENV: &str = "KICAD_RS_CACHE_DIR";
+
+// Cache is a content-addressed store for the output of expensive commands (i.e. running cue),
+// with each output stored in a file named by the SHA-256 hash of all the inputs of the command
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    // The directory given by $KICAD_RS_CACHE_DIR, or "kicad-rs" in the user's cache
+    // directory ($XDG_CACHE_HOME or ~/.cache). None if neither can be determined.
+    pub fn default_dir() -> Option<PathBuf> {
+        let var = |name: &str| {
+            env::var_os(name)
+                .filter(|v| !v.is_empty())
+                .map(PathBuf::from)
+        };
+        var(CACHE_DIR_ENV)
+            .or_else(|| var("XDG_CACHE_HOME").map(|d| d.join("kicad-rs")))
+            .or_else(|| var("HOME").map(|d| d.join(".cache").join("kicad-rs")))
+    }
+
+    // Compute the key for the given inputs. Each input is prefixed by its length, so that
+    // moving bytes from one input to the next changes the key.
+    pub fn key(inputs: &[&[u8]]) -> String {
+        let mut hasher = Sha256::new();
+        for input in inputs.iter() {
+            hasher.update((input.len() as u64).to_le_bytes());
+            hasher.update(input);
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        fs::read(self.dir.join(key)).ok()
+    }
+
+    // Store the data for the key. The file is written under a temporary name and then
+    // renamed, so that concurrent runs never read a partially written entry.
+    pub fn put(&self, key: &str, data: &[u8]) -> DynamicResult<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut f = NamedTempFile::new_in(&self.dir)?;
+        f.write_all(data)?;
+        f.persist(self.dir.join(key))?;
+        Ok(())
+    }
+
+    // Return the cached data for the inputs, or compute and store it. Only successful
+    // results are stored, and failing to store them doesn't fail the computation.
+    pub fn get_or_insert_with<F>(&self, inputs: &[&[u8]], f: F) -> DynamicResult<Vec<u8>>
+    where
+        F: FnOnce() -> DynamicResult<Vec<u8>>,
+    {
+        let key = Cache::key(inputs);
+        if let Some(data) = self.get(&key) {
+            return Ok(data);
+        }
+        let data = f()?;
+        let _ = self.put(&key, &data);
+        Ok(data)
+    }
+
+    // Return the output of "<bin> version", which is cached by the path, size and modification
+    // time of the binary, so that it's only run again once the binary changes. It's run every
+    // time if the binary can't be found.
+    pub fn version_of(&self, bin: &Path) -> DynamicResult<Vec<u8>> {
+        let run =
+            || -> DynamicResult<Vec<u8>> { Ok(Command::new(bin).arg("version").output()?.stdout) };
+        match binary_identity(bin) {
+            Some(identity) => self.get_or_insert_with(&[b"version", &identity], run),
+            None => run(),
+        }
+    }
+}
+
+// binary_identity identifies the given binary by its canonical path, size and modification
+// time. Binaries given by name only (e.g. "cue") are looked up in $PATH.
+fn binary_identity(bin: &Path) -> Option<Vec<u8>> {
+    let path = if bin.components().count() > 1 {
+        bin.to_path_buf()
+    } else {
+        env::split_paths(&env::var_os("PATH")?)
+            .map(|dir| dir.join(bin))
+            .find(|p| p.is_file())?
+    };
+    let path = fs::canonicalize(path).ok()?;
+    let metadata = fs::metadata(&path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+
+    let mut identity = path.to_string_lossy().into_owned().into_bytes();
+    identity.extend_from_slice(&metadata.len().to_le_bytes());
+    identity.extend_from_slice(&modified.as_nanos().to_le_bytes());
+    Some(identity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn cache_outputs() {
+        let dir = tempdir().unwrap();
+        let cache = Cache::new(dir.path().join("cache"));
+        let inputs: &[&[u8]] = &[b"policy", b"schematic"];
+
+        let data = cache
+            .get_or_insert_with(inputs, || Ok(b"classified".to_vec()))
+            .unwrap();
+        assert_eq!(data, b"classified");
+        let data = cache
+            .get_or_insert_with(inputs, || panic!("the output should be cached"))
+            .unwrap();
+        assert_eq!(data, b"classified");
+
+        assert!(cache
+            .get_or_insert_with(&[b"invalid"], || Err("cue failed".into()))
+            .is_err());
+        assert!(cache.get(&Cache::key(&[b"invalid"])).is_none());
+        assert_ne!(Cache::key(&[b"ab", b"c"]), Cache::key(&[b"a", b"bc"]));
+    }
+
+    #[test]
+    fn identify_binaries() {
+        let dir = tempdir().unwrap();
+        let bin = dir.path().join("cue");
+        fs::write(&bin, b"v1").unwrap();
+        let identity = binary_identity(&bin).unwrap();
+        assert_eq!(binary_identity(&bin), Some(identity.clone()));
+
+        // Replacing the binary changes its identity
+        fs::write(&bin, b"v0.4").unwrap();
+        assert_ne!(binary_identity(&bin), Some(identity));
+        assert_eq!(binary_identity(&dir.path().join("missing")), None);
+    }
+}
@@ -19,6 +19,37 @@ where
     Ok(())
 }
 
+// Format is a serialization format supported for output. For input, YAML is always
+// used, as it's a superset of JSON.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Yaml,
+    Json,
+}
+
+// marshal marshals a serializable value in the given format
+pub fn marshal<T, W>(format: Format, data: T, writer: W) -> DynamicResult<()>
+where
+    T: Serialize,
+    W: io::Write,
+{
+    match format {
+        Format::Yaml => marshal_yaml(data, writer),
+        Format::Json => marshal_json(data, writer),
+    }
+}
+
+// marshal_json marshals a serializable value to a pretty-printed JSON string using Serde
+pub fn marshal_json<T, W>(data: T, mut writer: W) -> DynamicResult<()>
+where
+    T: Serialize,
+    W: io::Write,
+{
+    serde_json::to_writer_pretty(&mut writer, &data)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
 // unmarshal_yaml is the reverse operation of marshal_yaml
 pub fn unmarshal_yaml<R, T>(reader: R) -> serde_yaml::Result<T>
 where
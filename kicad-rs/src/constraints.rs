@@ -0,0 +1,468 @@
+use crate::eval::parse_quantity;
+use crate::labels::{Labels, LabelsMatch};
+use crate::requirements::{Requirement, Threshold};
+use crate::types::{Component, Schematic};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+// SchematicConstraint is a rule spanning all the components of the schematic hierarchy,
+// which can't be expressed for each component or sheet on its own in the CUE policy
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct SchematicConstraint {
+    // The name the constraint is reported and waived by
+    pub name: String,
+    // Only violations of the error severity fail the policy
+    #[serde(default)]
+    pub severity: Severity,
+    // Only the components assigned this class are constrained, or all if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub class: Option<String>,
+    // Require every constrained component to match these label and attribute requirements
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    #[serde(default)]
+    pub require: HashMap<String, Requirement>,
+    // Group the constrained components, and apply the limits below to each group
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub group_by: Option<GroupBy>,
+    // Allow at most the given number of distinct values of a label or attribute
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub max_distinct: Option<MaxDistinct>,
+    // Require the values of this label or attribute to be unique, e.g. "MountingPosition"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub unique: Option<String>,
+    // Limit the number of components
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub count: Option<Count>,
+    // Limit the sum of the numeric values of a label or attribute
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub sum: Option<Sum>,
+}
+
+// GroupBy groups components by the value of a label or attribute (e.g. "I2CBus"), and/or
+// by the sheet they're in. Components without the key aren't constrained.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct GroupBy {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub sheet: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct MaxDistinct {
+    // The label or attribute, e.g. "Value". Components without it aren't counted.
+    pub key: String,
+    pub max: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct Count {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub min: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub max: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct Sum {
+    // The label or attribute, e.g. "Current". Components without a numeric value aren't summed.
+    pub key: String,
+    pub max: Threshold,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum Severity {
+    #[default]
+    Error,
+    Warning,
+    Info,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Info => write!(f, "info"),
+        }
+    }
+}
+
+// Violation describes how the schematic violates a constraint, or the #Policy of a class. It's
+// waived if all the components it concerns waive the constraint.
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    // The name of the constraint, or the class of the policy
+    pub constraint: String,
+    pub severity: Severity,
+    pub references: Vec<String>,
+    pub message: String,
+    pub waived: bool,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.waived {
+            write!(f, "waived ")?;
+        }
+        write!(
+            f,
+            "{}: {}: {}",
+            self.severity, self.constraint, self.message
+        )
+    }
+}
+
+// check_constraints lists all the violations of the constraints in the schematic
+pub fn check_constraints(sch: &Schematic, constraints: &[SchematicConstraint]) -> Vec<Violation> {
+    let mut components = Vec::new();
+    collect_components(sch, &mut vec![], &mut components);
+
+    let mut violations = Vec::new();
+    for constraint in constraints.iter() {
+        let constrained: Vec<(&str, &Component)> = components
+            .iter()
+            .map(|(sheet, c)| (sheet.as_str(), *c))
+            .filter(|(_, c)| match &constraint.class {
+                Some(class) => c.classes.contains(class),
+                None => true,
+            })
+            .collect();
+
+        for (_, comp) in constrained.iter() {
+            let mut failed: Vec<&str> = constraint
+                .require
+                .iter()
+                .filter(|(_, r)| !r.matches(*comp))
+                .map(|(name, _)| name.as_str())
+                .collect();
+            if !failed.is_empty() {
+                failed.sort_unstable();
+                let message = format!(
+                    "{} doesn't meet {}",
+                    comp.labels.reference,
+                    failed.join(", ")
+                );
+                violations.push(violation(constraint, &[comp], message));
+            }
+        }
+
+        for (group, members) in group_components(&constrained, constraint.group_by.as_ref()) {
+            check_group(constraint, &group, &members, &mut violations);
+        }
+    }
+    violations
+}
+
+// check_group checks the limits of the constraint on a group of components, described by
+// the given group name (empty if the components aren't grouped)
+fn check_group(
+    constraint: &SchematicConstraint,
+    group: &str,
+    members: &[&Component],
+    violations: &mut Vec<Violation>,
+) {
+    let references = |components: &[&Component]| {
+        components
+            .iter()
+            .map(|c| c.labels.reference.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    if let Some(max_distinct) = &constraint.max_distinct {
+        let mut by_value: BTreeMap<String, Vec<&Component>> = BTreeMap::new();
+        for comp in members.iter() {
+            if let Some(value) = comp.get_label(&max_distinct.key) {
+                by_value.entry(value).or_default().push(comp);
+            }
+        }
+        if by_value.len() > max_distinct.max {
+            let message = format!(
+                "{} distinct values of {}{}, at most {} allowed: {}",
+                by_value.len(),
+                max_distinct.key,
+                group,
+                max_distinct.max,
+                by_value
+                    .iter()
+                    .map(|(value, components)| format!("{} ({})", value, references(components)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            let components: Vec<&Component> = by_value.values().flatten().copied().collect();
+            violations.push(violation(constraint, &components, message));
+        }
+    }
+
+    if let Some(key) = &constraint.unique {
+        let mut by_value: BTreeMap<String, Vec<&Component>> = BTreeMap::new();
+        for comp in members.iter() {
+            if let Some(value) = comp.get_label(key) {
+                by_value.entry(value).or_default().push(comp);
+            }
+        }
+        for (value, clashing) in by_value.iter().filter(|(_, c)| c.len() > 1) {
+            let message = format!(
+                "{} {} is not unique{}: {}",
+                key,
+                value,
+                group,
+                references(clashing)
+            );
+            violations.push(violation(constraint, clashing, message));
+        }
+    }
+
+    if let Some(count) = &constraint.count {
+        let n = members.len();
+        let message = match (count.min, count.max) {
+            (Some(min), _) if n < min => Some(format!("at least {} allowed", min)),
+            (_, Some(max)) if n > max => Some(format!("at most {} allowed", max)),
+            _ => None,
+        };
+        if let Some(message) = message {
+            let message = format!(
+                "{} component(s){}, {}: {}",
+                n,
+                group,
+                message,
+                references(members)
+            );
+            violations.push(violation(constraint, members, message));
+        }
+    }
+
+    if let Some(sum) = &constraint.sum {
+        let summed: Vec<(&Component, f64)> = members
+            .iter()
+            .filter_map(|c| {
+                c.get_label(&sum.key)
+                    .and_then(|v| parse_quantity(&v))
+                    .map(|v| (*c, v))
+            })
+            .collect();
+        let total: f64 = summed.iter().map(|(_, v)| v).sum();
+        match sum.max.value() {
+            Some(max) if total > max => {
+                let components: Vec<&Component> = summed.iter().map(|(c, _)| *c).collect();
+                let message = format!(
+                    "the sum of {}{} is {}, at most {} allowed: {}",
+                    sum.key,
+                    group,
+                    total,
+                    max,
+                    references(&components)
+                );
+                violations.push(violation(constraint, &components, message));
+            }
+            _ => (),
+        }
+    }
+}
+
+// violation creates a violation of the constraint concerning the given components
+fn violation(
+    constraint: &SchematicConstraint,
+    components: &[&Component],
+    message: String,
+) -> Violation {
+    Violation {
+        constraint: constraint.name.clone(),
+        severity: constraint.severity,
+        references: components
+            .iter()
+            .map(|c| c.labels.reference.clone())
+            .collect(),
+        message,
+        waived: !components.is_empty()
+            && components
+                .iter()
+                .all(|c| c.labels.waivers.contains_key(&constraint.name)),
+    }
+}
+
+// group_components groups the components as specified, naming each group for reporting. The
+// components form a single group with an empty name if they aren't grouped.
+fn group_components<'c>(
+    components: &[(&str, &'c Component)],
+    group_by: Option<&GroupBy>,
+) -> BTreeMap<String, Vec<&'c Component>> {
+    let mut groups: BTreeMap<String, Vec<&Component>> = BTreeMap::new();
+    for (sheet, comp) in components.iter() {
+        let mut names = vec![];
+        if let Some(key) = group_by.and_then(|g| g.key.as_ref()) {
+            match comp.get_label(key) {
+                Some(value) => names.push(format!("{} {}", key, value)),
+                None => continue,
+            }
+        }
+        if group_by.map(|g| g.sheet).unwrap_or(false) {
+            names.push(format!("sheet {}", sheet));
+        }
+        let name = if names.is_empty() {
+            String::new()
+        } else {
+            format!(" in {}", names.join(", "))
+        };
+        groups.entry(name).or_default().push(comp);
+    }
+    groups
+}
+
+// collect_components lists the components of the schematic hierarchy with the path of the
+// sheet they're in (e.g. "/Power/Buck"), built from the names of the sheets like for classifiers
+fn collect_components<'s>(
+    sch: &'s Schematic,
+    prefix: &mut Vec<String>,
+    components: &mut Vec<(String, &'s Component)>,
+) {
+    let path = format!("/{}", prefix.join("/"));
+    components.extend(sch.components.values().map(|c| (path.clone(), c)));
+    for (name, sub_schematic) in sch.sub_schematics.iter() {
+        prefix.push(name.clone());
+        collect_components(sub_schematic, prefix, components);
+        prefix.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{attribute, component, schematic};
+    use crate::types::Attribute;
+
+    #[test]
+    fn violations_and_waivers() {
+        let constraints: Vec<SchematicConstraint> = serde_yaml::from_str(
+            "
+- name: resistor_values
+  class: resistor
+  severity: warning
+  maxDistinct: {key: Value, max: 1}
+- name: footprints
+  maxDistinct: {key: footprintName, max: 1}
+- name: lt_5
+  class: resistor
+  require: {tolerance: {op: Exists, key: tolerance}}
+",
+        )
+        .unwrap();
+        let resistor = |reference: &str, value: &str, expression: &str| {
+            let mut r = component(
+                reference,
+                vec![(
+                    "Value",
+                    Attribute {
+                        value: value.into(),
+                        ..attribute(expression, None)
+                    },
+                )],
+            );
+            r.labels.footprint_name = "R_0402".into();
+            r.classes = vec!["resistor".into()];
+            r
+        };
+        let mut r1 = resistor("R1", "10k", "10e3");
+        r1.labels.waivers.insert(
+            "lt_5".into(),
+            "tolerance is irrelevant for a pull-up".into(),
+        );
+        let mut sch = schematic(vec![r1]);
+        sch.sub_schematics.insert(
+            "Power".into(),
+            schematic(vec![resistor("R2", "4.7k", "4.7e3")]),
+        );
+
+        let violations: Vec<String> = check_constraints(&sch, &constraints)
+            .iter()
+            .map(|v| v.to_string())
+            .collect();
+        assert_eq!(
+            violations,
+            vec![
+                "warning: resistor_values: 2 distinct values of Value, at most 1 allowed: 10k (R1), 4.7k (R2)",
+                "waived error: lt_5: R1 doesn't meet tolerance",
+                "error: lt_5: R2 doesn't meet tolerance",
+            ]
+        );
+    }
+
+    #[test]
+    fn grouped_limits() {
+        let constraints: Vec<SchematicConstraint> = serde_yaml::from_str(
+            "
+- name: i2c_addresses
+  groupBy: {key: I2CBus}
+  unique: I2CAddress
+- name: sensors_per_sheet
+  groupBy: {sheet: true}
+  count: {max: 1}
+  sum: {key: Current, max: 10m}
+",
+        )
+        .unwrap();
+        let sensor = |reference: &str, bus: &str, address: &str| {
+            let mut u = component(
+                reference,
+                vec![(
+                    "Current",
+                    Attribute {
+                        value: "6 mA".into(),
+                        ..attribute("6e-3", None)
+                    },
+                )],
+            );
+            u.labels.extra.insert("I2CBus".into(), bus.into());
+            u.labels.extra.insert("I2CAddress".into(), address.into());
+            u
+        };
+        let mut sch = schematic(vec![]);
+        sch.sub_schematics.insert(
+            "Sensors".into(),
+            schematic(vec![
+                sensor("U1", "SDA1", "0x48"),
+                sensor("U2", "SDA1", "0x48"),
+            ]),
+        );
+        sch.sub_schematics.insert(
+            "Display".into(),
+            schematic(vec![sensor("U3", "SDA2", "0x48")]),
+        );
+
+        let violations: Vec<String> = check_constraints(&sch, &constraints)
+            .iter()
+            .map(|v| v.to_string())
+            .collect();
+        assert_eq!(
+            violations,
+            vec![
+                "error: i2c_addresses: I2CAddress 0x48 is not unique in I2CBus SDA1: U1, U2",
+                "error: sensors_per_sheet: 2 component(s) in sheet /Sensors, at most 1 allowed: U1, U2",
+                "error: sensors_per_sheet: the sum of Current in sheet /Sensors is 0.012, at most 0.01 allowed: U1, U2",
+            ]
+        );
+    }
+}
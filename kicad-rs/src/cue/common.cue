@@ -5,27 +5,48 @@
 // we can enforce subSchematics to also abide by the same schema.
 schematic: #Schematic
 #Schematic: {
+    // Only set for the top-level schematic, see types.rs and schema/schematic.schema.json
+    apiVersion?: "kicad.racklet.io/v1alpha1"
+    kind?: "Schematic"
+    meta: #Meta
+    globals?: [string]: #Attribute
     // The most important field of stdin that should be validated is the components map.
     // The components map uses the component reference as a key.
     components: [string]: #Component
     // Support nested schematics
     subSchematics: [string]: #Schematic
-    // TODO: Maybe enforce other rules on globals etc,?
     ...
 }
 
+#Meta: {
+    filename?: string
+    title?: string
+    date?: string
+    revision?: string
+    company?: string
+    comments?: [...string]
+    variant?: string
+}
+
 #Component: {
     labels: #Labels
     attributes: [string]: #Attribute
+    checks?: [string]: #Attribute
+    units?: [string]: #Unit
     classes: [...string]
 }
 
+#Unit: {
+    expressions?: [...string]
+}
+
 #Attribute: {
     value: string | float
     expression: string
     type: "Float" | "String"
     unit?: string
     comment?: string
+    format?: string
 }
 
 #Labels: {
@@ -36,5 +57,13 @@ schematic: #Schematic
     symbolName: string
     model?: string
     datasheet?: string
+    dnp?: bool
+    variants?: [string]: #Variant
+    waivers?: [string]: string
     extra: [string]: string
 }
+
+#Variant: {
+    dnp?: bool
+    expressions?: [string]: string
+}
@@ -3,6 +3,15 @@
 // the component
 // This file depends on common.cue
 
+// Apply the sheet policies of policy_schema.cue to every sheet, and to the root schematic
+#Schematic: #SheetPolicy
+schematic: #RootPolicy
+
+// The classes whose policy isn't applied to a component, keyed by reference. Set by the
+// classifier for the policies a component waives, and for conflicting policies of the
+// "warning" and "info" severities.
+#Skipped: [string]: [string]: true
+
 #Component: {
     labels: #Labels
     attributes: [string]: #Attribute
@@ -10,7 +19,7 @@
 
     for comp_class in classes {
         for pol_class, pol_class_spec in #Policy {
-            if pol_class == comp_class {
+            if pol_class == comp_class && #Skipped[labels.reference][pol_class] == _|_ {
                 generated: "\(comp_class)": {
                     // Merge attributes from both the class spec and the component
                     for attr, attr_spec in pol_class_spec.attributes {
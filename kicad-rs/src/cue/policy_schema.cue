@@ -2,12 +2,24 @@
 // This file depends on common.cue
 
 // The user-defined #Policy object specifies what attributes and labels are mandated
-// for each class, along with any needed defaults
+// for each class, along with any needed defaults. Only conflicts with the policy of the
+// "error" severity fail the classifier, and components can waive the policy of a class.
 #Policy: [string]: {
+    severity: *"error" | "warning" | "info"
     attributes: [string]: #Attribute
     labels: #Labels
 }
 
+// The user-defined #SheetPolicy object is unified with every sheet of the schematic, and
+// #RootPolicy with the root schematic only. They can e.g. require meta fields and globals:
+// #RootPolicy: meta: {revision: string, company: "Racklet"}
+#SheetPolicy: {
+    meta?: #Meta
+    globals?: [string]: #Attribute
+    ...
+}
+#RootPolicy: #SheetPolicy
+
 // The user-defined #Classifiers object specifies how various attributes are labels
 // can be used to classify a component to belonging to a class.
 #Classifiers: [...#Classifier]
@@ -15,12 +27,70 @@
     class: string
     labels?: #RequirementMap
     attributes?: #RequirementMap
+    // Matches the "path" and "filename" of the sheet the component is in
+    sheet?: #RequirementMap
+    // Matches the classes already assigned to the component, e.g. with the Exists operator
+    classes?: #RequirementMap
 }
 
 // The requirement string can be arbitrarily defined, it's used to enable
 // the merging of classes extending each other
-#RequirementMap: [string]: {
+#RequirementMap: [string]: #Requirement
+
+// Numeric values may be given as numbers, or as strings like "100n" or "100 nF".
+// The AnyOf, AllOf and Not operators group the requirements nested in them.
+#Requirement: {
     key: string
     op: string
-    values: [...string] | [float]
+    values?: [...(string | number)]
+} | {
+    op: "AnyOf" | "AllOf" | "Not"
+    requirements: [...#Requirement]
+}
+
+// The user-defined #Constraints object specifies rules on the components of the schematic,
+// which are checked after the policy has been applied. Only violations of the "error"
+// severity that aren't waived by all the components concerned fail the policy.
+#Constraints: [...#Constraint]
+#Constraint: {
+    name: string
+    severity?: "error" | "warning" | "info"
+    // Only constrain the components assigned this class
+    class?: string
+    // Every component must match these requirements
+    require?: #RequirementMap
+    // Apply the limits below to each group of components with the same value of the label
+    // or attribute key, and/or in the same sheet
+    groupBy?: {
+        key?: string
+        sheet?: bool
+    }
+    // At most max distinct values of the label or attribute key
+    maxDistinct?: {
+        key: string
+        max: int & >=0
+    }
+    // The values of the label or attribute must be unique
+    unique?: string
+    // The number of components
+    count?: {
+        min?: int & >=0
+        max?: int & >=0
+    }
+    // The sum of the numeric values of the label or attribute key, e.g. max: "500m"
+    sum?: {
+        key: string
+        max: string | number
+    }
+}
+
+// #Rules are exported by the classifier, and evaluated outside of CUE
+#Rules: {
+    classifiers: #Classifiers
+    constraints: #Constraints
+    severities: {
+        for class, policy in #Policy {
+            "\(class)": policy.severity
+        }
+    }
 }
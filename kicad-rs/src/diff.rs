@@ -0,0 +1,488 @@
+use crate::codec;
+use crate::error::{errorf, DynamicResult};
+use crate::parser::SchematicTree;
+use crate::types::{Attribute, Component, Schematic};
+use kicad_parse_gen::schematic as kicad_schematic;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::path::{Component as PathComponent, Path};
+use std::process::Command;
+use tempfile::tempdir;
+
+// ChangeKind describes what kind of change was made to the subject of a Change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Label,
+    Expression,
+    Value,
+    Unit,
+    Comment,
+    Classes,
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Label => "label",
+            ChangeKind::Expression => "expression",
+            ChangeKind::Value => "value",
+            ChangeKind::Unit => "unit",
+            ChangeKind::Comment => "comment",
+            ChangeKind::Classes => "classes",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// Change is a single semantic change between two schematic revisions. The subject is e.g.
+// "R1" for a component, "R1.Value" for an attribute, "R1.footprintName" for a label,
+// "Globals.Vin" for a global or "sheet power" for a hierarchical sheet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub subject: String,
+    pub kind: ChangeKind,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+// SheetDiff holds the changes within a single sheet, identified by its
+// path of sheet names in the hierarchy, e.g. "/" or "/power"
+#[derive(Debug, PartialEq)]
+pub struct SheetDiff {
+    pub path: String,
+    pub changes: Vec<Change>,
+}
+
+// Format selects how a diff is rendered by write_diff
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Markdown,
+}
+
+// load_schematic loads the schematic at the given path either from the working tree, or
+// from the given git revision. Paths ending in .yaml or .yml are read as the output of
+// the parser or classifier (the latter also containing classes), all other paths are
+// parsed as KiCad schematics.
+pub fn load_schematic(path: &Path, rev: Option<&str>) -> DynamicResult<Schematic> {
+    let is_yaml = matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("yaml") | Some("yml")
+    );
+
+    match (rev, is_yaml) {
+        (None, true) => decode_yaml(File::open(path)?),
+        (None, false) => SchematicTree::load(path)?.parse(),
+        (Some(rev), true) => decode_yaml(&git_show(rev, path)?[..]),
+        (Some(rev), false) => {
+            // Sub-sheets are looked up relative to their parent schematic, so write the
+            // whole hierarchy at the given revision into a temporary directory first
+            let tmp_dir = tempdir()?;
+            let root = tmp_dir.path().join(relative_path(path)?);
+            checkout_revision(rev, tmp_dir.path(), &root)?;
+            SchematicTree::load(&root)?.parse()
+        }
+    }
+}
+
+fn decode_yaml<R: std::io::Read>(reader: R) -> DynamicResult<Schematic> {
+    let sch: Schematic = codec::unmarshal_yaml(reader)?;
+    sch.check_type_meta()?;
+    Ok(sch)
+}
+
+// checkout_revision writes the schematic at the given path inside the temporary directory
+// and recursively all its sub-sheets, reading their contents from the given revision
+fn checkout_revision(rev: &str, tmp_dir: &Path, path: &Path) -> DynamicResult<()> {
+    let rel_path = path.strip_prefix(tmp_dir)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, git_show(rev, rel_path)?)?;
+
+    let schematic = kicad_schematic::parse_file(path)?;
+    for sub_sheet in schematic.sheets.iter() {
+        let filename = kicad_schematic::filename_for_sheet(&schematic, sub_sheet)?;
+        checkout_revision(rev, tmp_dir, &filename)?;
+    }
+
+    Ok(())
+}
+
+// git_show reads the contents of the given path (relative to the current
+// directory) at the given revision
+fn git_show(rev: &str, path: &Path) -> DynamicResult<Vec<u8>> {
+    let output = Command::new("git")
+        .arg("show")
+        .arg(format!("{}:./{}", rev, path.display()))
+        .output()?;
+    if !output.status.success() {
+        return Err(errorf(&format!(
+            "git show {}:{} failed: {}",
+            rev,
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(output.stdout)
+}
+
+// relative_path makes sure the given path can be looked up from the current directory
+// using git, and placed inside a temporary directory
+fn relative_path(path: &Path) -> DynamicResult<&Path> {
+    if path
+        .components()
+        .any(|c| !matches!(c, PathComponent::Normal(_) | PathComponent::CurDir))
+    {
+        return Err(errorf(&format!(
+            "the path of a schematic at a git revision must be relative, and within the current directory: {}",
+            path.display()
+        )));
+    }
+
+    Ok(path)
+}
+
+// diff compares the two schematics recursively, and returns the changes per sheet path.
+// Sheets without any changes are omitted.
+pub fn diff(old: &Schematic, new: &Schematic) -> Vec<SheetDiff> {
+    let mut sheets = Vec::new();
+    diff_sheet("", old, new, &mut sheets);
+    sheets
+}
+
+fn diff_sheet(path: &str, old: &Schematic, new: &Schematic, sheets: &mut Vec<SheetDiff>) {
+    let mut changes = Vec::new();
+
+    diff_attributes("Globals", &old.globals, &new.globals, &mut changes);
+
+    for reference in sorted_keys(&old.components, &new.components) {
+        match (old.components.get(reference), new.components.get(reference)) {
+            (Some(o), Some(n)) => diff_component(reference, o, n, &mut changes),
+            (o, n) => changes.push(Change {
+                subject: reference.into(),
+                kind: if o.is_some() {
+                    ChangeKind::Removed
+                } else {
+                    ChangeKind::Added
+                },
+                old: o.map(describe_component),
+                new: n.map(describe_component),
+            }),
+        }
+    }
+
+    let sheet_ids = sorted_keys(&old.sub_schematics, &new.sub_schematics);
+    for sheet_id in sheet_ids.iter() {
+        match (
+            old.sub_schematics.get(*sheet_id),
+            new.sub_schematics.get(*sheet_id),
+        ) {
+            (Some(_), Some(_)) => (),
+            (o, _) => changes.push(Change {
+                subject: format!("sheet {}", sheet_id),
+                kind: if o.is_some() {
+                    ChangeKind::Removed
+                } else {
+                    ChangeKind::Added
+                },
+                old: None,
+                new: None,
+            }),
+        }
+    }
+
+    if !changes.is_empty() {
+        sheets.push(SheetDiff {
+            path: if path.is_empty() {
+                "/".into()
+            } else {
+                path.into()
+            },
+            changes,
+        });
+    }
+
+    // Recurse into the sheets present in both revisions
+    for sheet_id in sheet_ids {
+        if let (Some(o), Some(n)) = (
+            old.sub_schematics.get(sheet_id),
+            new.sub_schematics.get(sheet_id),
+        ) {
+            diff_sheet(&format!("{}/{}", path, sheet_id), o, n, sheets);
+        }
+    }
+}
+
+fn diff_component(reference: &str, old: &Component, new: &Component, changes: &mut Vec<Change>) {
+    let old_labels = label_map(old);
+    let new_labels = label_map(new);
+    for name in sorted_keys(&old_labels, &new_labels) {
+        push_if_changed(
+            changes,
+            format!("{}.{}", reference, name),
+            ChangeKind::Label,
+            old_labels.get(name).map(|s| s.to_string()),
+            new_labels.get(name).map(|s| s.to_string()),
+        );
+    }
+
+    diff_attributes(reference, &old.attributes, &new.attributes, changes);
+    diff_attributes(reference, &old.checks, &new.checks, changes);
+
+    push_if_changed(
+        changes,
+        reference.into(),
+        ChangeKind::Classes,
+        describe_classes(&old.classes),
+        describe_classes(&new.classes),
+    );
+}
+
+fn diff_attributes(
+    prefix: &str,
+    old: &BTreeMap<String, Attribute>,
+    new: &BTreeMap<String, Attribute>,
+    changes: &mut Vec<Change>,
+) {
+    for name in sorted_keys(old, new) {
+        let subject = format!("{}.{}", prefix, name);
+        let (o, n) = match (old.get(name), new.get(name)) {
+            (Some(o), Some(n)) => (o, n),
+            (o, n) => {
+                changes.push(Change {
+                    subject,
+                    kind: if o.is_some() {
+                        ChangeKind::Removed
+                    } else {
+                        ChangeKind::Added
+                    },
+                    old: o.map(describe_attribute),
+                    new: n.map(describe_attribute),
+                });
+                continue;
+            }
+        };
+
+        let kinds = vec![
+            (
+                ChangeKind::Expression,
+                Some(o.expression.clone()),
+                Some(n.expression.clone()),
+            ),
+            (
+                ChangeKind::Value,
+                Some(o.value.to_string()),
+                Some(n.value.to_string()),
+            ),
+            (ChangeKind::Unit, o.unit.clone(), n.unit.clone()),
+            (ChangeKind::Comment, o.comment.clone(), n.comment.clone()),
+        ];
+        for (kind, old_str, new_str) in kinds {
+            push_if_changed(changes, subject.clone(), kind, old_str, new_str);
+        }
+    }
+}
+
+fn push_if_changed(
+    changes: &mut Vec<Change>,
+    subject: String,
+    kind: ChangeKind,
+    old: Option<String>,
+    new: Option<String>,
+) {
+    if old != new {
+        changes.push(Change {
+            subject,
+            kind,
+            old,
+            new,
+        });
+    }
+}
+
+// sorted_keys returns the union of the keys of both maps in sorted order
+fn sorted_keys<'a, K: Ord, A, B>(a: &'a BTreeMap<K, A>, b: &'a BTreeMap<K, B>) -> BTreeSet<&'a K> {
+    a.keys().chain(b.keys()).collect()
+}
+
+// label_map lists all labels of the component, including the optional ones
+fn label_map(c: &Component) -> BTreeMap<&str, &str> {
+    let mut m = c.labels.to_map();
+    if let Some(model) = &c.labels.model {
+        m.insert("model", model);
+    }
+    if let Some(datasheet) = &c.labels.datasheet {
+        m.insert("datasheet", datasheet);
+    }
+    m
+}
+
+fn describe_component(c: &Component) -> String {
+    format!("{}:{}", c.labels.symbol_library, c.labels.symbol_name)
+}
+
+fn describe_attribute(a: &Attribute) -> String {
+    let value = a.value.to_string();
+    if a.expression.is_empty() || a.expression == value {
+        value
+    } else {
+        format!("{} = {}", a.expression, value)
+    }
+}
+
+fn describe_classes(classes: &[String]) -> Option<String> {
+    if classes.is_empty() {
+        return None;
+    }
+
+    let mut classes = classes.to_vec();
+    classes.sort();
+    Some(classes.join(", "))
+}
+
+// write_diff renders the given sheet diffs in the given format. The Markdown
+// format is suitable for e.g. posting as a comment on a pull request.
+pub fn write_diff<W: std::io::Write>(
+    sheets: &[SheetDiff],
+    format: Format,
+    w: &mut W,
+) -> DynamicResult<()> {
+    if sheets.is_empty() {
+        writeln!(w, "No changes")?;
+        return Ok(());
+    }
+
+    for (i, sheet) in sheets.iter().enumerate() {
+        match format {
+            Format::Text => {
+                writeln!(w, "sheet {}", sheet.path)?;
+                for c in sheet.changes.iter() {
+                    writeln!(w, "  {}", format_text(c))?;
+                }
+            }
+            Format::Markdown => {
+                if i > 0 {
+                    writeln!(w)?;
+                }
+                writeln!(w, "### Sheet `{}`", sheet.path)?;
+                writeln!(w)?;
+                writeln!(w, "| Subject | Change | Old | New |")?;
+                writeln!(w, "| --- | --- | --- | --- |")?;
+                for c in sheet.changes.iter() {
+                    writeln!(
+                        w,
+                        "| `{}` | {} | {} | {} |",
+                        c.subject,
+                        c.kind,
+                        format_markdown_cell(&c.old),
+                        format_markdown_cell(&c.new)
+                    )?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn format_text(c: &Change) -> String {
+    match (c.kind, &c.old, &c.new) {
+        (ChangeKind::Added, _, Some(new)) => format!("+ {}: {}", c.subject, new),
+        (ChangeKind::Added, _, None) => format!("+ {}", c.subject),
+        (ChangeKind::Removed, Some(old), _) => format!("- {}: {}", c.subject, old),
+        (ChangeKind::Removed, None, _) => format!("- {}", c.subject),
+        (kind, old, new) => format!(
+            "~ {} {}: {} -> {}",
+            c.subject,
+            kind,
+            format_text_value(old),
+            format_text_value(new)
+        ),
+    }
+}
+
+fn format_text_value(s: &Option<String>) -> String {
+    match s {
+        Some(s) => format!("{:?}", s),
+        None => "(none)".into(),
+    }
+}
+
+fn format_markdown_cell(s: &Option<String>) -> String {
+    match s {
+        Some(s) if !s.is_empty() => format!("`{}`", s.replace('|', "\\|")),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schematic(yaml: &str) -> Schematic {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    const OLD: &str = "
+meta: {}
+components:
+  R1:
+    labels: {reference: R1, footprintName: R_0603, footprintLibrary: Resistor_SMD, symbolName: R, symbolLibrary: Device}
+    attributes:
+      Value: {type: String, value: 2 kOhm, expression: R2*2, unit: Ohm}
+  R2:
+    labels: {reference: R2, footprintName: R_0603, footprintLibrary: Resistor_SMD, symbolName: R, symbolLibrary: Device}
+subSchematics:
+  power:
+    meta: {}
+";
+
+    const NEW: &str = "
+meta: {}
+components:
+  R1:
+    labels: {reference: R1, footprintName: R_0805, footprintLibrary: Resistor_SMD, symbolName: R, symbolLibrary: Device}
+    classes: [resistor]
+    attributes:
+      Value: {type: String, value: 3 kOhm, expression: R2*3, unit: Ohm}
+subSchematics:
+  power:
+    meta: {}
+    components:
+      C1:
+        labels: {reference: C1, footprintName: C_0603, footprintLibrary: Capacitor_SMD, symbolName: C, symbolLibrary: Device}
+";
+
+    #[test]
+    fn diff_schematics() {
+        let sheets = diff(&schematic(OLD), &schematic(NEW));
+        let summary: Vec<(&str, &str, ChangeKind)> = sheets
+            .iter()
+            .flat_map(|s| {
+                s.changes
+                    .iter()
+                    .map(move |c| (s.path.as_str(), c.subject.as_str(), c.kind))
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("/", "R1.footprintName", ChangeKind::Label),
+                ("/", "R1.Value", ChangeKind::Expression),
+                ("/", "R1.Value", ChangeKind::Value),
+                ("/", "R1", ChangeKind::Classes),
+                ("/", "R2", ChangeKind::Removed),
+                ("/power", "C1", ChangeKind::Added),
+            ]
+        );
+
+        assert!(diff(&schematic(NEW), &schematic(NEW)).is_empty());
+    }
+}
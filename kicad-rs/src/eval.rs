@@ -1,20 +1,76 @@
+mod dimension;
 mod display;
+mod engine;
 mod entry;
+mod explain;
+mod graph;
 mod index;
 mod path;
 
 use crate::error::{errorf, DynamicResult};
-use crate::eval::index::{ComponentIndex, Node, SheetIndex};
-use crate::eval::path::Path;
+pub use crate::eval::display::{parse_quantity, Format};
+pub use crate::eval::engine::Engine;
+pub use crate::eval::explain::{Explanation, FunctionCall};
+pub use crate::eval::graph::{write_graph, GraphFormat};
+use crate::eval::index::ComponentIndex;
+pub(crate) use crate::eval::index::{Node, SheetIndex};
+pub(crate) use crate::eval::path::Path;
+use crate::parser::is_check;
 use crate::types::Schematic;
+use evalexpr::{Operator, Value};
+use kicad_functions::FunctionRegistry;
+use serde::{Deserialize, Serialize};
 use std::path::Path as StdPath;
 
-pub fn index_schematic(sch: &mut Schematic) -> DynamicResult<SheetIndex> {
-    let mut index = SheetIndex::new();
+// Globals of a schematic (text note or project definitions) are
+// referenced in expressions as e.g. "Globals.Vin"
+pub const GLOBALS_KEY: &str = "Globals";
+
+// Arithmetic selects how numbers are treated during evaluation. In Float mode (the
+// default), all numeric literals and attribute values are floats, so that e.g.
+// "R7.Value/500" doesn't perform integer division. Integer mode keeps the integer
+// semantics of the expression language, where "1/2" evaluates to 0.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Arithmetic {
+    #[default]
+    Float,
+    Integer,
+}
+
+// index_schematic builds a SheetIndex for the given schematic, resolving
+// function calls in expressions through the given function registry
+pub fn index_schematic<'a>(
+    sch: &'a mut Schematic,
+    functions: &'a FunctionRegistry,
+) -> DynamicResult<SheetIndex<'a>> {
+    let mut index = SheetIndex::new(functions);
+    index.filename = sch.meta.filename.clone();
+
+    if !sch.globals.is_empty() {
+        let mut globals_idx = ComponentIndex::new();
+        for (name, attribute) in sch.globals.iter_mut() {
+            globals_idx.insert(name.into(), attribute.into());
+        }
+        index
+            .map
+            .insert(GLOBALS_KEY.into(), Node::Component(globals_idx));
+    }
 
     for component in sch.components.values_mut() {
+        if index.map.contains_key(&component.labels.reference) {
+            return Err(errorf(&format!(
+                "component and globals name collision: {}",
+                component.labels.reference
+            )));
+        }
+
         let mut component_idx = ComponentIndex::new();
-        for (name, attribute) in component.attributes.iter_mut() {
+        for (name, attribute) in component
+            .attributes
+            .iter_mut()
+            .chain(component.checks.iter_mut())
+        {
             if component_idx.contains_key(name) {
                 return Err(errorf(&format!("duplicate attribute definition: {}", name)));
             }
@@ -27,51 +83,31 @@ pub fn index_schematic(sch: &mut Schematic) -> DynamicResult<SheetIndex> {
     }
 
     for (sch_id, sub_sch) in sch.sub_schematics.iter_mut() {
-        let sch_name: &str = sub_sch
-            .meta
-            .filename
-            .as_ref()
-            .map(|s| StdPath::new(s).file_stem().map(|s| s.to_str()).flatten())
-            .flatten()
-            .unwrap_or(&sch_id);
+        let sch_name = sheet_name(sch_id, sub_sch);
         if index.map.contains_key(sch_name) {
             return Err(errorf(&format!(
                 "component and schematic name collision: {}",
                 sch_name
             )));
         }
-        index
-            .map
-            .insert(sch_name.into(), Node::Sheet(index_schematic(sub_sch)?));
+        index.map.insert(
+            sch_name.into(),
+            Node::Sheet(index_schematic(sub_sch, functions)?),
+        );
     }
 
     Ok(index)
 }
 
-pub fn evaluate_schematic(index: &mut SheetIndex) -> DynamicResult<()> {
-    // Perform resolving recursively in depth-first order
-    for node in index.map.values_mut() {
-        if let Node::Sheet(sub_index) = node {
-            evaluate_schematic(sub_index)?;
-        }
-    }
-
-    // Collect all attributes for all components
-    let mut paths = Vec::new();
-    for (node_ref, node) in index.map.iter() {
-        if let Node::Component(component_index) = node {
-            for a in component_index.keys() {
-                paths.push(vec![node_ref.into(), a.into()].into());
-            }
-        }
-    }
-
-    // Evaluate all the collected attributes
-    for path in paths.iter() {
-        evaluate(index, path)?;
-    }
-
-    Ok(())
+// sheet_name returns the name a sub-sheet with the given ID is referred to by in paths
+// (e.g. "Power.R5.Value"): the stem of its filename, or its ID if it has no filename
+pub fn sheet_name<'s>(id: &'s str, sch: &'s Schematic) -> &'s str {
+    sch.meta
+        .filename
+        .as_ref()
+        .and_then(|s| StdPath::new(s).file_stem())
+        .and_then(|s| s.to_str())
+        .unwrap_or(id)
 }
 
 // TODO: Support u, k, M, G, etc. suffixes. Now the evaluator treats them as a variable.
@@ -81,29 +117,70 @@ pub fn evaluate_schematic(index: &mut SheetIndex) -> DynamicResult<()> {
 //  "35" vs "35 F". "35 F" looks nicer in KiCad, but also might mess up the parsing unless
 //  we have a well-known "undo" method like stripping the " {}" suffix where {} is the unit
 //  before parsing the rest of the string into a float or string.
-// TODO: An expression like "R7.Value/500" will perform integer division if both expressions
-//  resolve to an integer, which is something to be aware of. Additionally, it seems like
-//  putting just "500.0" in an expression resolves to "500" in the output, something which might
-//  be desired, but just worth documenting.
-fn evaluate(idx: &mut SheetIndex, p: &Path) -> DynamicResult<()> {
-    let entry = idx
-        .resolve_entry(p.iter())
-        .ok_or(errorf(&format!("entry not found: {}", p)))?;
-
-    if entry
-        .value_defined()
-        .map_err(|e| errorf(&format!("error accessing {}: {}", p, e.to_string())))?
-    {
-        return Ok(()); // Don't update if already set
-    }
+// TODO: Putting just "500.0" in an expression resolves to "500" in the output, since the
+//  formatting drops insignificant digits. This might be desired, but is worth documenting.
+pub fn evaluate_schematic(index: &mut SheetIndex, arithmetic: Arithmetic) -> DynamicResult<()> {
+    // Compile all expressions of the hierarchy into a graph, and evaluate it in topological order
+    Engine::compile(index, arithmetic)?.evaluate()
+}
 
-    let node = evalexpr::build_operator_tree(entry.get_expression())?;
-    for dep in node.iter_variable_identifiers().map(|id| id.into()) {
-        evaluate(idx, &dep)?;
-    }
+// failed_checks returns a message naming the path and expression of each design check in
+// the evaluated index that doesn't hold. A check that isn't a boolean is an error.
+pub fn failed_checks(index: &SheetIndex) -> DynamicResult<Vec<String>> {
+    let mut failed = Vec::new();
+    collect_failed_checks(index, &mut vec![], &mut failed)?;
+    failed.sort();
+    Ok(failed)
+}
 
-    let value = node.eval_with_context(idx)?;
-    idx.update_entry(p.iter(), value)?;
+fn collect_failed_checks(
+    index: &SheetIndex,
+    prefix: &mut Vec<String>,
+    failed: &mut Vec<String>,
+) -> DynamicResult<()> {
+    for (node_ref, node) in index.map.iter() {
+        prefix.push(node_ref.into());
+        match node {
+            Node::Sheet(sub_index) => collect_failed_checks(sub_index, prefix, failed)?,
+            Node::Component(component_index) => {
+                for (name, entry) in component_index.iter().filter(|(n, _)| is_check(n)) {
+                    let p: Path = prefix
+                        .iter()
+                        .cloned()
+                        .chain(Some(name.into()))
+                        .collect::<Vec<_>>()
+                        .into();
+                    match entry.get_value() {
+                        Some(Value::Boolean(true)) => (),
+                        Some(Value::Boolean(false)) => {
+                            failed.push(format!("{}: {}", p, entry.get_expression()))
+                        }
+                        _ => {
+                            return Err(errorf(&format!(
+                                "check {} does not evaluate to a boolean: {}",
+                                p,
+                                entry.get_expression()
+                            )))
+                        }
+                    }
+                }
+            }
+        }
+        prefix.pop();
+    }
 
     Ok(())
 }
+
+// convert_integer_literals turns all integer constants in the expression tree into floats
+fn convert_integer_literals(node: &mut evalexpr::Node) {
+    if let Operator::Const { value } = node.operator_mut() {
+        if let Value::Int(i) = value {
+            *value = Value::Float(*i as f64);
+        }
+    }
+
+    node.children_mut()
+        .iter_mut()
+        .for_each(convert_integer_literals);
+}
@@ -0,0 +1,388 @@
+use evalexpr::{Node, Operator, Value};
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter, Result};
+use std::ops::{Div, Mul};
+
+// The SI base units needed for electronics, in the order of the exponents in Dimension
+const BASE_UNITS: [&str; 5] = ["kg", "m", "s", "A", "K"];
+
+// Named units recognized in "_unit" fields. Units not found here (and not composed of
+// these) are left unchecked. Earlier entries are preferred when naming derived units.
+const NAMED_UNITS: &[(&str, [i8; 5])] = &[
+    ("V", [1, 2, -3, -1, 0]),
+    ("A", [0, 0, 0, 1, 0]),
+    ("Ohm", [1, 2, -3, -2, 0]),
+    ("F", [-1, -2, 4, 2, 0]),
+    ("H", [1, 2, -2, -2, 0]),
+    ("s", [0, 0, 1, 0, 0]),
+    ("Hz", [0, 0, -1, 0, 0]),
+    ("W", [1, 2, -3, 0, 0]),
+    ("J", [1, 2, -2, 0, 0]),
+    ("C", [0, 0, 1, 1, 0]),
+    ("S", [-1, -2, 3, 2, 0]),
+    ("K", [0, 0, 0, 0, 1]),
+];
+
+// Named units that are inverses of other named units
+const INVERSE_UNITS: &[&str] = &["Hz", "S"];
+
+// Alternative spellings for the named units. "R" is deliberately not an alias of "Ohm", since it
+// reads like a reference designator. Note that "C" is the coulomb, capacitances are in "F".
+const UNIT_ALIASES: &[(&str, &str)] = &[("Ω", "Ohm"), ("ohm", "Ohm")];
+
+type BinaryOp = fn(Dimension, Dimension) -> Dimension;
+
+// Dimension describes a physical quantity by the exponents of the SI base units
+// it is composed of, e.g. volts are kg·m²·s⁻³·A⁻¹
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Dimension {
+    exponents: [i8; 5],
+}
+
+impl Dimension {
+    pub const DIMENSIONLESS: Dimension = Dimension { exponents: [0; 5] };
+
+    // Parse a unit string like "V", "Ohm", "V/s" or "A*s^2" into a Dimension.
+    // Returns None if the unit (or any part of it) is not recognized.
+    pub fn parse(unit: &str) -> Option<Self> {
+        let mut parts = unit.trim().split('/');
+        let mut dimension = Self::parse_product(parts.next()?)?;
+        for part in parts {
+            dimension = dimension / Self::parse_product(part)?;
+        }
+        Some(dimension)
+    }
+
+    fn parse_product(s: &str) -> Option<Self> {
+        let s = s.trim().trim_start_matches('(').trim_end_matches(')');
+        if s == "1" {
+            return Some(Self::DIMENSIONLESS);
+        }
+
+        s.split(&['*', '·'][..])
+            .map(|factor| Self::parse_factor(factor.trim()))
+            .try_fold(Self::DIMENSIONLESS, |acc, d| Some(acc * d?))
+    }
+
+    fn parse_factor(s: &str) -> Option<Self> {
+        let (name, exponent) = if let Some((name, exp)) = s.split_once('^') {
+            (name, exp.trim().parse::<i8>().ok()?)
+        } else if let Some(name) = s.strip_suffix('²') {
+            (name, 2)
+        } else if let Some(name) = s.strip_suffix('³') {
+            (name, 3)
+        } else {
+            (s, 1)
+        };
+
+        let name = UNIT_ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == name)
+            .map(|(_, n)| *n)
+            .unwrap_or(name);
+
+        NAMED_UNITS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, exponents)| Dimension {
+                exponents: *exponents,
+            })
+            .or_else(|| {
+                BASE_UNITS.iter().position(|b| *b == name).map(|i| {
+                    let mut d = Self::DIMENSIONLESS;
+                    d.exponents[i] = 1;
+                    d
+                })
+            })
+            .and_then(|d| d.checked_powi(exponent))
+    }
+
+    pub fn is_dimensionless(&self) -> bool {
+        *self == Self::DIMENSIONLESS
+    }
+
+    pub fn powi(self, n: i8) -> Self {
+        let mut d = self;
+        d.exponents.iter_mut().for_each(|e| *e *= n);
+        d
+    }
+
+    // Like powi, but returns None if an exponent overflows
+    fn checked_powi(self, n: i8) -> Option<Self> {
+        let mut d = self;
+        for e in d.exponents.iter_mut() {
+            *e = e.checked_mul(n)?;
+        }
+        Some(d)
+    }
+
+    fn named(&self) -> Option<&'static str> {
+        NAMED_UNITS
+            .iter()
+            .find(|(_, exponents)| *exponents == self.exponents)
+            .map(|(name, _)| *name)
+    }
+}
+
+impl Mul for Dimension {
+    type Output = Dimension;
+
+    // Multiplying quantities adds the exponents of their base units
+    #[allow(clippy::suspicious_arithmetic_impl)]
+    fn mul(self, rhs: Self) -> Self::Output {
+        let mut d = self;
+        for (e, r) in d.exponents.iter_mut().zip(rhs.exponents.iter()) {
+            *e += r;
+        }
+        d
+    }
+}
+
+impl Div for Dimension {
+    type Output = Dimension;
+
+    fn div(self, rhs: Self) -> Self::Output {
+        self * rhs.powi(-1)
+    }
+}
+
+// Formats the dimension as a unit string that can be parsed back. Named units are preferred,
+// then products or quotients of two named units (e.g. "V/s"), and finally base units.
+impl Display for Dimension {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        if self.is_dimensionless() {
+            return write!(f, "1");
+        }
+
+        if let Some(name) = self.named() {
+            return write!(f, "{}", name);
+        }
+
+        if let Some(name) = self.powi(-1).named() {
+            return write!(f, "1/{}", name);
+        }
+
+        // Try products, then quotients of two named units. Combinations not involving
+        // the inverse units (Hz, S) are preferred, as in "V/s" over "V*Hz".
+        let ops: [(&str, BinaryOp); 2] = [("*", Mul::mul), ("/", Div::div)];
+        for allow_inverse in [false, true].iter() {
+            for (op, op_fn) in ops.iter() {
+                for (a, a_exp) in NAMED_UNITS.iter() {
+                    for (b, b_exp) in NAMED_UNITS.iter() {
+                        if !allow_inverse
+                            && (INVERSE_UNITS.contains(a) || INVERSE_UNITS.contains(b))
+                        {
+                            continue;
+                        }
+                        let a_dim = Dimension { exponents: *a_exp };
+                        let b_dim = Dimension { exponents: *b_exp };
+                        if *self == op_fn(a_dim, b_dim) {
+                            return write!(f, "{}{}{}", a, op, b);
+                        }
+                    }
+                }
+            }
+        }
+
+        let factors = |positive: bool| -> Vec<String> {
+            BASE_UNITS
+                .iter()
+                .zip(self.exponents.iter())
+                .filter(|(_, e)| if positive { **e > 0 } else { **e < 0 })
+                .map(|(b, e)| match e.abs() {
+                    1 => b.to_string(),
+                    n => format!("{}^{}", b, n),
+                })
+                .collect()
+        };
+        let (num, den) = (factors(true), factors(false));
+        match (num.is_empty(), den.len()) {
+            (_, 0) => write!(f, "{}", num.join("*")),
+            (true, _) => write!(f, "1/{}", den.join("/")),
+            (false, _) => write!(f, "{}/{}", num.join("*"), den.join("/")),
+        }
+    }
+}
+
+// analyze computes the Dimension of the given expression tree, using the lookup function to
+// resolve the dimensions of referenced variables. Numeric literals are dimensionless, and a
+// dimensionless operand adopts the dimension of the other operand in additive operations.
+// Unknown dimensions (e.g. function results or unrecognized units) are not checked.
+// An error is returned if incompatible dimensions are added, subtracted or compared.
+pub fn analyze<F>(node: &Node, lookup: &F) -> std::result::Result<Option<Dimension>, String>
+where
+    F: Fn(&str) -> Option<Dimension>,
+{
+    let children = node
+        .children()
+        .iter()
+        .map(|c| analyze(c, lookup))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let child = |i: usize| children.get(i).copied().flatten();
+
+    Ok(match node.operator() {
+        Operator::RootNode | Operator::Chain => children.last().copied().flatten(),
+        Operator::Const {
+            value: Value::Int(_),
+        }
+        | Operator::Const {
+            value: Value::Float(_),
+        } => Some(Dimension::DIMENSIONLESS),
+        Operator::VariableIdentifierRead { identifier } => lookup(identifier),
+        Operator::Add | Operator::Sub | Operator::Mod => combine(node, child(0), child(1))?,
+        Operator::Eq
+        | Operator::Neq
+        | Operator::Gt
+        | Operator::Lt
+        | Operator::Geq
+        | Operator::Leq => {
+            combine(node, child(0), child(1))?;
+            None
+        }
+        Operator::Neg => child(0),
+        Operator::Mul => child(0).zip(child(1)).map(|(a, b)| a * b),
+        Operator::Div => child(0).zip(child(1)).map(|(a, b)| a / b),
+        Operator::Exp => match child(0) {
+            Some(base) if base.is_dimensionless() => Some(base),
+            Some(base) => node
+                .children()
+                .get(1)
+                .and_then(integer_constant)
+                .and_then(|n| base.checked_powi(n)),
+            None => None,
+        },
+        Operator::FunctionIdentifier { identifier } => {
+            // Arguments of multi-argument functions are given as a tuple
+            let arguments = match node.children().first().map(unwrap_root) {
+                Some(c) if matches!(c.operator(), Operator::Tuple) => c
+                    .children()
+                    .iter()
+                    .map(|c| analyze(c, lookup))
+                    .collect::<std::result::Result<Vec<_>, _>>()?,
+                _ => children.clone(),
+            };
+            match identifier.as_str() {
+                "min" | "max" => arguments
+                    .iter()
+                    .skip(1)
+                    .try_fold(arguments.first().copied().flatten(), |acc, d| {
+                        combine(node, acc, *d)
+                    })?,
+                "floor" | "round" | "ceil" => child(0),
+                _ => None,
+            }
+        }
+        _ => None,
+    })
+}
+
+// combine checks that the two dimensions are compatible in an additive operation
+fn combine(
+    node: &Node,
+    a: Option<Dimension>,
+    b: Option<Dimension>,
+) -> std::result::Result<Option<Dimension>, String> {
+    match (a, b) {
+        (Some(a), Some(b)) if a.is_dimensionless() => Ok(Some(b)),
+        (Some(a), Some(b)) if b.is_dimensionless() || a == b => Ok(Some(a)),
+        (Some(a), Some(b)) => Err(format!("incompatible units {} and {} in {}", a, b, node)),
+        _ => Ok(None),
+    }
+}
+
+// unwrap_root descends through the root nodes that parentheses create in the tree
+fn unwrap_root(node: &Node) -> &Node {
+    match (node.operator(), node.children()) {
+        (Operator::RootNode, [child]) => unwrap_root(child),
+        _ => node,
+    }
+}
+
+// integer_constant returns the value of an integer-valued constant (sub)expression, like "-2".
+// Returns None for constants out of the range of exponents, which are then left unchecked.
+fn integer_constant(node: &Node) -> Option<i8> {
+    let node = unwrap_root(node);
+    match (node.operator(), node.children()) {
+        (Operator::Neg, [child]) => integer_constant(child).and_then(i8::checked_neg),
+        (
+            Operator::Const {
+                value: Value::Int(i),
+            },
+            _,
+        ) => i8::try_from(*i).ok(),
+        (
+            Operator::Const {
+                value: Value::Float(f),
+            },
+            _,
+        ) if f.fract() == 0.0 && (f64::from(i8::MIN)..=f64::from(i8::MAX)).contains(f) => {
+            Some(*f as i8)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dim(unit: &str) -> Option<Dimension> {
+        Dimension::parse(unit)
+    }
+
+    fn analyze_str(expr: &str) -> std::result::Result<Option<String>, String> {
+        let node = evalexpr::build_operator_tree(expr).unwrap();
+        let lookup = |id: &str| match id {
+            "R1.Value" => dim("Ohm"),
+            "C1.Value" => dim("F"),
+            "L1.Value" => dim("H"),
+            "Vin" => dim("V"),
+            "I" => dim("A"),
+            _ => None,
+        };
+        analyze(&node, &lookup).map(|d| d.map(|d| d.to_string()))
+    }
+
+    #[test]
+    fn parse_units() {
+        assert_eq!(dim("Ω"), dim("Ohm"));
+        assert_eq!(dim("V/A"), dim("Ohm"));
+        assert_eq!(dim("A*s"), dim("C"));
+        assert_eq!(dim("s^-1"), dim("Hz"));
+        assert_eq!(dim("kg*m²/s^3/A"), dim("V"));
+        assert_eq!(dim("pcs."), None);
+        assert_eq!(dim("V/€"), None);
+        assert_eq!(dim("R"), None);
+        assert_eq!(dim("V^100"), None);
+    }
+
+    #[test]
+    fn format_units() {
+        assert_eq!(dim("V/s").unwrap().to_string(), "V/s");
+        assert_eq!(dim("Ohm*H").unwrap().to_string(), "Ohm*H");
+        assert_eq!(dim("m^2").unwrap().to_string(), "m^2");
+        assert_eq!(dim("1/K").unwrap().to_string(), "1/K");
+    }
+
+    #[test]
+    fn analyze_expressions() {
+        assert_eq!(analyze_str("R1.Value * C1.Value"), Ok(Some("s".into())));
+        assert_eq!(analyze_str("Vin / R1.Value"), Ok(Some("A".into())));
+        assert_eq!(analyze_str("Vin * I * 0.5"), Ok(Some("W".into())));
+        assert_eq!(analyze_str("R1.Value/500.0 + 3"), Ok(Some("Ohm".into())));
+        assert_eq!(
+            analyze_str("round(1/(2*3.14*R1.Value*C1.Value))"),
+            Ok(Some("Hz".into()))
+        );
+        assert_eq!(analyze_str("max(Vin, 3) * 2"), Ok(Some("V".into())));
+        assert_eq!(analyze_str("I^2 * R1.Value"), Ok(Some("W".into())));
+        assert_eq!(analyze_str("C1.Value^(-1)"), Ok(Some("1/F".into())));
+        assert_eq!(analyze_str("Vin^256"), Ok(None));
+        assert_eq!(analyze_str("Vin^(-(-128))"), Ok(None));
+        assert_eq!(analyze_str("Vin^100"), Ok(None));
+        assert_eq!(analyze_str("idx(Unknown, 1) + Vin"), Ok(None));
+        assert!(analyze_str("Vin + R1.Value").is_err());
+        assert!(analyze_str("Vin > R1.Value * 2").is_err());
+        assert!(analyze_str("min(Vin, I)").is_err());
+    }
+}
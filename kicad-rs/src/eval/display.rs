@@ -1,3 +1,4 @@
+use crate::error::{errorf, DynamicResult};
 use evalexpr::Value;
 use gpoint::GPoint;
 use std::fmt::{Display, Formatter, Result};
@@ -5,50 +6,256 @@ use std::fmt::{Display, Formatter, Result};
 const UNITS_LARGE: &[char] = &['k', 'M', 'G', 'T', 'P', 'E', 'Z', 'Y'];
 const UNITS_SMALL: &[char] = &['m', 'µ', 'n', 'p', 'f', 'a', 'z', 'y'];
 
+// Notation selects how a floating-point value is written
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Notation {
+    // e.g. "4.99 kOhm"
+    Si,
+    // The prefix takes the place of the decimal point, e.g. "4k7" or "0R1"
+    Rkm,
+    // e.g. "5%" for 0.05
+    Percent,
+}
+
+// Format describes how the values of an attribute are written. It's parsed from a spec like
+// "digits=4, rkm", given in the "_format" field of the attribute or per unit in the project.
+// The options, separated by commas or whitespace, are:
+// - digits=N: the number of significant digits, 3 by default
+// - rkm: RKM code notation, e.g. "4k7", with "R" in place of the decimal point for resistances
+// - percent: the value as a percentage without a unit, e.g. "5%" for 0.05
+// - prefix=P: always use the SI prefix P (e.g. "k" or "u"), or no prefix for "prefix=none"
+// - nounit: leave out the unit
+// - sci: use scientific notation for values beyond the range of the SI prefixes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Format {
+    digits: usize,
+    notation: Notation,
+    // The exponent of the forced prefix, a multiple of three
+    prefix: Option<i32>,
+    unit: bool,
+    scientific: bool,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self {
+            digits: 3,
+            notation: Notation::Si,
+            prefix: None,
+            unit: true,
+            scientific: false,
+        }
+    }
+}
+
+impl Format {
+    // Parse a format spec, see Format for the options
+    pub fn parse(spec: &str) -> DynamicResult<Self> {
+        let mut format = Self::default();
+        for option in spec.split(|c: char| c == ',' || c.is_whitespace()) {
+            match option.split_once('=') {
+                None if option.is_empty() => (),
+                None if option == "rkm" => format.notation = Notation::Rkm,
+                None if option == "percent" => format.notation = Notation::Percent,
+                None if option == "nounit" => format.unit = false,
+                None if option == "sci" => format.scientific = true,
+                Some(("digits", n)) => {
+                    format.digits = match n.parse::<usize>() {
+                        Ok(n @ 1..=15) => n,
+                        _ => return Err(errorf(&format!("invalid number of digits: {}", n))),
+                    }
+                }
+                Some(("prefix", "none")) => format.prefix = Some(0),
+                Some(("prefix", p)) => {
+                    format.prefix = Some(
+                        prefix_exponent(p)
+                            .ok_or_else(|| errorf(&format!("unknown SI prefix: {}", p)))?,
+                    )
+                }
+                _ => return Err(errorf(&format!("unknown format option: {}", option))),
+            }
+        }
+        Ok(format)
+    }
+}
+
+// The exponent of the given SI prefix, "u" is accepted for micro
+fn prefix_exponent(prefix: &str) -> Option<i32> {
+    let mut chars = prefix.chars();
+    let c = match (chars.next()?, chars.next()) {
+        ('u', None) => 'µ',
+        (c, None) => c,
+        _ => return None,
+    };
+    let large = UNITS_LARGE
+        .iter()
+        .position(|p| *p == c)
+        .map(|i| 1 + i as i32);
+    let small = UNITS_SMALL
+        .iter()
+        .position(|p| *p == c)
+        .map(|i| -1 - i as i32);
+    large.or(small).map(|i| 3 * i)
+}
+
+// The SI prefix with the given exponent, None for exponent zero
+fn prefix_char(exponent: i32) -> Option<char> {
+    match exponent / 3 {
+        0 => None,
+        i if i > 0 => UNITS_LARGE.get(i as usize - 1).copied(),
+        i => UNITS_SMALL.get((-i) as usize - 1).copied(),
+    }
+}
+
+// parse_quantity parses a formatted value like "100 nF", "4.99k", "4k7" or "4R7" into a number.
+// The number may be followed by an SI prefix and a unit, or be written in RKM code.
+pub fn parse_quantity(s: &str) -> Option<f64> {
+    let s = s.trim();
+    if !s.starts_with(|c: char| c.is_ascii_digit() || c == '.' || c == '-' || c == '+') {
+        return None;
+    }
+
+    // The longest leading part that is a number
+    let end = (1..=s.len())
+        .rev()
+        .filter(|i| s.is_char_boundary(*i))
+        .find(|i| s[..*i].parse::<f64>().is_ok())?;
+    let number = &s[..end];
+    let mut rest = s[end..].trim_start().chars();
+    let (exponent, decimals) = match rest.next() {
+        None => return number.parse().ok(),
+        Some(c) => {
+            let decimals: String = rest.clone().take_while(|c| c.is_ascii_digit()).collect();
+            match prefix_exponent(&c.to_string()) {
+                Some(exponent) => (exponent, decimals),
+                // "R" takes the place of the decimal point of resistances in RKM code
+                None if c == 'R' => (0, decimals),
+                None => (0, String::new()),
+            }
+        }
+    };
+
+    // Scale by parsing the exponent along with the number, which avoids rounding errors
+    if number.contains(['e', 'E']) {
+        return Some(number.parse::<f64>().ok()? * 10f64.powi(exponent));
+    }
+    let mantissa = if decimals.is_empty() || number.contains('.') {
+        number.to_string()
+    } else {
+        format!("{}.{}", number, decimals)
+    };
+    format!("{}e{}", mantissa, exponent).parse().ok()
+}
+
+// Whether values of the given unit are resistances, which are written with "R" in RKM code
+fn is_resistance(unit: &Option<String>) -> bool {
+    matches!(unit.as_deref(), Some("Ohm") | Some("ohm") | Some("Ω"))
+}
+
 pub struct PrettyPrintValue<'a> {
     value: &'a Value,
     unit: &'a Option<String>,
+    format: Format,
 }
 
 impl<'a> PrettyPrintValue<'a> {
     pub fn new(value: &'a Value, unit: &'a Option<String>) -> Self {
-        Self { value, unit }
+        Self {
+            value,
+            unit,
+            format: Format::default(),
+        }
     }
-}
 
-impl<'a> Display for PrettyPrintValue<'a> {
-    fn fmt(&self, f: &mut Formatter) -> Result {
-        match self.value {
-            Value::Float(mut float) => {
-                let mut prefix = None;
-                if let Some((mut iter, mul)) = match float {
-                    _ if float == 0.0 => None,
-                    _ if float.abs() > 1000.0 => Some((UNITS_LARGE.iter(), 0.001)),
-                    _ if float.abs() < 1.0 => Some((UNITS_SMALL.iter(), 1000.0)),
-                    _ => None,
-                } {
-                    while let (i @ Some(_), true) =
-                        (iter.next(), float.abs() > 1000.0 || float.abs() < 1.0)
-                    {
-                        prefix = i;
-                        float *= mul;
-                    }
-                }
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
 
-                // Use dynamic precision with at most three significant digits
-                write!(f, "{:.3}", GPoint(float))?;
-                if self.unit.is_some() {
-                    write!(f, " ")?;
-                }
+    // Scale the value to the forced SI prefix, or to the one that brings it between 1 and 1000.
+    // Returns the scaled value, the prefix, and whether the value is beyond the prefix range.
+    fn scale(&self, mut float: f64) -> (f64, Option<char>, bool) {
+        if let Some(exponent) = self.format.prefix {
+            return (float / 10f64.powi(exponent), prefix_char(exponent), false);
+        }
 
-                prefix.map(|u| write!(f, "{}", u)).transpose()?;
-                self.unit.as_ref().map(|u| write!(f, "{}", u)).transpose()?;
+        let mut prefix = None;
+        if let Some((mut iter, mul)) = match float {
+            _ if float == 0.0 => None,
+            _ if float.abs() > 1000.0 => Some((UNITS_LARGE.iter(), 0.001)),
+            _ if float.abs() < 1.0 => Some((UNITS_SMALL.iter(), 1000.0)),
+            _ => None,
+        } {
+            while let (i @ Some(_), true) = (iter.next(), float.abs() > 1000.0 || float.abs() < 1.0)
+            {
+                prefix = i.copied();
+                float *= mul;
+            }
+        }
 
-                Ok(())
+        let out_of_range = float != 0.0 && (float.abs() > 1000.0 || float.abs() < 1.0);
+        (float, prefix, out_of_range)
+    }
+
+    fn fmt_float(&self, f: &mut Formatter, float: f64) -> Result {
+        let digits = self.format.digits;
+        let unit = self.unit.as_ref().filter(|_| self.format.unit);
+        let (scaled, prefix, out_of_range) = self.scale(float);
+
+        match self.format.notation {
+            Notation::Percent => return write!(f, "{:.*}%", digits, GPoint(float * 100.0)),
+            Notation::Rkm => {
+                // Resistances below one ohm are written like "0R1" rather than "100m"
+                let (scaled, prefix) = match self.format.prefix {
+                    None if is_resistance(self.unit) && float.abs() < 1.0 => (float, None),
+                    _ => (scaled, prefix),
+                };
+                let number = format!("{:.*}", digits, GPoint(scaled));
+                let letter = match prefix {
+                    Some('µ') => Some('u'),
+                    Some(p) => Some(p),
+                    None if is_resistance(self.unit) => Some('R'),
+                    None => None,
+                };
+                return match letter {
+                    Some(l) if number.contains('.') => {
+                        write!(f, "{}", number.replacen('.', &l.to_string(), 1))
+                    }
+                    Some(l) => write!(f, "{}{}", number, l),
+                    None => write!(f, "{}", number),
+                };
+            }
+            Notation::Si => (),
+        }
+
+        if out_of_range && self.format.scientific {
+            write!(f, "{:.*}", digits, GPoint(float))?;
+            if let Some(u) = unit {
+                write!(f, " {}", u)?;
             }
+            return Ok(());
+        }
+
+        // Use dynamic precision with at most the given number of significant digits
+        write!(f, "{:.*}", digits, GPoint(scaled))?;
+        if unit.is_some() {
+            write!(f, " ")?;
+        }
+
+        prefix.map(|u| write!(f, "{}", u)).transpose()?;
+        unit.map(|u| write!(f, "{}", u)).transpose()?;
+
+        Ok(())
+    }
+}
+
+impl<'a> Display for PrettyPrintValue<'a> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self.value {
+            Value::Float(float) => self.fmt_float(f, *float),
             other => {
                 other.fmt(f)?;
-                if let Some(unit) = self.unit {
+                if let Some(unit) = self.unit.as_ref().filter(|_| self.format.unit) {
                     write!(f, " {}", unit)?;
                 }
                 Ok(())
@@ -106,4 +313,40 @@ mod tests {
         (0.00123456, "1.23m"),
         (3e-27, "0.003y")
     );
+
+    fn format(value: f64, unit: &str, spec: &str) -> String {
+        let unit = Some(unit.to_string()).filter(|u| !u.is_empty());
+        PrettyPrintValue::new(&value.into(), &unit)
+            .with_format(Format::parse(spec).unwrap())
+            .to_string()
+    }
+
+    #[test]
+    fn quantities() {
+        assert_eq!(parse_quantity("100 nF"), Some(100e-9));
+        assert_eq!(parse_quantity("4.99k"), Some(4990.0));
+        assert_eq!(parse_quantity("4k7"), Some(4700.0));
+        assert_eq!(parse_quantity("4R7"), Some(4.7));
+        assert_eq!(parse_quantity("100R"), Some(100.0));
+        assert_eq!(parse_quantity("10 Ohm"), Some(10.0));
+        assert_eq!(parse_quantity("1.5e3"), Some(1500.0));
+        assert_eq!(parse_quantity("C_Small"), None);
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(format(4990.0, "Ohm", "digits=4"), "4.99 kOhm");
+        assert_eq!(format(4700.0, "Ohm", "rkm"), "4k7");
+        assert_eq!(format(0.1, "Ohm", "rkm"), "0R1");
+        assert_eq!(format(4.7, "Ohm", "rkm"), "4R7");
+        assert_eq!(format(100.0, "Ohm", "rkm"), "100R");
+        assert_eq!(format(4.7e-6, "F", "rkm"), "4u7");
+        assert_eq!(format(0.1e-6, "F", "prefix=n, nounit"), "100n");
+        assert_eq!(format(2200.0, "V", "prefix=none digits=4"), "2200 V");
+        assert_eq!(format(0.05, "", "percent"), "5%");
+        assert_eq!(format(3e27, "C", "sci"), "3e+27 C");
+        assert!(Format::parse("digits=0").is_err());
+        assert!(Format::parse("prefix=q").is_err());
+        assert!(Format::parse("bold").is_err());
+    }
 }
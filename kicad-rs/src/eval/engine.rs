@@ -0,0 +1,522 @@
+use crate::error::{errorf, DynamicResult};
+use crate::eval::dimension;
+use crate::eval::entry::Entry;
+use crate::eval::explain::{Explanation, FunctionCall};
+use crate::eval::index::{Node, SheetIndex};
+use crate::eval::{convert_integer_literals, Arithmetic};
+use crate::parser::VALUE_FIELD_KEY;
+use evalexpr::{Context, EvalexprResult, Value};
+use kicad_functions::FunctionRegistry;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+// Engine evaluates the attributes of a SheetIndex using a pre-compiled expression graph.
+// Every attribute is identified by the index of its interned full path (e.g.
+// "Properties.Globals.TargetVoltage"), its expression is parsed once, and the variable
+// identifiers in it are bound to the attributes they refer to. Evaluation follows a
+// topological order of the graph, so values are never looked up by path while evaluating.
+pub struct Engine<'i, 'a> {
+    arithmetic: Arithmetic,
+    functions: &'a FunctionRegistry,
+    ids: HashMap<String, usize>,
+    paths: Vec<String>,
+    sheets: Vec<String>,
+    entries: Vec<&'i mut Entry<'a>>,
+    compiled: Vec<Compiled>,
+    // The attributes referring to each attribute, kept in sync with the compiled bindings
+    dependents: Vec<BTreeSet<usize>>,
+    order: Vec<usize>,
+    values: Vec<Option<Value>>,
+    // The function calls made by each expression, only recorded if requested (for explain)
+    record_calls: bool,
+    calls: Vec<Vec<FunctionCall>>,
+}
+
+// Compiled is a parsed expression with its identifiers bound to attribute IDs
+struct Compiled {
+    tree: evalexpr::Node,
+    bindings: HashMap<String, usize>,
+}
+
+// BoundContext resolves the identifiers of a single compiled expression, and optionally records
+// the function calls made by it. As arguments are evaluated first, inner calls are recorded first.
+struct BoundContext<'e> {
+    bindings: &'e HashMap<String, usize>,
+    values: &'e [Option<Value>],
+    functions: &'e FunctionRegistry,
+    calls: Option<&'e RefCell<Vec<FunctionCall>>>,
+}
+
+impl<'e> Context for BoundContext<'e> {
+    fn get_value(&self, identifier: &str) -> Option<&Value> {
+        self.values[*self.bindings.get(identifier)?].as_ref()
+    }
+
+    fn call_function(&self, identifier: &str, argument: &Value) -> EvalexprResult<Value> {
+        let result = self.functions.call(identifier, argument)?;
+        if let Some(calls) = self.calls {
+            calls.borrow_mut().push(FunctionCall {
+                function: identifier.into(),
+                argument: argument.to_string(),
+                result: result.to_string(),
+            });
+        }
+        Ok(result)
+    }
+}
+
+impl<'i, 'a> Engine<'i, 'a> {
+    // Compile all the expressions in the index into an expression graph
+    pub fn compile(index: &'i mut SheetIndex<'a>, arithmetic: Arithmetic) -> DynamicResult<Self> {
+        let functions = index.functions();
+        let mut collected = Vec::new();
+        collect_entries(index, "", &mut collected);
+        collected.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut engine = Self {
+            arithmetic,
+            functions,
+            ids: HashMap::new(),
+            paths: Vec::new(),
+            sheets: Vec::new(),
+            entries: Vec::new(),
+            compiled: Vec::new(),
+            dependents: Vec::new(),
+            order: Vec::new(),
+            values: Vec::new(),
+            record_calls: false,
+            calls: Vec::new(),
+        };
+        for (id, (path, sheet, entry)) in collected.into_iter().enumerate() {
+            engine.ids.insert(path.clone(), id);
+            engine.paths.push(path);
+            engine.sheets.push(sheet);
+            engine.entries.push(entry);
+            engine.values.push(None);
+            engine.calls.push(Vec::new());
+            engine.dependents.push(BTreeSet::new());
+        }
+
+        for id in 0..engine.entries.len() {
+            let compiled = engine.compile_expression(id, engine.entries[id].get_expression())?;
+            engine.compiled.push(compiled);
+            engine.add_dependents(id);
+        }
+        engine.order = engine.topological_order()?;
+
+        Ok(engine)
+    }
+
+    // Record the function calls made while evaluating, so that they can be explained
+    pub fn record_calls(&mut self) {
+        self.record_calls = true;
+    }
+
+    // Evaluate all attributes in topological order
+    pub fn evaluate(&mut self) -> DynamicResult<()> {
+        for i in 0..self.order.len() {
+            self.evaluate_entry(self.order[i])?;
+        }
+        Ok(())
+    }
+
+    // Change the expression of the attribute at the given full path, and re-evaluate only
+    // it and the attributes downstream of it. Returns the paths of the re-evaluated attributes.
+    // If the expression doesn't compile, introduces a dependency loop or fails to evaluate,
+    // the previous expression is restored (and re-evaluated along with everything downstream
+    // of it), leaving the engine unchanged, and an error is returned.
+    pub fn set_expression(&mut self, path: &str, expression: &str) -> DynamicResult<Vec<String>> {
+        let id = *self
+            .ids
+            .get(path)
+            .ok_or_else(|| errorf(&format!("entry not found: {}", path)))?;
+        let compiled = self.compile_expression(id, expression)?;
+
+        // The order only needs to change if the dependencies of the attribute changed
+        let mut previous_order = None;
+        let previous = if compiled.bindings != self.compiled[id].bindings {
+            let previous = self.replace_compiled(id, compiled);
+            match self.topological_order() {
+                Ok(order) => previous_order = Some(std::mem::replace(&mut self.order, order)),
+                Err(e) => {
+                    self.replace_compiled(id, previous);
+                    return Err(e);
+                }
+            }
+            previous
+        } else {
+            std::mem::replace(&mut self.compiled[id], compiled)
+        };
+        let previous_expression = self.entries[id].get_expression().to_string();
+        self.entries[id].set_expression(expression);
+
+        // Find everything downstream of the changed attribute. The dependents of an attribute
+        // don't depend on its own expression, so this is the same for the previous expression.
+        let mut dirty = BTreeSet::new();
+        let mut queue = VecDeque::from(vec![id]);
+        while let Some(next) = queue.pop_front() {
+            if dirty.insert(next) {
+                queue.extend(self.dependents[next].iter());
+            }
+        }
+
+        match self.evaluate_dirty(&dirty) {
+            Ok(order) => Ok(order.into_iter().map(|i| self.paths[i].clone()).collect()),
+            Err(e) => {
+                self.replace_compiled(id, previous);
+                if let Some(order) = previous_order {
+                    self.order = order;
+                }
+                self.entries[id].set_expression(&previous_expression);
+                // The previous values were evaluated the same way, so if this fails, they
+                // already failed to evaluate before the change
+                let _ = self.evaluate_dirty(&dirty);
+                Err(e)
+            }
+        }
+    }
+
+    // Reset the given attributes and re-evaluate them in topological order, which is returned
+    fn evaluate_dirty(&mut self, dirty: &BTreeSet<usize>) -> DynamicResult<Vec<usize>> {
+        let order: Vec<usize> = self
+            .order
+            .iter()
+            .copied()
+            .filter(|i| dirty.contains(i))
+            .collect();
+        for i in order.iter() {
+            self.entries[*i].reset();
+            self.values[*i] = None;
+        }
+        for i in order.iter() {
+            self.evaluate_entry(*i)?;
+        }
+        Ok(order)
+    }
+
+    // Parse the given expression of the given attribute, and bind its identifiers
+    fn compile_expression(&self, id: usize, expression: &str) -> DynamicResult<Compiled> {
+        let path = &self.paths[id];
+        let mut tree = evalexpr::build_operator_tree(expression)
+            .map_err(|e| errorf(&format!("{}: {}", path, e)))?;
+        if self.arithmetic == Arithmetic::Float {
+            convert_integer_literals(&mut tree);
+        }
+
+        let mut bindings = HashMap::new();
+        for identifier in tree.iter_variable_identifiers() {
+            let dependency = self
+                .resolve(&self.sheets[id], identifier)
+                .ok_or_else(|| errorf(&format!("entry not found: {}", identifier)))?;
+            bindings.insert(identifier.to_string(), dependency);
+        }
+
+        Ok(Compiled { tree, bindings })
+    }
+
+    // Replace the compiled expression of the given attribute, updating the dependents of the
+    // attributes it referred to and refers to now. Returns the previous compiled expression.
+    fn replace_compiled(&mut self, id: usize, compiled: Compiled) -> Compiled {
+        for dependency in self.compiled[id].bindings.values() {
+            self.dependents[*dependency].remove(&id);
+        }
+        let previous = std::mem::replace(&mut self.compiled[id], compiled);
+        self.add_dependents(id);
+        previous
+    }
+
+    // Register the given attribute as a dependent of the attributes its expression refers to
+    fn add_dependents(&mut self, id: usize) {
+        for dependency in self.compiled[id].bindings.values() {
+            self.dependents[*dependency].insert(id);
+        }
+    }
+
+    // Resolve an identifier relative to the given sheet. Referencing a
+    // component without an attribute refers to the value of the component.
+    fn resolve(&self, sheet: &str, identifier: &str) -> Option<usize> {
+        let path = if sheet.is_empty() {
+            identifier.to_string()
+        } else {
+            format!("{}.{}", sheet, identifier)
+        };
+        self.ids
+            .get(&path)
+            .or_else(|| self.ids.get(&format!("{}.{}", path, VALUE_FIELD_KEY)))
+            .copied()
+    }
+
+    // Order the attributes topologically, such that every attribute comes after its dependencies
+    fn topological_order(&self) -> DynamicResult<Vec<usize>> {
+        let mut remaining = vec![0; self.compiled.len()];
+        for dependents in self.dependents.iter() {
+            for dependent in dependents.iter() {
+                remaining[*dependent] += 1;
+            }
+        }
+
+        let mut queue: VecDeque<usize> = (0..remaining.len())
+            .filter(|i| remaining[*i] == 0)
+            .collect();
+        let mut order = Vec::with_capacity(remaining.len());
+        while let Some(id) = queue.pop_front() {
+            order.push(id);
+            for dependent in self.dependents[id].iter() {
+                remaining[*dependent] -= 1;
+                if remaining[*dependent] == 0 {
+                    queue.push_back(*dependent);
+                }
+            }
+        }
+
+        if let Some(id) = (0..remaining.len()).find(|i| remaining[*i] > 0) {
+            return Err(errorf(&format!(
+                "error accessing {}: dependency loop detected",
+                self.paths[id]
+            )));
+        }
+        Ok(order)
+    }
+
+    // Evaluate a single attribute whose dependencies have been evaluated
+    fn evaluate_entry(&mut self, id: usize) -> DynamicResult<()> {
+        let compiled = &self.compiled[id];
+        let path = &self.paths[id];
+
+        // Derive the unit of the value from the units of the dependencies
+        let entries = &self.entries;
+        let lookup = |identifier: &str| {
+            compiled
+                .bindings
+                .get(identifier)
+                .and_then(|d| entries[*d].get_dimension())
+        };
+        let dimension = dimension::analyze(&compiled.tree, &lookup)
+            .map_err(|e| errorf(&format!("{}: {}", path, e)))?;
+        self.entries[id]
+            .update_dimension(dimension)
+            .map_err(|e| errorf(&format!("{}: {}", path, e)))?;
+
+        let calls = RefCell::new(Vec::new());
+        let context = BoundContext {
+            bindings: &compiled.bindings,
+            values: &self.values,
+            functions: self.functions,
+            calls: Some(&calls).filter(|_| self.record_calls),
+        };
+        let value = match (self.arithmetic, compiled.tree.eval_with_context(&context)?) {
+            (Arithmetic::Float, Value::Int(i)) => Value::Float(i as f64),
+            (_, value) => value,
+        };
+
+        self.entries[id].update(value.clone())?;
+        self.values[id] = Some(value);
+        self.calls[id] = calls.into_inner();
+        Ok(())
+    }
+
+    // Explain how the value of the attribute at the given full path (e.g. "R5.Value" or "R5")
+    // was computed. The engine needs to have recorded the calls while evaluating.
+    pub fn explain(&self, path: &str) -> DynamicResult<Explanation> {
+        let id = self
+            .resolve("", path)
+            .ok_or_else(|| errorf(&format!("entry not found: {}", path)))?;
+        self.explain_entry(id, &mut HashSet::new())
+    }
+
+    // Every attribute is explained in full only the first time it's encountered, later
+    // occurrences refer back to it. This keeps the output linear in the size of the graph.
+    fn explain_entry(
+        &self,
+        id: usize,
+        explained: &mut HashSet<usize>,
+    ) -> DynamicResult<Explanation> {
+        let entry = &self.entries[id];
+        let value = self.values[id]
+            .as_ref()
+            .ok_or_else(|| errorf(&format!("not evaluated: {}", self.paths[id])))?;
+        let mut explanation = Explanation {
+            path: self.paths[id].clone(),
+            expression: entry.get_expression().into(),
+            value: entry.format_value(value)?,
+            calls: vec![],
+            dependencies: vec![],
+            explained_above: !explained.insert(id),
+        };
+        if explanation.explained_above {
+            return Ok(explanation);
+        }
+
+        explanation.calls = self.calls[id].clone();
+        // Identifiers can occur several times in the expression, explain each dependency once
+        let compiled = &self.compiled[id];
+        let mut dependencies = vec![];
+        for identifier in compiled.tree.iter_variable_identifiers() {
+            let dependency = compiled.bindings[identifier];
+            if !dependencies.contains(&dependency) {
+                dependencies.push(dependency);
+            }
+        }
+        for dependency in dependencies {
+            explanation
+                .dependencies
+                .push(self.explain_entry(dependency, explained)?);
+        }
+        Ok(explanation)
+    }
+}
+
+// collect_entries lists all attributes of the index with their full path and the path of their sheet
+fn collect_entries<'i, 'a>(
+    index: &'i mut SheetIndex<'a>,
+    sheet: &str,
+    collected: &mut Vec<(String, String, &'i mut Entry<'a>)>,
+) {
+    let join = |prefix: &str, name: &str| {
+        if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", prefix, name)
+        }
+    };
+
+    for (node_ref, node) in index.map.iter_mut() {
+        match node {
+            Node::Sheet(sub_index) => collect_entries(sub_index, &join(sheet, node_ref), collected),
+            Node::Component(component_index) => {
+                let component_path = join(sheet, node_ref);
+                for (name, entry) in component_index.iter_mut() {
+                    collected.push((join(&component_path, name), sheet.to_string(), entry));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::index_schematic;
+    use crate::eval::path::Path;
+    use crate::testutil::{attribute, component};
+    use crate::types::Schematic;
+
+    fn schematic(r2_expression: &str) -> Schematic {
+        let ohm = Some("Ohm");
+        crate::testutil::schematic(vec![
+            component("R1", vec![("Value", attribute("R2 * 2", ohm))]),
+            component("R2", vec![("Value", attribute(r2_expression, ohm))]),
+            component("R3", vec![("Value", attribute("10", ohm))]),
+        ])
+    }
+
+    #[test]
+    fn incremental_evaluation() {
+        let mut sch = schematic("15");
+        let functions = FunctionRegistry::with_builtins();
+        let mut index = index_schematic(&mut sch, &functions).unwrap();
+        let mut engine = Engine::compile(&mut index, Arithmetic::Float).unwrap();
+        engine.evaluate().unwrap();
+
+        let updated = engine.set_expression("R2.Value", "R3 + 1").unwrap();
+        assert_eq!(updated, vec!["R2.Value", "R1.Value"]);
+        drop(engine);
+        assert_eq!(
+            index
+                .resolve_entry(Path::from("R1.Value").iter())
+                .unwrap()
+                .get_value(),
+            Some(&Value::Float(22.0))
+        );
+    }
+
+    #[test]
+    fn failed_set_expression() {
+        let mut sch = schematic("15");
+        let functions = FunctionRegistry::with_builtins();
+        let mut index = index_schematic(&mut sch, &functions).unwrap();
+        let mut engine = Engine::compile(&mut index, Arithmetic::Float).unwrap();
+        engine.evaluate().unwrap();
+
+        // Neither a dependency loop nor an invalid expression may change the engine
+        assert!(engine.set_expression("R2.Value", "R1").is_err());
+        assert!(engine.set_expression("R2.Value", "(1 +").is_err());
+        assert!(engine.set_expression("R2.Value", "R4").is_err());
+        assert_eq!(
+            engine.set_expression("R3.Value", "20").unwrap(),
+            vec!["R3.Value"]
+        );
+        engine.evaluate().unwrap();
+        drop(engine);
+
+        let r2 = index.resolve_entry(Path::from("R2.Value").iter()).unwrap();
+        assert_eq!(r2.get_expression(), "15");
+        assert_eq!(
+            index
+                .resolve_entry(Path::from("R1.Value").iter())
+                .unwrap()
+                .get_value(),
+            Some(&Value::Float(30.0))
+        );
+    }
+
+    #[test]
+    fn failed_evaluation() {
+        let mut sch = schematic("15");
+        let functions = FunctionRegistry::with_builtins();
+        let mut index = index_schematic(&mut sch, &functions).unwrap();
+        let mut engine = Engine::compile(&mut index, Arithmetic::Float).unwrap();
+        engine.evaluate().unwrap();
+
+        // The expression compiles, but fails to evaluate. The previous expression and the
+        // values downstream of it are restored without evaluating the whole engine again.
+        assert!(engine
+            .set_expression("R2.Value", "R3 + missing(1)")
+            .is_err());
+        drop(engine);
+
+        let r2 = index.resolve_entry(Path::from("R2.Value").iter()).unwrap();
+        assert_eq!(r2.get_expression(), "15");
+        assert_eq!(r2.get_value(), Some(&Value::Float(15.0)));
+        assert_eq!(
+            index
+                .resolve_entry(Path::from("R1.Value").iter())
+                .unwrap()
+                .get_value(),
+            Some(&Value::Float(30.0))
+        );
+    }
+
+    #[test]
+    fn float_function_arguments() {
+        // With float arithmetic, the integer literals passed to functions are floats too
+        let expression = r#"idx(idx(vdiv_top(3, 0.5, "R2/(R1+R2)", "E3", (10, 100)), 2), 1)"#;
+        let mut sch = crate::testutil::schematic(vec![component(
+            "R1",
+            vec![("Value", attribute(expression, None))],
+        )]);
+        let functions = FunctionRegistry::with_builtins();
+        let mut index = index_schematic(&mut sch, &functions).unwrap();
+        let mut engine = Engine::compile(&mut index, Arithmetic::Float).unwrap();
+        engine.evaluate().unwrap();
+        drop(engine);
+        assert_eq!(
+            index
+                .resolve_entry(Path::from("R1.Value").iter())
+                .unwrap()
+                .get_value(),
+            Some(&Value::Float(47.0))
+        );
+    }
+
+    #[test]
+    fn dependency_loop() {
+        let mut sch = schematic("R1");
+        let functions = FunctionRegistry::with_builtins();
+        let mut index = index_schematic(&mut sch, &functions).unwrap();
+        let err = Engine::compile(&mut index, Arithmetic::Float)
+            .err()
+            .unwrap();
+        assert!(err.to_string().ends_with("dependency loop detected"));
+    }
+}
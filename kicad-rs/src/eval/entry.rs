@@ -1,15 +1,18 @@
 use crate::error::{errorf, DynamicResult};
-use crate::eval::display::PrettyPrintValue;
+use crate::eval::dimension::Dimension;
+use crate::eval::display::{Format, PrettyPrintValue};
 use crate::types;
 use crate::types::Attribute;
 use evalexpr::{EvalexprError, EvalexprResult, Value, ValueType};
-use std::cell::RefCell;
+use std::collections::HashMap;
 
 #[derive(Debug)]
 pub struct Entry<'a> {
-    set_in_progress: RefCell<bool>,
     attribute: &'a mut Attribute,
     value: Option<Value>,
+    dimension: Option<Dimension>,
+    // The default formats by unit, for when the attribute doesn't specify its own
+    default_formats: Option<&'a HashMap<String, Format>>,
 }
 
 // This (slightly modified) function's origin is for some reason marked as private for
@@ -35,8 +38,9 @@ impl<'a> Entry<'a> {
     }
 
     pub fn update(&mut self, value: Value) -> EvalexprResult<Option<Value>> {
-        *self.set_in_progress.borrow_mut() = false;
-        let str = PrettyPrintValue::new(&value, &self.attribute.unit).to_string();
+        let str = self
+            .format_value(&value)
+            .map_err(|e| EvalexprError::CustomMessage(e.to_string()))?;
         self.attribute.value = types::Value::parse(str);
         if let Some(t) = self.value.as_ref().map(|v| ValueType::from(v)) {
             if t != ValueType::from(&value) {
@@ -47,22 +51,92 @@ impl<'a> Entry<'a> {
         Ok(self.value.replace(value))
     }
 
-    pub fn value_defined(&self) -> DynamicResult<bool> {
-        if self.value.is_none() && *self.set_in_progress.borrow() {
-            return Err(errorf("dependency loop detected"));
+    // Format the value with the unit and format of the attribute
+    pub fn format_value(&self, value: &Value) -> DynamicResult<String> {
+        let unit = self.get_unit();
+        let format = match self.attribute.format.as_ref() {
+            Some(spec) => Format::parse(spec)?,
+            None => unit
+                .as_ref()
+                .and_then(|u| self.default_formats?.get(u))
+                .copied()
+                .unwrap_or_default(),
+        };
+        Ok(PrettyPrintValue::new(value, &unit)
+            .with_format(format)
+            .to_string())
+    }
+
+    // Use the given formats by unit for the values of the attribute, unless it specifies a format
+    pub fn set_default_formats(&mut self, formats: &'a HashMap<String, Format>) {
+        self.default_formats = Some(formats);
+    }
+
+    pub fn get_dimension(&self) -> Option<Dimension> {
+        self.dimension
+    }
+
+    // Returns the declared unit of the attribute, or the one derived from its expression.
+    // The derived unit is only used for formatting the value, it isn't stored as a field.
+    pub fn get_unit(&self) -> Option<String> {
+        self.attribute.unit.clone().or_else(|| {
+            self.dimension
+                .filter(|d| !d.is_dimensionless())
+                .map(|d| d.to_string())
+        })
+    }
+
+    // Check the dimension derived from the expression against the declared unit. If the
+    // attribute doesn't declare a unit, the derived one is used. Dimensionless values
+    // (e.g. plain numeric literals) are compatible with any declared unit.
+    pub fn update_dimension(&mut self, derived: Option<Dimension>) -> DynamicResult<()> {
+        let derived = match derived {
+            Some(d) => d,
+            None => return Ok(()), // Nothing to check against
+        };
+
+        match (self.dimension, &self.attribute.unit) {
+            (Some(declared), _) => {
+                if !derived.is_dimensionless() && declared != derived {
+                    return Err(errorf(&format!(
+                        "unit mismatch: declared {}, but the expression yields {}",
+                        declared, derived
+                    )));
+                }
+            }
+            (None, None) => self.dimension = Some(derived),
+            (None, Some(_)) => (), // Unrecognized unit, don't check
         }
 
-        *self.set_in_progress.borrow_mut() = true;
-        Ok(self.value.is_some())
+        Ok(())
+    }
+
+    // Replace the expression of the attribute, resetting the evaluation state
+    pub fn set_expression(&mut self, expression: &str) {
+        self.attribute.expression = expression.into();
+        self.reset();
     }
+
+    // Forget the evaluated value and the derived unit, e.g. for re-evaluation
+    pub fn reset(&mut self) {
+        self.value = None;
+        self.dimension = declared_dimension(self.attribute);
+    }
+}
+
+// The dimension of the unit declared for the attribute, if the unit is recognized
+fn declared_dimension(attribute: &Attribute) -> Option<Dimension> {
+    attribute.unit.as_ref().and_then(|u| Dimension::parse(u))
 }
 
 impl<'a> From<&'a mut Attribute> for Entry<'a> {
     fn from(attribute: &'a mut Attribute) -> Self {
+        let dimension = declared_dimension(attribute);
         Self {
-            set_in_progress: RefCell::new(false),
             attribute,
             value: None,
+            dimension,
+            default_formats: None,
         }
     }
 }
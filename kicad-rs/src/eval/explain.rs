@@ -0,0 +1,101 @@
+use std::fmt;
+
+// Explanation is the derivation tree of an evaluated attribute: its expression and
+// formatted value, the function calls made by the expression, and the explanations
+// of all the attributes the expression references (see Engine::explain). Attributes
+// referenced several times are explained once, and only refer back to that afterwards.
+#[derive(Debug)]
+pub struct Explanation {
+    pub path: String,
+    pub expression: String,
+    pub value: String,
+    pub calls: Vec<FunctionCall>,
+    pub dependencies: Vec<Explanation>,
+    // Set if the attribute is explained earlier in the tree, leaving calls and dependencies empty
+    pub explained_above: bool,
+}
+
+// FunctionCall records the argument and result of a function call in an expression
+#[derive(Debug, Clone)]
+pub struct FunctionCall {
+    pub function: String,
+    pub argument: String,
+    pub result: String,
+}
+
+impl Explanation {
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        let pad = "  ".repeat(indent);
+        if self.explained_above {
+            return writeln!(f, "{}{} = {} (see above)", pad, self.path, self.value);
+        }
+
+        writeln!(f, "{}{} = {}", pad, self.path, self.value)?;
+        writeln!(f, "{}  expression: {}", pad, self.expression)?;
+        for call in self.calls.iter() {
+            // Multiple arguments are evaluated as a tuple, which already has parentheses
+            if call.argument.starts_with('(') {
+                writeln!(
+                    f,
+                    "{}  call: {}{} = {}",
+                    pad, call.function, call.argument, call.result
+                )?;
+            } else {
+                writeln!(
+                    f,
+                    "{}  call: {}({}) = {}",
+                    pad, call.function, call.argument, call.result
+                )?;
+            }
+        }
+        for dependency in self.dependencies.iter() {
+            dependency.fmt_indented(f, indent + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::eval::{index_schematic, Arithmetic, Engine};
+    use crate::testutil::{attribute, component, schematic};
+    use kicad_functions::FunctionRegistry;
+
+    #[test]
+    fn explain_value() {
+        let ohm = Some("Ohm");
+        let mut sch = schematic(vec![
+            component(
+                "R1",
+                vec![("Value", attribute("min(R2, 2000) * 2 + R3 - R3", ohm))],
+            ),
+            component("R2", vec![("Value", attribute("R3 * 3", ohm))]),
+            component("R3", vec![("Value", attribute("500", ohm))]),
+        ]);
+        let functions = FunctionRegistry::with_builtins();
+        let mut index = index_schematic(&mut sch, &functions).unwrap();
+        let mut engine = Engine::compile(&mut index, Arithmetic::Float).unwrap();
+        engine.record_calls();
+        engine.evaluate().unwrap();
+
+        let explanation = engine.explain("R1").unwrap();
+        assert_eq!(
+            explanation.to_string(),
+            "R1.Value = 3 kOhm
+  expression: min(R2, 2000) * 2 + R3 - R3
+  call: min(1500, 2000) = 1500
+  R2.Value = 1.5 kOhm
+    expression: R3 * 3
+    R3.Value = 500 Ohm
+      expression: 500
+  R3.Value = 500 Ohm (see above)
+"
+        );
+    }
+}
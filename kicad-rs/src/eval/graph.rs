@@ -0,0 +1,199 @@
+use crate::error::DynamicResult;
+use crate::eval::index::{Node, SheetIndex};
+use crate::eval::path::Path;
+use crate::parser::VALUE_FIELD_KEY;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+// GraphFormat selects the language the dependency graph is written in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+// GraphNode is an attribute in the dependency graph, labeled with the path of its sheet,
+// the component reference, the attribute name and the computed value (if any)
+#[derive(Debug)]
+struct GraphNode {
+    sheet: String,
+    reference: String,
+    attribute: String,
+    value: Option<String>,
+}
+
+// Graph contains the attributes of the sheet hierarchy keyed by their full path (e.g.
+// "Properties.Globals.TargetVoltage"), and edges from each dependency to its dependent
+#[derive(Debug, Default)]
+struct Graph {
+    nodes: BTreeMap<String, GraphNode>,
+    edges: BTreeSet<(String, String)>,
+}
+
+// write_graph writes the attribute dependency graph of the index in the given format
+pub fn write_graph<W: Write>(
+    index: &SheetIndex,
+    format: GraphFormat,
+    w: &mut W,
+) -> DynamicResult<()> {
+    let mut graph = Graph::default();
+    collect(index, &mut vec![], &mut graph);
+
+    // Mermaid node IDs can't contain dots, so number the nodes instead
+    let ids: BTreeMap<&String, String> = graph
+        .nodes
+        .keys()
+        .enumerate()
+        .map(|(i, k)| (k, format!("n{}", i)))
+        .collect();
+
+    match format {
+        GraphFormat::Dot => {
+            writeln!(w, "digraph dependencies {{")?;
+            writeln!(w, "    rankdir=LR;")?;
+            writeln!(w, "    node [shape=box];")?;
+            for (path, node) in graph.nodes.iter() {
+                writeln!(
+                    w,
+                    "    \"{}\" [label=\"{}\"];",
+                    path,
+                    label(node).replace('"', "\\\"").replace('\n', "\\n")
+                )?;
+            }
+            for (from, to) in graph.edges.iter() {
+                writeln!(w, "    \"{}\" -> \"{}\";", from, to)?;
+            }
+            writeln!(w, "}}")?;
+        }
+        GraphFormat::Mermaid => {
+            writeln!(w, "graph LR")?;
+            for (path, node) in graph.nodes.iter() {
+                writeln!(
+                    w,
+                    "    {}[\"{}\"]",
+                    ids[path],
+                    label(node).replace('"', "#quot;").replace('\n', "<br/>")
+                )?;
+            }
+            for (from, to) in graph.edges.iter() {
+                writeln!(w, "    {} --> {}", ids[from], ids[to])?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn label(node: &GraphNode) -> String {
+    let mut s = format!("{}\n{}.{}", node.sheet, node.reference, node.attribute);
+    if let Some(value) = node.value.as_ref() {
+        s += &format!("\n= {}", value);
+    }
+    s
+}
+
+fn collect(index: &SheetIndex, prefix: &mut Vec<String>, graph: &mut Graph) {
+    for (node_ref, node) in index.map.iter() {
+        match node {
+            Node::Sheet(sub_index) => {
+                prefix.push(node_ref.into());
+                collect(sub_index, prefix, graph);
+                prefix.pop();
+            }
+            Node::Component(component_index) => {
+                for (name, entry) in component_index.iter() {
+                    let path = full_path(prefix, &[node_ref.as_str(), name.as_str()]);
+                    graph.nodes.insert(
+                        path.clone(),
+                        GraphNode {
+                            sheet: format!("/{}", prefix.join("/")),
+                            reference: node_ref.into(),
+                            attribute: name.into(),
+                            value: entry.get_value().and_then(|v| entry.format_value(v).ok()),
+                        },
+                    );
+
+                    // Expressions that fail to parse are reported by the evaluator and linter
+                    let tree = match evalexpr::build_operator_tree(entry.get_expression()) {
+                        Ok(tree) => tree,
+                        Err(_) => continue,
+                    };
+                    for id in tree.iter_variable_identifiers() {
+                        if let Some(dep) = resolve(index, &Path::from(id)) {
+                            let dep: Vec<&str> = dep.iter().map(|s| s.as_str()).collect();
+                            graph.edges.insert((full_path(prefix, &dep), path.clone()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// resolve returns the path of the attribute the given path refers to within the index,
+// with the attribute name filled in for references to the value of a component
+fn resolve(index: &SheetIndex, path: &Path) -> Option<Vec<String>> {
+    let mut resolved = vec![];
+    let mut current = index;
+    let mut iter = path.iter();
+    while let Some(segment) = iter.next() {
+        resolved.push(segment.clone());
+        match current.map.get(segment)? {
+            Node::Sheet(sub_index) => current = sub_index,
+            Node::Component(component_index) => {
+                let attribute = iter
+                    .next()
+                    .cloned()
+                    .unwrap_or_else(|| VALUE_FIELD_KEY.into());
+                if iter.next().is_some() || !component_index.contains_key(&attribute) {
+                    return None;
+                }
+                resolved.push(attribute);
+                return Some(resolved);
+            }
+        }
+    }
+    None
+}
+
+fn full_path(prefix: &[String], path: &[&str]) -> String {
+    prefix
+        .iter()
+        .map(|s| s.as_str())
+        .chain(path.iter().copied())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::{evaluate_schematic, index_schematic, Arithmetic};
+    use crate::testutil::{attribute, component, schematic};
+    use kicad_functions::FunctionRegistry;
+
+    #[test]
+    fn dot_graph() {
+        let mut sch = schematic(vec![
+            component("R1", vec![("Value", attribute("R2 * 2", Some("Ohm")))]),
+            component("R2", vec![("Value", attribute("1500", Some("Ohm")))]),
+        ]);
+        let functions = FunctionRegistry::with_builtins();
+        let mut index = index_schematic(&mut sch, &functions).unwrap();
+        evaluate_schematic(&mut index, Arithmetic::Float).unwrap();
+
+        let mut out = Vec::new();
+        write_graph(&index, GraphFormat::Dot, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "digraph dependencies {
+    rankdir=LR;
+    node [shape=box];
+    \"R1.Value\" [label=\"/\\nR1.Value\\n= 3 kOhm\"];
+    \"R2.Value\" [label=\"/\\nR2.Value\\n= 1.5 kOhm\"];
+    \"R2.Value\" -> \"R1.Value\";
+}
+"
+        );
+    }
+}
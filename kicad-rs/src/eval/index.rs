@@ -1,14 +1,19 @@
+use crate::eval::display::Format;
 use crate::eval::entry::Entry;
 use crate::eval::path::Path;
 use crate::parser::VALUE_FIELD_KEY;
 use evalexpr::{Context, ContextWithMutableVariables, EvalexprError, EvalexprResult, Value};
+use kicad_functions::FunctionRegistry;
 use std::collections::HashMap;
 
 pub type ComponentIndex<'a> = HashMap<String, Entry<'a>>;
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct SheetIndex<'a> {
     pub(crate) map: HashMap<String, Node<'a>>,
+    // The file the sheet was parsed from, for context in error messages
+    pub(crate) filename: Option<String>,
+    functions: &'a FunctionRegistry,
 }
 
 #[derive(Debug)]
@@ -18,8 +23,30 @@ pub enum Node<'a> {
 }
 
 impl<'a> SheetIndex<'a> {
-    pub fn new() -> Self {
-        Default::default()
+    pub fn new(functions: &'a FunctionRegistry) -> Self {
+        Self {
+            map: HashMap::new(),
+            filename: None,
+            functions,
+        }
+    }
+
+    pub fn functions(&self) -> &'a FunctionRegistry {
+        self.functions
+    }
+
+    // Use the given formats by unit for all attributes in the hierarchy that don't specify one
+    pub fn set_default_formats(&mut self, formats: &'a HashMap<String, Format>) {
+        for node in self.map.values_mut() {
+            match node {
+                Node::Sheet(sub_index) => sub_index.set_default_formats(formats),
+                Node::Component(component_index) => {
+                    for entry in component_index.values_mut() {
+                        entry.set_default_formats(formats);
+                    }
+                }
+            }
+        }
     }
 
     pub fn resolve_entry<'b>(
@@ -73,7 +100,7 @@ impl<'a> Context for SheetIndex<'a> {
     }
 
     fn call_function(&self, identifier: &str, argument: &Value) -> EvalexprResult<Value> {
-        kicad_functions::call_function(identifier, argument)
+        self.functions.call(identifier, argument)
     }
 }
 
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 // Labels is a trait describing a string-string of labels describing some object
 pub trait Labels {
@@ -11,8 +11,8 @@ pub trait LabelsMatch {
     fn matches<L: Labels>(&self, labels: &L) -> bool;
 }
 
-// Implement the Labels trait for a string-string HashMap
-impl Labels for HashMap<&str, &str> {
+// Implement the Labels trait for a string-string BTreeMap
+impl Labels for BTreeMap<&str, &str> {
     fn get_label(&self, key: &str) -> Option<String> {
         self.get(key).map(|s| s.to_string())
     }
@@ -1,8 +1,20 @@
+pub mod cache;
 pub mod codec;
+pub mod constraints;
+pub mod diff;
 pub mod error;
 pub mod eval;
 pub mod labels;
+pub mod lint;
 pub mod parser;
+pub mod pipeline;
 pub mod policy;
+pub mod project;
+pub mod report;
 pub mod requirements;
+pub mod schema;
+#[cfg(test)]
+mod testutil;
 pub mod types;
+pub mod variant;
+pub mod watch;
@@ -0,0 +1,205 @@
+use crate::error::DynamicResult;
+use crate::eval::{self, Node, Path, SheetIndex, GLOBALS_KEY};
+use crate::parser::SchematicTree;
+use crate::types::Schematic;
+use kicad_functions::FunctionRegistry;
+use std::collections::HashSet;
+use std::fmt;
+
+// Finding is a single problem found by the linter, with the schematic file, the sheet path
+// (e.g. "/" or "/Properties") and the component reference (or "Globals") it was found in
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Finding {
+    pub file: String,
+    pub sheet: String,
+    pub reference: String,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: sheet {}: {}: {}",
+            self.file, self.sheet, self.reference, self.message
+        )
+    }
+}
+
+// lint reports all problems with the expressions and expression fields of the schematic at
+// once, instead of failing on the first one like the evaluator does. The schematic needs to
+// be the one parsed from the given tree, it's only borrowed mutably to index it.
+pub fn lint(
+    tree: &SchematicTree,
+    schematic: &mut Schematic,
+    functions: &FunctionRegistry,
+) -> DynamicResult<Vec<Finding>> {
+    let mut findings = tree.lint_fields();
+    let index = eval::index_schematic(schematic, functions)?;
+    findings.extend(lint_index(&index));
+    findings.sort();
+    findings.dedup();
+    Ok(findings)
+}
+
+// lint_index checks all expressions in the index without evaluating them, and reports expressions
+// that fail to parse, references to components or attributes that don't exist, and globals
+// that aren't referenced by any expression
+fn lint_index(index: &SheetIndex) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut used = HashSet::new();
+    lint_sheet(index, &mut vec![], &mut findings, &mut used);
+    lint_unused_globals(index, &mut vec![], &mut findings, &used);
+    findings
+}
+
+fn lint_sheet(
+    index: &SheetIndex,
+    prefix: &mut Vec<String>,
+    findings: &mut Vec<Finding>,
+    used: &mut HashSet<String>,
+) {
+    for (node_ref, node) in index.map.iter() {
+        let component_index = match node {
+            Node::Sheet(sub_index) => {
+                prefix.push(node_ref.into());
+                lint_sheet(sub_index, prefix, findings, used);
+                prefix.pop();
+                continue;
+            }
+            Node::Component(component_index) => component_index,
+        };
+
+        for (name, entry) in component_index.iter() {
+            let mut finding =
+                |message: String| findings.push(lint_finding(index, prefix, node_ref, message));
+
+            let node = match evalexpr::build_operator_tree(entry.get_expression()) {
+                Ok(node) => node,
+                Err(e) => {
+                    finding(format!(
+                        "{}: invalid expression {:?}: {}",
+                        name,
+                        entry.get_expression(),
+                        e
+                    ));
+                    continue;
+                }
+            };
+
+            for id in node.iter_variable_identifiers() {
+                let p = Path::from(id);
+                used.insert(
+                    prefix
+                        .iter()
+                        .map(|s| s.as_str())
+                        .chain(Some(id))
+                        .collect::<Vec<_>>()
+                        .join("."),
+                );
+                if index.resolve_entry(p.iter()).is_some() {
+                    continue;
+                }
+
+                let first = p.iter().next().map(|s| s.as_str()).unwrap_or_default();
+                if index.map.contains_key(first) {
+                    finding(format!("{}: reference to unknown attribute {}", name, id));
+                } else {
+                    finding(format!(
+                        "{}: reference to unknown component or sheet {}",
+                        name, first
+                    ));
+                }
+            }
+        }
+    }
+}
+
+fn lint_unused_globals(
+    index: &SheetIndex,
+    prefix: &mut Vec<String>,
+    findings: &mut Vec<Finding>,
+    used: &HashSet<String>,
+) {
+    for (node_ref, node) in index.map.iter() {
+        match node {
+            Node::Sheet(sub_index) => {
+                prefix.push(node_ref.into());
+                lint_unused_globals(sub_index, prefix, findings, used);
+                prefix.pop();
+            }
+            Node::Component(globals) if node_ref == GLOBALS_KEY => {
+                for name in globals.keys() {
+                    let p: Vec<&str> = prefix
+                        .iter()
+                        .map(|s| s.as_str())
+                        .chain(vec![GLOBALS_KEY, name])
+                        .collect();
+                    if !used.contains(&p.join(".")) {
+                        findings.push(lint_finding(
+                            index,
+                            prefix,
+                            node_ref,
+                            format!("global {} is never used", name),
+                        ));
+                    }
+                }
+            }
+            Node::Component(_) => (),
+        }
+    }
+}
+
+fn lint_finding(
+    index: &SheetIndex,
+    prefix: &[String],
+    reference: &str,
+    message: String,
+) -> Finding {
+    Finding {
+        file: index.filename.clone().unwrap_or_default(),
+        sheet: format!("/{}", prefix.join("/")),
+        reference: reference.into(),
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{attribute, component, schematic};
+
+    #[test]
+    fn lint_expressions() {
+        let mut sch = schematic(vec![
+            component(
+                "R1",
+                vec![
+                    ("Value", attribute("Globals.Vin * R2.Value", None)),
+                    ("power", attribute("R1.current * C1", None)),
+                    ("current", attribute("(1 +", None)),
+                ],
+            ),
+            component("R2", vec![("Value", attribute("1000", None))]),
+        ]);
+        sch.meta.filename = Some("test.sch".into());
+        sch.globals.insert("Vin".into(), attribute("5", None));
+        sch.globals.insert("Unused".into(), attribute("3", None));
+        let functions = FunctionRegistry::with_builtins();
+        let index = eval::index_schematic(&mut sch, &functions).unwrap();
+
+        let mut messages: Vec<String> = lint_index(&index)
+            .into_iter()
+            .map(|f| format!("{} {}: {}", f.sheet, f.reference, f.message))
+            .collect();
+        messages.sort();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0], "/ Globals: global Unused is never used");
+        // The parse error message itself depends on the evalexpr version
+        assert!(messages[1].starts_with("/ R1: current: invalid expression \"(1 +\": "));
+        assert_eq!(
+            messages[2],
+            "/ R1: power: reference to unknown component or sheet C1"
+        );
+    }
+}
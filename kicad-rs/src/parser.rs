@@ -1,14 +1,40 @@
 use kicad_parse_gen::schematic as kicad_schematic;
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
 use crate::error::{errorf, DynamicResult};
+use crate::eval::{Format, GLOBALS_KEY};
+use crate::lint::Finding;
 use crate::types::*;
 
 // All symbols in Eeschema files have a mandatory value field which is used for the primary
 // unit of the component (i.e. resistance for a resistor, capacitance for a capacitor)
 pub(crate) const VALUE_FIELD_KEY: &str = "Value";
 
+// The names of the fields the model and datasheet labels are read from
+const MODEL_FIELD_KEY: &str = "Model";
+const DATASHEET_FIELD_KEY: &str = "UserDocLink";
+
+// The names of the fields the assembly variants are read from. "DNP" marks the component as
+// not populated. "Variant_<name>" is DNP or Fit, overriding that in the named variant, and
+// "Variant_<name>_<attribute>_expr" overrides the expression of the attribute in it.
+const DNP_FIELD_KEY: &str = "dnp";
+const VARIANT_FIELD_PREFIX: &str = "variant_";
+
+// The name of the fields policy waivers are read from, e.g. "Policy_waive = resistor: reason".
+// Several waivers can be given in fields named "Policy_waive_<anything>".
+const WAIVER_FIELD_KEY: &str = "policy_waive";
+
+// Attributes named "Check" or "Check_<name>" (case-insensitive) are design checks,
+// i.e. boolean expressions that must evaluate to true
+const CHECK_FIELD_KEY: &str = "check";
+
+// is_check returns true if the given attribute name denotes a design check
+pub(crate) fn is_check(attr_name: &str) -> bool {
+    let name = attr_name.to_lowercase();
+    name == CHECK_FIELD_KEY || name.starts_with(&(CHECK_FIELD_KEY.to_string() + "_"))
+}
+
 // SchematicTree keeps track of all kicad_parse_gen
 // Schematics in a hierarchical schematic configuration
 #[derive(Debug)]
@@ -33,23 +59,66 @@ impl SchematicTree {
         })
     }
 
-    // Parse the SchematicTree into our own nested Schematic struct
+    // List the paths of all schematic files in the hierarchy, starting from the node this is called for
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.schematic
+            .filename
+            .iter()
+            .cloned()
+            .chain(self.sub_schematics.values().flat_map(|s| s.files()))
+            .collect()
+    }
+
+    // Parse the SchematicTree into our own nested Schematic struct,
+    // which is the top-level object of the YAML document
     pub fn parse(&self) -> DynamicResult<Schematic> {
-        parse_schematic(self)
+        let mut sch = parse_schematic(self)?;
+        sch.set_type_meta();
+        Ok(sch)
     }
 
     // Update the components in the kicad_parse_gen Schematic tree using the given
-    // nested Schematic struct (copy values from Attributes to ComponentFields)
+    // nested Schematic struct. Values are copied from Attributes to ComponentFields,
+    // and the expression, unit and comment fields of attributes as well as the model,
+    // datasheet and extra label fields are synced where they differ from the schematic.
+    // Other labels (e.g. the footprint, classes or waivers) are never written.
     pub fn update(&mut self, schematic: &Schematic) -> DynamicResult<()> {
-        // Update the fields of all components in this schematic
+        // The components and expressions of a variant differ from those in the schematic
+        if let Some(variant) = schematic.meta.variant.as_ref() {
+            return Err(errorf(&format!(
+                "not writing variant {} back into the schematic",
+                variant
+            )));
+        }
+
+        // Update the fields of all components in this schematic, including all units of
+        // multi-unit symbols, which share the same reference
         for (_, component) in schematic.components.iter() {
-            self.schematic
-                .modify_component(&component.labels.reference, |c| {
-                    for (attr_name, attribute) in component.attributes.iter() {
-                        let name = attr_name.as_str().or_default(VALUE_FIELD_KEY);
-                        c.update_field(name, &attribute.value.to_string());
+            let reference = &component.labels.reference;
+            // Unannotated components (e.g. "R?") share their reference with unrelated ones,
+            // so the element a component was parsed from can't be told apart. They're left
+            // as is, the linter reports those with expressions.
+            if reference.ends_with('?') {
+                continue;
+            }
+
+            let mut found = false;
+            for el in self.schematic.elements.iter_mut() {
+                if let kicad_schematic::Element::Component(c) = el {
+                    if &c.reference == reference {
+                        let unit = unit_name(c);
+                        update_component(c, component, &unit)?;
+                        found = true;
+                        // Only multi-unit symbols have several elements with the same reference
+                        if component.units.is_empty() {
+                            break;
+                        }
                     }
-                })
+                }
+            }
+            if !found {
+                return Err(errorf(&format!("unknown component: {}", reference)));
+            }
         }
 
         // Recursively update sub-schematics
@@ -76,6 +145,253 @@ impl SchematicTree {
         }
         Ok(())
     }
+
+    // Report expression-related problems that are only visible in the KiCad fields: unit,
+    // comment and format fields without a matching expression field, invalid format specs,
+    // components with expressions that aren't annotated yet (e.g. "L?"), which expressions
+    // can't refer to, and text note lines that aren't valid global definitions
+    pub fn lint_fields(&self) -> Vec<Finding> {
+        let mut findings = vec![];
+        self.lint_fields_in("", &mut findings);
+        findings
+    }
+
+    fn lint_fields_in(&self, sheet: &str, findings: &mut Vec<Finding>) {
+        let file = self
+            .schematic
+            .filename
+            .as_ref()
+            .map(|f| f.display().to_string())
+            .unwrap_or_default();
+
+        let components = self.schematic.components();
+        for comp in components.iter() {
+            let mut finding = |message: String| {
+                findings.push(Finding {
+                    file: file.clone(),
+                    sheet: if sheet.is_empty() {
+                        "/".into()
+                    } else {
+                        sheet.into()
+                    },
+                    reference: comp.reference.clone(),
+                    message,
+                })
+            };
+
+            let names: Vec<String> = comp.fields.iter().map(|f| f.name.to_lowercase()).collect();
+            // The expression may be defined in another unit of a multi-unit symbol
+            let symbol_names: Vec<String> = components
+                .iter()
+                .filter(|u| u.reference == comp.reference && !comp.reference.ends_with('?'))
+                .flat_map(|u| u.fields.iter().map(|f| f.name.to_lowercase()))
+                .chain(names.iter().cloned())
+                .collect();
+            let has_expr = |main_key: &str| {
+                symbol_names.contains(&(main_key.to_string() + "_expr"))
+                    || symbol_names.contains(&(main_key.to_string() + "_expression"))
+            };
+
+            if comp.reference.ends_with('?')
+                && names
+                    .iter()
+                    .any(|n| n.ends_with("_expr") || n.ends_with("_expression"))
+            {
+                finding("unannotated component with expressions, annotate the schematic".into());
+            }
+
+            for (name, f) in names.iter().zip(comp.fields.iter()) {
+                if name.ends_with("_format") {
+                    if let Some(Err(e)) = f.value.as_str().filter_empty().map(|v| Format::parse(&v))
+                    {
+                        finding(format!("field {}: {}", f.name, e));
+                    }
+                }
+
+                let suffix = match ["_unit", "_comment", "_format"]
+                    .iter()
+                    .find(|s| name.ends_with(*s))
+                {
+                    Some(s) => s,
+                    None => continue,
+                };
+                let main_key = &name[..name.len() - suffix.len()];
+                if !has_expr(main_key) {
+                    // The suffix is ASCII, so it has the same length in the original name
+                    finding(format!(
+                        "field {} has no matching {}_expr field",
+                        f.name,
+                        &f.name[..f.name.len() - suffix.len()]
+                    ));
+                }
+            }
+        }
+
+        // Text note lines that look like global definitions, but aren't valid ones
+        for message in note_lines(&self.schematic)
+            .filter_map(parse_global_line)
+            .filter_map(Result::err)
+        {
+            findings.push(Finding {
+                file: file.clone(),
+                sheet: if sheet.is_empty() {
+                    "/".into()
+                } else {
+                    sheet.into()
+                },
+                reference: GLOBALS_KEY.into(),
+                message,
+            });
+        }
+
+        for (sheet_id, sub_schematic) in self.sub_schematics.iter() {
+            let sheet_name = sub_schematic
+                .schematic
+                .filename
+                .as_ref()
+                .and_then(|f| f.file_stem())
+                .and_then(|s| s.to_str())
+                .unwrap_or(sheet_id);
+            sub_schematic.lint_fields_in(&format!("{}/{}", sheet, sheet_name), findings);
+        }
+    }
+}
+
+// update_component writes the attributes and labels of the given Component into the fields of
+// the KiCad component, which is the given unit of the symbol. Expression fields of attributes
+// no longer present are removed. Values are written to all units of multi-unit symbols, but
+// the expression, unit and comment fields only to the unit the expression is defined in.
+// Attributes without an expression (e.g. defaulted by a policy) are written as a plain field
+// holding the value, their unit, comment and format can only be stored for expressions.
+fn update_component(
+    c: &mut kicad_schematic::Component,
+    component: &Component,
+    unit: &str,
+) -> DynamicResult<()> {
+    let mut expr_fields = vec![];
+    for (attr_name, attribute) in component.attributes.iter().chain(component.checks.iter()) {
+        let name = attr_name.as_str().or_default(VALUE_FIELD_KEY);
+        let value = attribute.value.to_string();
+        match value.as_str().filter_empty() {
+            // The field is added if the component doesn't have it yet
+            Some(_) => sync_field(c, &[name.into()], Some(&value))?,
+            None => c.update_field(name, &value),
+        }
+        if attribute.expression.as_str().filter_empty().is_none()
+            || !defines_expression(component, attr_name, unit)
+        {
+            continue;
+        }
+
+        let expr_keys = [
+            attr_name.to_string() + "_expr",
+            attr_name.to_string() + "_expression",
+        ];
+        sync_field(c, &expr_keys, Some(&attribute.expression))?;
+        sync_field(
+            c,
+            &[attr_name.to_string() + "_unit"],
+            attribute.unit.as_deref(),
+        )?;
+        sync_field(
+            c,
+            &[attr_name.to_string() + "_comment"],
+            attribute.comment.as_deref(),
+        )?;
+        sync_field(
+            c,
+            &[attr_name.to_string() + "_format"],
+            attribute.format.as_deref(),
+        )?;
+        expr_fields.extend(expr_keys.iter().map(|k| k.to_lowercase()));
+    }
+
+    // Remove the expression, unit and comment fields of attributes that have been removed
+    let removed: Vec<String> = c
+        .fields
+        .iter()
+        .map(|f| f.name.to_lowercase())
+        .filter(|n| n.ends_with("_expr") || n.ends_with("_expression"))
+        .filter(|n| !n.starts_with(VARIANT_FIELD_PREFIX) && !expr_fields.contains(n))
+        .collect();
+    for expr_key in removed.iter() {
+        let main_key = expr_key
+            .trim_end_matches("_expr")
+            .trim_end_matches("_expression");
+        for suffix in ["_expr", "_expression", "_unit", "_comment", "_format"].iter() {
+            sync_field(c, &[main_key.to_string() + suffix], None)?;
+        }
+    }
+
+    let labels = &component.labels;
+    sync_field(c, &[MODEL_FIELD_KEY.into()], labels.model.as_deref())?;
+    sync_field(
+        c,
+        &[DATASHEET_FIELD_KEY.into()],
+        labels.datasheet.as_deref(),
+    )?;
+    for (key, value) in labels.extra.iter() {
+        sync_field(c, std::slice::from_ref(key), Some(value))?;
+    }
+
+    Ok(())
+}
+
+// defines_expression returns true if the expression of the given attribute belongs in the
+// given unit of the component. Expressions not listed for any unit belong in the first one.
+fn defines_expression(component: &Component, attr_name: &str, unit: &str) -> bool {
+    if component.units.is_empty() {
+        return true;
+    }
+
+    let listed = |u: &ComponentUnit| u.expressions.iter().any(|e| e == attr_name);
+    match component.units.iter().find(|(_, u)| listed(u)) {
+        Some(_) => matches!(component.units.get(unit), Some(u) if listed(u)),
+        None => component.units.keys().next().map(|u| u.as_str()) == Some(unit),
+    }
+}
+
+// sync_field makes the field matching any of the given case-insensitive names hold the given
+// value. If no such field exists, it's added with the first name. If the value is None, the
+// field is removed. Empty-like values (see filter_empty) are considered equal to None.
+fn sync_field(
+    c: &mut kicad_schematic::Component,
+    names: &[String],
+    value: Option<&str>,
+) -> DynamicResult<()> {
+    let value = value.filter_empty();
+    let position = c
+        .fields
+        .iter()
+        .position(|f| names.iter().any(|n| n.eq_ignore_ascii_case(&f.name)));
+
+    match (position, value) {
+        (Some(i), value) if c.fields[i].value.as_str().filter_empty() == value => (),
+        (Some(i), Some(value)) => c.fields[i].value = value,
+        (Some(i), None) => {
+            c.fields.remove(i);
+        }
+        (None, Some(value)) => {
+            // Model the new field after the last one, which is usually a hidden user field.
+            // Components always have the mandatory reference and value fields, so a
+            // component without any fields is a malformed schematic.
+            let last = c.fields.last().ok_or_else(|| {
+                errorf(&format!(
+                    "cannot add field {} to component {} without fields",
+                    names[0], c.reference
+                ))
+            })?;
+            let mut field = last.clone();
+            field.i = c.fields.iter().map(|f| f.i).max().unwrap_or(0) + 1;
+            field.name = names[0].clone();
+            field.value = value;
+            field.visible = false;
+            c.fields.push(field);
+        }
+        (None, None) => (),
+    }
+
+    Ok(())
 }
 
 /// Turns the given KiCad schematic into a recursive Schematic struct
@@ -88,6 +404,8 @@ fn parse_schematic(file: &SchematicTree) -> DynamicResult<Schematic> {
 
     // Construct and return the parsed schematic
     Ok(Schematic {
+        api_version: None,
+        kind: None,
         meta,
         globals,
         components,
@@ -121,188 +439,441 @@ fn parse_meta(kicad_sch: &kicad_schematic::Schematic) -> DynamicResult<Schematic
         revision: kicad_sch.description.rev.as_str().filter_empty(),
         company: kicad_sch.description.comp.as_str().filter_empty(),
         comments,
+        variant: None,
     })
 }
 
-/// Parses global definitions from text notes in the KiCad schematic
+/// Parses global definitions from text notes in the KiCad schematic. Lines that aren't valid
+/// definitions are skipped here, and reported by `SchematicTree::lint_fields` instead.
 fn parse_globals(
     kicad_sch: &kicad_schematic::Schematic,
-) -> DynamicResult<HashMap<String, Attribute>> {
-    let mut globals = HashMap::new();
+) -> DynamicResult<BTreeMap<String, Attribute>> {
+    Ok(note_lines(kicad_sch)
+        .filter_map(parse_global_line)
+        .filter_map(Result::ok)
+        .collect())
+}
 
+/// Returns the lines of all text notes in the KiCad schematic
+fn note_lines(kicad_sch: &kicad_schematic::Schematic) -> impl Iterator<Item = &str> {
     // Loop through the elements of the schematic, which includes text notes as well
-    for el in &kicad_sch.elements {
-        // Only match Text elements that have type Note
-        let text_element = match el {
+    kicad_sch
+        .elements
+        .iter()
+        .filter_map(|el| match el {
+            // Only match Text elements that have type Note
             kicad_schematic::Element::Text(t) => match t.t {
-                kicad_schematic::TextType::Note => t,
-                _ => continue,
+                kicad_schematic::TextType::Note => Some(t),
+                _ => None,
             },
-            _ => continue,
-        };
-
-        // TODO: Require a special marked in the text for this parser to parse it.
+            _ => None,
+        })
         // The text element contains literal "\n" elements
-        for line in text_element.text.split("\\n") {
-            // Format: Foo[.Bar.Baz..] = <expr> [; <unit>]
-
-            // First, split by the equals sign. If the equals sign does not exist,
-            // just continue.
-            let (attr_name, expr) = match line.split_once("=") {
-                None => continue,
-                Some(a) => a,
-            };
+        .flat_map(|t| t.text.split("\\n"))
+}
 
-            // Then, split the "remaining" part expr into two parts by ";", where
-            // the first part overwrites expr, and the other part optionally becomes unit
-            let (expr, unit) = match expr.split_once(";") {
-                None => (expr, None),
-                Some(a) => (a.0, Some(a.1)),
-            };
+/// Parses a line of a text note as a global definition. Lines without an equals sign or with
+/// an empty name or expression are plain text, and return None. A name that isn't a plain
+/// identifier (e.g. the dotted "Foo.Bar", which expressions can't refer to) or an expression
+/// that doesn't parse makes the line an invalid definition, and returns an error.
+fn parse_global_line(line: &str) -> Option<Result<(String, Attribute), String>> {
+    // TODO: Require a special marked in the text for this parser to parse it.
+    // Format: Foo = <expr> [; <unit>]
 
-            // Trim whitespace for all variables
-            let (attr_name, expr) = (attr_name.trim(), expr.trim());
+    // First, split by the equals sign. If the equals sign does not exist,
+    // the line is plain text.
+    let (attr_name, expr) = line.split_once("=")?;
 
-            // attr_name and expr must be non-empty
-            if attr_name.is_empty() || expr.is_empty() {
-                continue;
-            }
+    // Then, split the "remaining" part expr into two parts by ";", where
+    // the first part overwrites expr, and the other part optionally becomes unit
+    let (expr, unit) = match expr.split_once(";") {
+        None => (expr, None),
+        Some(a) => (a.0, Some(a.1)),
+    };
 
-            // Push the new attribute into the given vector
-            globals.insert(
-                attr_name.into(),
-                Attribute {
-                    value: String::new().into(), // TODO: How do we resolve this value?
-                    expression: expr.into(),
-                    unit: unit.map(|u| u.trim().into()),
-                    comment: None,
-                },
-            );
-        }
+    // Trim whitespace for all variables
+    let (attr_name, expr) = (attr_name.trim(), expr.trim());
+
+    // attr_name and expr must be non-empty
+    if attr_name.is_empty() || expr.is_empty() {
+        return None;
     }
 
-    Ok(globals)
+    if !is_global_name(attr_name) {
+        return Some(Err(format!("invalid global name: {}", attr_name)));
+    }
+    if let Err(e) = evalexpr::build_operator_tree(expr) {
+        return Some(Err(format!(
+            "global {}: invalid expression {:?}: {}",
+            attr_name, expr, e
+        )));
+    }
+
+    Some(Ok((
+        attr_name.into(),
+        Attribute {
+            value: String::new().into(), // TODO: How do we resolve this value?
+            expression: expr.into(),
+            unit: unit.map(|u| u.trim().into()),
+            comment: None,
+            format: None,
+        },
+    )))
+}
+
+// is_global_name returns true if the given name can be referenced as "Globals.<name>", i.e.
+// it's a plain identifier without dots
+pub(crate) fn is_global_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_alphanumeric() || c == '_')
 }
 
 /// Parses the component definitions present in the given KiCad schematic
 fn parse_components(
     kicad_sch: &kicad_schematic::Schematic,
-) -> DynamicResult<HashMap<String, Component>> {
-    let mut components = HashMap::new();
+) -> DynamicResult<BTreeMap<String, Component>> {
+    let mut components = BTreeMap::new();
 
-    // Walk through all components in the sheet
+    // Group the components in the sheet by reference, the units of multi-unit symbols (e.g.
+    // U1A to U1D of a quad op-amp) are separate components sharing the same reference.
+    // Unannotated components (e.g. "R?") are unrelated, and parsed one by one instead.
+    let mut symbols: Vec<Vec<&kicad_schematic::Component>> = vec![];
     for comp in kicad_sch.components() {
-        // Require comp.name to be non-empty
-        if comp.name.is_empty() {
-            return Err(errorf("Every component must have a name"));
+        match symbols
+            .iter_mut()
+            .find(|units| !comp.reference.ends_with('?') && units[0].reference == comp.reference)
+        {
+            Some(units) => units.push(comp),
+            None => symbols.push(vec![comp]),
         }
+    }
 
-        let footprint_str = get_component_attr(&comp, "Footprint");
-        let symbol_str = comp.name.as_str();
-
-        // Fill in the metadata about the component. Reference and package fields are validated to be non-empty
-        // later, once we know if the component should be included in the result.
-        let mut c = Component {
-            labels: ComponentLabels {
-                reference: comp.reference.clone(),
-                footprint_library: footprint_str.split_char_n(':', 0).or_empty_str(),
-                footprint_name: footprint_str.split_char_n(':', 1).or_empty_str(),
-                symbol_library: symbol_str.split_char_n(':', 0).or_empty_str(),
-                symbol_name: symbol_str.split_char_n(':', 1).or_empty_str(),
-                model: get_component_attr(&comp, "Model"),
-                datasheet: get_component_attr(&comp, "UserDocLink"),
-                extra: HashMap::new(),
-            },
-            classes: vec![],
-            attributes: HashMap::new(),
-            generated: serde_json::Value::Null,
+    for units in symbols {
+        let parsed = if units.len() == 1 {
+            parse_component(units[0])?
+        } else {
+            let (merged, defined_in) = merge_units(&units)?;
+            parse_component(&merged)?.map(|mut c| {
+                c.units = units_of(&c, &units, &defined_in);
+                c
+            })
         };
 
-        // m maps the lower-case representation to the whatever-cased representation
-        let mut m = HashMap::new();
-        // Walk through all the fields, and fill in the m map
-        for f in &comp.fields {
-            // Optimistically try to insert key_lower into m, and error if there was a duplicate
-            let key_lower = f.name.to_lowercase();
-            match m.insert(key_lower, f.name.clone()) {
-                None => (), // Key didn't exist before, all ok
-                Some(oldval) => {
-                    return Err(errorf(&format!(
-                        "duplicate keys: {} and {}",
-                        oldval, f.name
-                    )));
-                }
+        if let Some(c) = parsed {
+            components.insert(c.labels.reference.clone(), c);
+        }
+    }
+
+    Ok(components)
+}
+
+// parse_component parses a single KiCad component, or all units of a multi-unit symbol merged
+// into one. Returns None for components without expressions, which are left out of the result.
+fn parse_component(comp: &kicad_schematic::Component) -> DynamicResult<Option<Component>> {
+    // Require comp.name to be non-empty
+    if comp.name.is_empty() {
+        return Err(errorf("Every component must have a name"));
+    }
+
+    let footprint_str = get_component_attr(comp, "Footprint");
+    let symbol_str = comp.name.as_str();
+
+    // Fill in the metadata about the component. Reference and package fields are validated to be non-empty
+    // later, once we know if the component should be included in the result.
+    let mut c = Component {
+        labels: ComponentLabels {
+            reference: comp.reference.clone(),
+            footprint_library: footprint_str.split_char_n(':', 0).or_empty_str(),
+            footprint_name: footprint_str.split_char_n(':', 1).or_empty_str(),
+            symbol_library: symbol_str.split_char_n(':', 0).or_empty_str(),
+            symbol_name: symbol_str.split_char_n(':', 1).or_empty_str(),
+            model: get_component_attr(comp, MODEL_FIELD_KEY),
+            datasheet: get_component_attr(comp, DATASHEET_FIELD_KEY),
+            dnp: false,
+            variants: BTreeMap::new(),
+            waivers: BTreeMap::new(),
+            extra: BTreeMap::new(),
+        },
+        classes: vec![],
+        attributes: BTreeMap::new(),
+        checks: BTreeMap::new(),
+        units: BTreeMap::new(),
+        generated: serde_json::Value::Null,
+    };
+
+    // m maps the lower-case representation to the whatever-cased representation
+    let mut m = HashMap::new();
+    // Walk through all the fields, and fill in the m map
+    for f in &comp.fields {
+        // Optimistically try to insert key_lower into m, and error if there was a duplicate
+        let key_lower = f.name.to_lowercase();
+        match m.insert(key_lower, f.name.clone()) {
+            None => (), // Key didn't exist before, all ok
+            Some(oldval) => {
+                return Err(errorf(&format!(
+                    "duplicate keys: {} and {}",
+                    oldval, f.name
+                )));
+            }
+        }
+    }
+
+    if let Some(dnp) = get_component_attr_mapped(comp, DNP_FIELD_KEY, &m) {
+        c.labels.dnp = parse_dnp(&comp.reference, DNP_FIELD_KEY, &dnp)?;
+    }
+
+    // Walk through the attributes, and look for one that ends with _expr or _expression
+    for f in &comp.fields {
+        let fname = f.name.to_lowercase();
+        if fname == WAIVER_FIELD_KEY || fname.starts_with(&(WAIVER_FIELD_KEY.to_string() + "_")) {
+            parse_waiver(&mut c.labels.waivers, &comp.reference, &f.value)?;
+            continue;
+        }
+        // Expressions of variants override the attributes, instead of defining new ones
+        if fname.starts_with(VARIANT_FIELD_PREFIX) {
+            parse_variant_field(
+                &mut c.labels.variants,
+                &comp.reference,
+                &fname,
+                &f.value,
+                &m,
+            )?;
+            continue;
+        }
+
+        // Strip the expr suffixes from the lower-cased fname, or skip it if the suffix isn't correct
+        let main_key = if fname.ends_with("_expr") {
+            fname.trim_end_matches("_expr")
+        } else if fname.ends_with("_expression") {
+            fname.trim_end_matches("_expression")
+        } else {
+            continue;
+        };
+
+        // The unit, comment & format values can be found from the main key + the
+        // "_unit"/"_comment"/"_format" suffixes
+        let unit_key = main_key.to_string() + "_unit";
+        let comment_key = main_key.to_string() + "_comment";
+        let format_key = main_key.to_string() + "_format";
+
+        // This will write out "Value" as the attribute name for the default attribute.
+        let attr_name: String = m
+            .get(main_key)
+            .map(|s| s.as_str())
+            .unwrap_or(main_key) // TODO: Instead of defaulting to main_key, fallback to f.name - the expr suffix
+            .into();
+
+        // Design checks are kept separate from the regular attributes
+        let target = if is_check(&attr_name) {
+            &mut c.checks
+        } else {
+            &mut c.attributes
+        };
+
+        // Create a new attribute with the given parameters
+        target.insert(
+            attr_name,
+            Attribute {
+                // Get the main key value. It is ok if it's empty, too.
+                value: Value::parse(get_component_attr_mapped(comp, main_key, &m).or_empty_str()),
+                // As this field corresponds to the main key expression
+                // attribute, we can get the expression directly
+                expression: f.value.clone(),
+                // Optionally, get the unit, a comment and the format
+                unit: get_component_attr_mapped(comp, &unit_key, &m),
+                comment: get_component_attr_mapped(comp, &comment_key, &m),
+                format: get_component_attr_mapped(comp, &format_key, &m),
+            },
+        );
+    }
+
+    // Only register to the list if it has any expressions, or if it has iccc_show = true set
+    if c.attributes.len() > 0
+        || c.checks.len() > 0
+        || get_component_attr_mapped(comp, "iccc_show", &m)
+            .or_empty_str()
+            .is_true_like()
+    {
+        // Validate that required fields are set
+        for (key, val) in &c.labels.to_map() {
+            if val.is_empty() {
+                return Err(errorf(&format!(
+                    "{}: Component.{} is a mandatory field",
+                    &comp.name, key
+                )));
             }
         }
 
-        // Walk through the attributes, and look for one that ends with _expr or _expression
-        for f in &comp.fields {
-            let fname = f.name.to_lowercase();
-            // Strip the expr suffixes from the lower-cased fname, or skip it if the suffix isn't correct
-            let main_key = if fname.ends_with("_expr") {
-                fname.trim_end_matches("_expr")
-            } else if fname.ends_with("_expression") {
-                fname.trim_end_matches("_expression")
+        return Ok(Some(c));
+    }
+
+    Ok(None)
+}
+
+// parse_variant_field parses a "Variant_<name>" field or a "Variant_<name>_<attribute>_expr" field
+// with the given lower-cased name into the variants of a component. Variant names are lower-cased,
+// and can't contain underscores, as the name ends at the first underscore after the prefix.
+fn parse_variant_field(
+    variants: &mut BTreeMap<String, ComponentVariant>,
+    reference: &str,
+    fname: &str,
+    value: &str,
+    m: &HashMap<String, String>,
+) -> DynamicResult<()> {
+    let rest = fname.trim_start_matches(VARIANT_FIELD_PREFIX);
+    let (name, key) = match rest.find('_') {
+        Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+        None => (rest, None),
+    };
+    let value = match value.filter_empty() {
+        Some(value) if !name.is_empty() => value,
+        _ => return Ok(()),
+    };
+
+    let variant = variants.entry(name.into()).or_default();
+    match key {
+        None => variant.dnp = Some(parse_dnp(reference, fname, &value)?),
+        Some(key) => {
+            let main_key = if key.ends_with("_expr") {
+                key.trim_end_matches("_expr")
+            } else if key.ends_with("_expression") {
+                key.trim_end_matches("_expression")
             } else {
-                continue;
+                return Err(errorf(&format!(
+                    "{}: unsupported variant field {}, expected Variant_<name> or Variant_<name>_<attribute>_expr",
+                    reference, fname
+                )));
             };
+            let attr_name = m.get(main_key).map(|s| s.as_str()).unwrap_or(main_key);
+            variant.expressions.insert(attr_name.into(), value);
+        }
+    }
+    Ok(())
+}
 
-            // The unit & comment values can be found from the main key + the "_unit"/"_comment" suffixes
-            let unit_key = main_key.to_string() + "_unit";
-            let comment_key = main_key.to_string() + "_comment";
-
-            // This will write out "Value" as the attribute name for the default attribute.
-            let attr_name = m
-                .get(main_key)
-                .map(|s| s.as_str())
-                .unwrap_or(main_key) // TODO: Instead of defaulting to main_key, fallback to f.name - the expr suffix
-                .into();
-
-            // Create a new attribute with the given parameters
-            c.attributes.insert(
-                attr_name,
-                Attribute {
-                    // Get the main key value. It is ok if it's empty, too.
-                    value: Value::parse(
-                        get_component_attr_mapped(&comp, main_key, &m).or_empty_str(),
-                    ),
-                    // As this field corresponds to the main key expression
-                    // attribute, we can get the expression directly
-                    expression: f.value.clone(),
-                    // Optionally, get the unit and a comment
-                    unit: get_component_attr_mapped(&comp, &unit_key, &m),
-                    comment: get_component_attr_mapped(&comp, &comment_key, &m),
+// parse_waiver parses the value of a waiver field, "<constraint>: <reason>", into the waivers
+// of a component. Empty waiver fields are ignored.
+fn parse_waiver(
+    waivers: &mut BTreeMap<String, String>,
+    reference: &str,
+    value: &str,
+) -> DynamicResult<()> {
+    let value = match value.filter_empty() {
+        Some(value) => value,
+        None => return Ok(()),
+    };
+    match value.find(':') {
+        Some(i) if !value[..i].trim().is_empty() => {
+            waivers.insert(value[..i].trim().into(), value[i + 1..].trim().into());
+            Ok(())
+        }
+        _ => Err(errorf(&format!(
+            "{}: invalid waiver {}, expected <constraint>: <reason>",
+            reference, value
+        ))),
+    }
+}
+
+// parse_dnp parses the value of a DNP or variant field, which is DNP (or true-like) for
+// components that aren't populated, and Fit (or false-like) for components that are
+fn parse_dnp(reference: &str, field: &str, value: &str) -> DynamicResult<bool> {
+    match value.to_lowercase().as_str() {
+        "dnp" | "true" | "1" => Ok(true),
+        "fit" | "false" | "0" => Ok(false),
+        _ => Err(errorf(&format!(
+            "{}: field {} must be DNP or Fit, got {}",
+            reference, field, value
+        ))),
+    }
+}
+
+// merge_units merges the units of a multi-unit symbol into a single KiCad component with the
+// fields of all units. Fields may be defined in several units as long as their values agree.
+// Returns the merged component, and the names of the units each (lower-case) field is set in.
+fn merge_units(
+    units: &[&kicad_schematic::Component],
+) -> DynamicResult<(kicad_schematic::Component, HashMap<String, Vec<String>>)> {
+    let mut merged = units[0].clone();
+    merged.fields.clear();
+    let mut defined_in: HashMap<String, Vec<String>> = HashMap::new();
+
+    for unit in units {
+        let name = unit_name(unit);
+        for f in unit.fields.iter() {
+            let value = f.value.as_str().filter_empty();
+            let key = f.name.to_lowercase();
+            match merged
+                .fields
+                .iter()
+                .position(|m| m.name.to_lowercase() == key)
+            {
+                None => merged.fields.push(f.clone()),
+                Some(i) => match (merged.fields[i].value.as_str().filter_empty(), &value) {
+                    (Some(a), Some(b)) if &a != b => {
+                        return Err(errorf(&format!(
+                            "{}: field {} differs between units {} and {}: {} and {}",
+                            unit.reference, f.name, defined_in[&key][0], name, a, b
+                        )));
+                    }
+                    (None, Some(_)) => merged.fields[i] = f.clone(),
+                    _ => (),
                 },
-            );
+            }
+            if value.is_some() {
+                defined_in.entry(key).or_default().push(name.clone());
+            }
         }
+    }
 
-        // Only register to the list if it has any expressions, or if it has iccc_show = true set
-        if c.attributes.len() > 0
-            || get_component_attr_mapped(&comp, "iccc_show", &m)
-                .or_empty_str()
-                .is_true_like()
-        {
-            // Validate that required fields are set
-            for (key, val) in &c.labels.to_map() {
-                if val.is_empty() {
-                    return Err(errorf(&format!(
-                        "{}: Component.{} is a mandatory field",
-                        &comp.name, key
-                    )));
+    Ok((merged, defined_in))
+}
+
+// units_of lists the attributes and checks of the merged component by the unit their
+// expression field is defined in. Expressions defined in several units are listed in all.
+fn units_of(
+    c: &Component,
+    units: &[&kicad_schematic::Component],
+    defined_in: &HashMap<String, Vec<String>>,
+) -> BTreeMap<String, ComponentUnit> {
+    let mut result: BTreeMap<String, ComponentUnit> = units
+        .iter()
+        .map(|u| (unit_name(u), ComponentUnit::default()))
+        .collect();
+    for name in c.attributes.keys().chain(c.checks.keys()) {
+        let main_key = name.to_lowercase();
+        for suffix in ["_expr", "_expression"].iter() {
+            for unit in defined_in
+                .get(&(main_key.clone() + suffix))
+                .into_iter()
+                .flatten()
+            {
+                let expressions = &mut result.entry(unit.clone()).or_default().expressions;
+                if !expressions.contains(name) {
+                    expressions.push(name.clone());
                 }
             }
-
-            // Grow the components vector
-            components.insert(c.labels.reference.clone(), c);
         }
     }
+    result
+}
 
-    Ok(components)
+// unit_name returns the name of the unit of a KiCad component as shown after its reference,
+// e.g. "A" for the first unit of U1. The unit number is the first item of the "U" line.
+fn unit_name(comp: &kicad_schematic::Component) -> String {
+    let number = comp.u.split_whitespace().next().unwrap_or_default();
+    match number.parse::<u8>() {
+        Ok(n @ 1..=26) => ((b'A' + n - 1) as char).to_string(),
+        _ => number.to_string(),
+    }
 }
 
 /// Parses nested hierarchical schematic definitions present in the given KiCad schematic
-fn parse_sub_schematics(tree: &SchematicTree) -> DynamicResult<HashMap<String, Schematic>> {
-    let mut sub_schematics = HashMap::new();
+fn parse_sub_schematics(tree: &SchematicTree) -> DynamicResult<BTreeMap<String, Schematic>> {
+    let mut sub_schematics = BTreeMap::new();
 
     // Recursively traverse and parse the sub-schematics
     for (id, schematic) in tree.sub_schematics.iter() {
@@ -436,3 +1007,74 @@ impl<'a, T: Default + PartialEq> OrDefault<'a, T> for T {
         self // Otherwise return the current value of the caller
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn global_lines() {
+        let (name, attribute) = parse_global_line("Vin = 12 ; V").unwrap().unwrap();
+        assert_eq!(name, "Vin");
+        assert_eq!(attribute.expression, "12");
+        assert_eq!(attribute.unit.as_deref(), Some("V"));
+
+        assert!(parse_global_line("Plain text").is_none());
+        assert!(parse_global_line("Vin = ").is_none());
+        assert!(parse_global_line("Foo.Bar = 1").unwrap().is_err());
+        assert!(parse_global_line("R1 = (1 +").unwrap().is_err());
+    }
+
+    #[test]
+    fn write_policy_defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("../testdata");
+        for file in ["test.sch", "Properties.sch"].iter() {
+            std::fs::copy(testdata.join(file), dir.path().join(file)).unwrap();
+        }
+        let path = dir.path().join("test.sch");
+        let mut tree = SchematicTree::load(&path).unwrap();
+        let mut sch = tree.parse().unwrap();
+
+        // Fill in defaults like the policy does for the classes of a component
+        let c5 = sch.components.get_mut("C5").unwrap();
+        c5.labels.datasheet = Some("https://example.com/c5.pdf".into());
+        c5.labels.extra.insert("Voltage".into(), "16V".into());
+        c5.attributes.get_mut("Value").unwrap().comment = Some("bulk".into());
+        c5.attributes.insert(
+            "Tolerance".into(),
+            Attribute {
+                value: "20%".into(),
+                expression: "".into(),
+                unit: Some("%".into()),
+                comment: None,
+                format: None,
+            },
+        );
+        tree.update(&sch).unwrap();
+        tree.write().unwrap();
+
+        let tree = SchematicTree::load(&path).unwrap();
+        let components = tree.schematic.components();
+        let c5 = components.iter().find(|c| c.reference == "C5").unwrap();
+        let field = |name: &str| get_component_attr(c5, name);
+        assert_eq!(
+            field(DATASHEET_FIELD_KEY).as_deref(),
+            Some("https://example.com/c5.pdf")
+        );
+        assert_eq!(field("Voltage").as_deref(), Some("16V"));
+        assert_eq!(field("Value_comment").as_deref(), Some("bulk"));
+        assert_eq!(field("Value_unit").as_deref(), Some("F"));
+        // Attributes without an expression are written as a plain field with their value
+        assert_eq!(field("Tolerance").as_deref(), Some("20%"));
+        assert_eq!(field("Tolerance_unit"), None);
+
+        let c5 = &tree.parse().unwrap().components["C5"];
+        assert_eq!(
+            c5.labels.datasheet.as_deref(),
+            Some("https://example.com/c5.pdf")
+        );
+        assert_eq!(c5.attributes["Value"].comment.as_deref(), Some("bulk"));
+        assert!(!c5.attributes.contains_key("Tolerance"));
+    }
+}
@@ -0,0 +1,97 @@
+use crate::cache::Cache;
+use crate::error::DynamicResult;
+use crate::eval;
+use crate::eval::{Engine, Explanation};
+use crate::parser::SchematicTree;
+use crate::policy;
+use crate::project::Project;
+use crate::types::Schematic;
+use std::path::Path;
+
+// The stages shared by the binaries, so that they can be run in-process one after
+// another without marshaling the schematic to YAML in between
+
+// load_project loads the project definitions, either from the given file or from next
+// to the schematic. Not having a project file at all is fine.
+pub fn load_project(schematic_path: &Path, project_path: Option<&Path>) -> DynamicResult<Project> {
+    match project_path {
+        Some(p) => Project::load(p),
+        None => Ok(Project::discover(schematic_path)?.unwrap_or_default()),
+    }
+}
+
+// load loads the hierarchical schematic tree and parses it, merging
+// the project globals into the globals of the root schematic
+pub fn load(path: &Path, project: &Project) -> DynamicResult<(SchematicTree, Schematic)> {
+    let tree = SchematicTree::load(path)?;
+    let mut schematic = tree.parse()?;
+    project.merge_globals(&mut schematic)?;
+    Ok((tree, schematic))
+}
+
+// evaluate evaluates all expressions in the schematic in place, and returns the
+// design checks that don't hold. Function calls in expressions are resolved using
+// the built-ins and the functions defined in the project.
+pub fn evaluate(schematic: &mut Schematic, project: &Project) -> DynamicResult<Vec<String>> {
+    // The index links to the schematic using mutable references, so that's
+    // why the schematic itself needs to be passed in as mutable here.
+    let functions = project.function_registry()?;
+    let formats = project.formats()?;
+    let mut index = eval::index_schematic(schematic, &functions)?;
+    index.set_default_formats(&formats);
+    eval::evaluate_schematic(&mut index, project.arithmetic)?;
+    eval::failed_checks(&index)
+}
+
+// evaluate_variant evaluates the given assembly variant of the schematic like evaluate. The
+// expressions are overridden by those of the variant first, and the components that aren't
+// populated in it are removed only after evaluating, so that others can still refer to them.
+// The design checks of the removed components are left out of the failed checks.
+pub fn evaluate_variant(
+    schematic: &mut Schematic,
+    project: &Project,
+    variant: &str,
+) -> DynamicResult<Vec<String>> {
+    schematic.override_expressions(variant)?;
+    let failed_checks = evaluate(schematic, project)?;
+    let removed = schematic.remove_unpopulated(variant);
+    Ok(failed_checks
+        .into_iter()
+        .filter(|c| !removed.iter().any(|r| c.starts_with(&format!("{}.", r))))
+        .collect())
+}
+
+// explain evaluates the schematic (or the given assembly variant of it) like evaluate, and
+// explains how the value of the attribute at the given path (e.g. "R5.Value") was computed
+pub fn explain(
+    schematic: &mut Schematic,
+    project: &Project,
+    variant: Option<&str>,
+    path: &str,
+) -> DynamicResult<Explanation> {
+    if let Some(variant) = variant {
+        schematic.override_expressions(variant)?;
+    }
+
+    let functions = project.function_registry()?;
+    let formats = project.formats()?;
+    let mut index = eval::index_schematic(schematic, &functions)?;
+    index.set_default_formats(&formats);
+    let mut engine = Engine::compile(&mut index, project.arithmetic)?;
+    engine.record_calls();
+    engine.evaluate()?;
+    engine.explain(path)
+}
+
+// classify classifies the components of the schematic and validates them
+// against the policy in the given CUE file, caching the results of cue if requested
+pub fn classify(
+    schematic: Schematic,
+    policy_path: &Path,
+    cue_bin: &Path,
+    cache: Option<&Cache>,
+) -> DynamicResult<Schematic> {
+    let mut schematic = policy::apply(policy_path, cue_bin, schematic, cache)?;
+    schematic.set_type_meta();
+    Ok(schematic)
+}
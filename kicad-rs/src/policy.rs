@@ -1,14 +1,14 @@
+use crate::cache::Cache;
 use crate::codec;
+use crate::constraints::{check_constraints, SchematicConstraint, Severity, Violation};
 use crate::error::{errorf, DynamicResult};
-use crate::labels::LabelsMatch;
+use crate::labels::{Labels, LabelsMatch};
 use crate::requirements::Requirement;
 use crate::types::{Component, Schematic};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::hash::Hash;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
 use std::io::Write;
-use std::iter::FromIterator;
 use std::path::Path;
 use std::process;
 use std::process::{Command, Stdio};
@@ -20,6 +20,7 @@ const CUE_MAP_BYTES: &'static str = include_str!("cue/map.cue");
 const CUE_MAP_FILE: &'static str = "map.cue";
 const CUE_REDUCE_BYTES: &'static str = include_str!("cue/reduce.cue");
 const CUE_REDUCE_FILE: &'static str = "reduce.cue";
+const CUE_SKIPPED_FILE: &'static str = "skipped.cue";
 const CUE_POLICY_SCHEMA_BYTES: &'static str = include_str!("cue/policy_schema.cue");
 const CUE_POLICY_SCHEMA_FILE: &'static str = "policy_schema.cue";
 
@@ -30,6 +31,18 @@ pub struct SchematicHolder {
     pub schematic: Schematic,
 }
 
+// PolicyRules holds the rules of the policy file that are evaluated in Rust rather than CUE
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRules {
+    pub classifiers: Vec<ComponentClassifier>,
+    pub constraints: Vec<SchematicConstraint>,
+    // The severity of the #Policy of each class, conflicts with it only fail if it's an error
+    #[serde(default)]
+    pub severities: BTreeMap<String, Severity>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 #[serde(deny_unknown_fields)]
@@ -44,34 +57,117 @@ pub struct ComponentClassifier {
     #[serde(skip_serializing_if = "HashMap::is_empty")]
     #[serde(default)]
     pub attributes: HashMap<String, Requirement>,
+    // Sheet matching, on the "path" (e.g. "/Power/Buck") and "filename" of the sheet
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    #[serde(default)]
+    pub sheet: HashMap<String, Requirement>,
+    // Class matching, on the classes already assigned to the component. Each assigned class
+    // is a key with the value "true", so e.g. Exists requires the class to be assigned.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    #[serde(default)]
+    pub classes: HashMap<String, Requirement>,
 }
 
-pub fn apply(cue_policy_file: &Path, cue_bin: &Path, sch: Schematic) -> DynamicResult<Schematic> {
-    // Write the in-binary policy schema file to a temporary directory
-    let tmp_dir = tempdir()?;
-    let mut m = HashMap::new();
-    m.insert(
-        CUE_POLICY_SCHEMA_FILE.into(),
-        Vec::from([CUE_COMMON_BYTES, CUE_POLICY_SCHEMA_BYTES]),
-    );
-    let m = write_temp_files(&tmp_dir, m)?;
-
-    // Execute the given policy file using CUE and decode the resulting YAML ComponentClassifier list
-    // This will use the first, classification part of the given CUE file
-    let c = Command::new(cue_bin)
-        .arg("export")
-        .arg(&m.get(CUE_POLICY_SCHEMA_FILE).unwrap())
-        .arg(cue_policy_file)
-        .arg("--expression=#Classifiers")
-        .arg("--out=yaml")
-        .output()?;
-
-    // Decode the classifiers from the YAML output
-    let classifiers: Vec<ComponentClassifier> = codec::unmarshal_yaml(c.stdout.as_slice())?;
+// ClassLabels exposes the classes assigned to a component as labels for class matching
+struct ClassLabels<'c>(&'c [String]);
+
+impl<'c> Labels for ClassLabels<'c> {
+    fn get_label(&self, key: &str) -> Option<String> {
+        self.0
+            .iter()
+            .find(|c| c.as_str() == key)
+            .map(|_| "true".into())
+    }
+}
+
+// apply classifies the components of the schematic and applies the policy in the given CUE
+// file. The outputs of cue are cached if a cache is given.
+pub fn apply(
+    cue_policy_file: &Path,
+    cue_bin: &Path,
+    sch: Schematic,
+    cache: Option<&Cache>,
+) -> DynamicResult<Schematic> {
+    // The policy file and the version of cue are inputs to all the CUE steps, and key
+    // their cached outputs together with the embedded CUE files and the step input. Files
+    // imported by the policy aren't part of the key, so changes to them aren't noticed.
+    // cue only runs on a cache miss, which is also when a missing binary is reported.
+    let policy = fs::read(cue_policy_file)?;
+    let cue_version = match cache {
+        Some(cache) => cache
+            .version_of(cue_bin)
+            .map_err(|e| cue_error(cue_bin, e))?,
+        None => vec![],
+    };
+
+    // Execute the given policy file using CUE to export the classifiers and constraints
+    let rules_yaml = run_cached(
+        cache,
+        &[
+            b"rules",
+            CUE_COMMON_BYTES.as_bytes(),
+            CUE_POLICY_SCHEMA_BYTES.as_bytes(),
+            &policy,
+            &cue_version,
+        ],
+        || {
+            // Write the in-binary policy schema file to a temporary directory
+            let tmp_dir = tempdir()?;
+            let mut m = HashMap::new();
+            m.insert(
+                CUE_POLICY_SCHEMA_FILE.into(),
+                Vec::from([CUE_COMMON_BYTES, CUE_POLICY_SCHEMA_BYTES]),
+            );
+            let m = write_temp_files(&tmp_dir, m)?;
+
+            let c = Command::new(cue_bin)
+                .arg("export")
+                .arg(&m.get(CUE_POLICY_SCHEMA_FILE).unwrap())
+                .arg(cue_policy_file)
+                .arg("--expression=#Rules")
+                .arg("--out=yaml")
+                .output()
+                .map_err(|e| cue_error(cue_bin, e))?;
+            if !c.status.success() {
+                writeln!(std::io::stderr(), "{}", str::from_utf8(&c.stderr)?)?;
+                return Err(errorf("policy error occurred"));
+            }
+            Ok(c.stdout)
+        },
+    )?;
+
+    // Decode the classifiers and constraints from the YAML output
+    let rules: PolicyRules = codec::unmarshal_yaml(rules_yaml.as_slice())?;
+    let classifiers = rules.classifiers;
+    for classifier in classifiers.iter() {
+        let requirements = classifier
+            .labels
+            .iter()
+            .chain(classifier.attributes.iter())
+            .chain(classifier.sheet.iter())
+            .chain(classifier.classes.iter());
+        for (name, requirement) in requirements {
+            requirement.validate().map_err(|e| {
+                errorf(&format!("classifier {}: {}: {}", classifier.class, name, e))
+            })?;
+        }
+    }
+    for constraint in rules.constraints.iter() {
+        for (name, requirement) in constraint.require.iter() {
+            requirement
+                .validate()
+                .map_err(|e| errorf(&format!("constraint {}: {}: {}", constraint.name, name, e)))?;
+        }
+        if let Some(sum) = constraint.sum.as_ref() {
+            sum.max
+                .validate()
+                .map_err(|e| errorf(&format!("constraint {}: {}", constraint.name, e)))?;
+        }
+    }
 
     // Make the now-owned schematic mutable for passing into the classifier function
     let mut sch = sch;
-    classify_components(&mut sch, &classifiers);
+    classify_components(&mut sch, &order_classifiers(&classifiers)?, &mut vec![]);
 
     // Marshal the now-classified Schematic back to YAML, inside the SchematicHolder struct
     // (to support arbitrary Schematic nesting) for piping to CUE defaulting and validation step
@@ -79,81 +175,403 @@ pub fn apply(cue_policy_file: &Path, cue_bin: &Path, sch: Schematic) -> DynamicR
     let sch_holder = SchematicHolder { schematic: sch };
     codec::marshal_yaml(&sch_holder, &mut schematic_yaml)?;
 
-    // Write the in-binary "map and reduction" CUE files to a temporary directory
-    let tmp_dir = tempdir()?;
-    let mut m = HashMap::new();
-    m.insert(
-        CUE_MAP_FILE.into(),
-        Vec::from([CUE_COMMON_BYTES, CUE_MAP_BYTES, CUE_POLICY_SCHEMA_BYTES]),
-    );
-    m.insert(
-        CUE_REDUCE_FILE.into(),
-        Vec::from([CUE_COMMON_BYTES, CUE_REDUCE_BYTES]),
-    );
-    let m = write_temp_files(&tmp_dir, m)?;
-
-    // Assemble the CUE command that will apply the policy of the given cue_policy_file
-    let cmd = format!(
-        "{} export --out=yaml {} {} yaml: - | {} export --out=yaml {} yaml: -",
-        cue_bin.display(),
-        cue_policy_file.display(),
-        m.get(CUE_MAP_FILE).unwrap(),
-        cue_bin.display(),
-        m.get(CUE_REDUCE_FILE).unwrap(),
-    );
-
-    // Execute the command with schematic_yaml passed to stdin, and capture stdout/stderr.
-    let output = exec_shell_pipe(&cmd, schematic_yaml)?;
-    // If there's data in stderr, we got an error we shall pass through
-    if !output.stderr.is_empty() {
-        writeln!(std::io::stderr(), "{}", str::from_utf8(&output.stderr)?)?;
-        return Err(errorf("policy error occurred"));
+    // The policy of each class is applied to the components assigned the class, unless they
+    // waive it. Waivers are listed in the report.
+    let mut components = Vec::new();
+    collect_components(&sch_holder.schematic, &mut components);
+    let policies: Vec<(&Component, &str)> = components
+        .iter()
+        .flat_map(|c| {
+            c.classes
+                .iter()
+                .filter(|class| rules.severities.contains_key(*class))
+                .map(move |class| (*c, class.as_str()))
+        })
+        .collect();
+    let mut skipped = Skipped::new();
+    for (c, class) in policies.iter() {
+        if c.labels.waivers.contains_key(*class) {
+            skip(&mut skipped, c, class);
+        }
     }
 
+    let run = PolicyRun {
+        cue_policy_file,
+        cue_bin,
+        cache,
+        policy: &policy,
+        cue_version: &cue_version,
+        schematic_yaml: &schematic_yaml,
+    };
+    let mut violations = vec![];
+    let output = match run.apply(&skipped)? {
+        Ok(output) => output,
+        Err(_) => {
+            // Find the policies that conflict, and only fail on those of the error severity.
+            // The others are skipped for the components concerned, which then don't get the
+            // defaults of those policies.
+            violations = policy_violations(&run, &policies, &skipped, &rules.severities)?;
+            for violation in violations.iter() {
+                writeln!(std::io::stderr(), "{}", violation)?;
+            }
+            let errors = violations
+                .iter()
+                .filter(|v| v.severity == Severity::Error)
+                .count();
+            if errors > 0 {
+                return Err(errorf(&format!("{} policy rule(s) violated", errors)));
+            }
+            for (c, class) in policies.iter() {
+                if violations
+                    .iter()
+                    .any(|v| v.constraint == *class && v.references.contains(&c.labels.reference))
+                {
+                    skip(&mut skipped, c, class);
+                }
+            }
+            run.apply(&skipped)?
+                .map_err(|conflict| policy_error(&conflict))?
+        }
+    };
+
     // If we were successful in passing it through, unmarshal back into the Schematic
-    let sch_holder: SchematicHolder = codec::unmarshal_yaml(output.stdout.as_slice())?;
+    let sch_holder: SchematicHolder = codec::unmarshal_yaml(output.as_slice())?;
+
+    // Check the constraints on the defaulted components. All violations are reported, but
+    // only those of the error severity that aren't waived fail the policy.
+    let constraint_violations = check_constraints(&sch_holder.schematic, &rules.constraints);
+    for violation in constraint_violations.iter() {
+        writeln!(std::io::stderr(), "{}", violation)?;
+    }
+    violations.extend(constraint_violations);
+    let errors = violations
+        .iter()
+        .filter(|v| v.severity == Severity::Error && !v.waived)
+        .count();
+    if errors > 0 {
+        return Err(errorf(&format!("{} policy rule(s) violated", errors)));
+    }
 
     Ok(sch_holder.schematic)
 }
 
-// classify_components recursively walks through a Schematic, and assigns the Component.classes field
-fn classify_components(sch: &mut Schematic, classifiers: &Vec<ComponentClassifier>) {
-    for (_, comp) in sch.components.iter_mut() {
-        comp.classes = classify_component(comp, classifiers);
-    }
-    for (_, sch) in sch.sub_schematics.iter_mut() {
-        classify_components(sch, classifiers);
+// Skipped lists the classes whose policy isn't applied to a component, keyed by reference
+type Skipped = BTreeMap<String, BTreeSet<String>>;
+
+fn skip(skipped: &mut Skipped, component: &Component, class: &str) {
+    skipped
+        .entry(component.labels.reference.clone())
+        .or_default()
+        .insert(class.into());
+}
+
+fn is_skipped(skipped: &Skipped, component: &Component, class: &str) -> bool {
+    skipped
+        .get(&component.labels.reference)
+        .map_or(false, |classes| classes.contains(class))
+}
+
+// PolicyRun holds the inputs of the CUE step defaulting and validating the classified
+// schematic against the policy
+struct PolicyRun<'a> {
+    cue_policy_file: &'a Path,
+    cue_bin: &'a Path,
+    cache: Option<&'a Cache>,
+    policy: &'a [u8],
+    cue_version: &'a [u8],
+    schematic_yaml: &'a [u8],
+}
+
+impl<'a> PolicyRun<'a> {
+    // apply runs the CUE step with the policies of the given classes skipped for each
+    // component. If the schematic conflicts with the policy, the error output of cue is
+    // returned instead of the defaulted schematic.
+    fn apply(&self, skipped: &Skipped) -> DynamicResult<Result<Vec<u8>, String>> {
+        // map.cue expects the skipped classes as a "#Skipped: [reference]: [class]: true"
+        // struct, which is written as JSON
+        let skipped: BTreeMap<&str, BTreeMap<&str, bool>> = skipped
+            .iter()
+            .map(|(r, classes)| {
+                (
+                    r.as_str(),
+                    classes.iter().map(|c| (c.as_str(), true)).collect(),
+                )
+            })
+            .collect();
+        let skipped_cue = format!("#Skipped: {}", serde_json::to_string(&skipped)?);
+
+        let mut conflict = None;
+        let result = run_cached(
+            self.cache,
+            &[
+                b"apply",
+                CUE_COMMON_BYTES.as_bytes(),
+                CUE_MAP_BYTES.as_bytes(),
+                CUE_POLICY_SCHEMA_BYTES.as_bytes(),
+                CUE_REDUCE_BYTES.as_bytes(),
+                self.policy,
+                self.cue_version,
+                self.schematic_yaml,
+                skipped_cue.as_bytes(),
+            ],
+            || {
+                // Write the in-binary "map and reduction" CUE files to a temporary directory
+                let tmp_dir = tempdir()?;
+                let mut m = HashMap::new();
+                m.insert(
+                    CUE_MAP_FILE.into(),
+                    Vec::from([CUE_COMMON_BYTES, CUE_MAP_BYTES, CUE_POLICY_SCHEMA_BYTES]),
+                );
+                m.insert(
+                    CUE_REDUCE_FILE.into(),
+                    Vec::from([CUE_COMMON_BYTES, CUE_REDUCE_BYTES]),
+                );
+                m.insert(CUE_SKIPPED_FILE.into(), Vec::from([skipped_cue.as_str()]));
+                let m = write_temp_files(&tmp_dir, m)?;
+
+                // Assemble the CUE command that will apply the policy of the given cue_policy_file
+                let cmd = format!(
+                    "{} export --out=yaml {} {} {} yaml: - | {} export --out=yaml {} yaml: -",
+                    self.cue_bin.display(),
+                    self.cue_policy_file.display(),
+                    m.get(CUE_MAP_FILE).unwrap(),
+                    m.get(CUE_SKIPPED_FILE).unwrap(),
+                    self.cue_bin.display(),
+                    m.get(CUE_REDUCE_FILE).unwrap(),
+                );
+
+                // Execute the command with schematic_yaml passed to stdin, and capture stdout/stderr.
+                let output = exec_shell_pipe(&cmd, self.schematic_yaml.to_vec())?;
+                // If there's data in stderr, the schematic conflicts with the policy
+                if !output.stderr.is_empty() {
+                    conflict = Some(String::from_utf8_lossy(&output.stderr).into_owned());
+                    return Err(errorf("policy error occurred"));
+                }
+                Ok(output.stdout)
+            },
+        );
+        match (result, conflict) {
+            (Ok(output), _) => Ok(Ok(output)),
+            (Err(_), Some(conflict)) => Ok(Err(conflict)),
+            (Err(e), None) => Err(e),
+        }
     }
 }
 
-// classify_component returns a list of classes for a given component, given the set of classifiers
-fn classify_component(comp: &Component, classifiers: &Vec<ComponentClassifier>) -> Vec<String> {
-    // Map all classifiers to their name if the component matches the classifier
-    let matched_classes: Vec<String> = classifiers
+// cue_error reports that cue couldn't be executed from the given path
+fn cue_error(cue_bin: &Path, e: impl std::fmt::Display) -> Box<dyn std::error::Error> {
+    errorf(&format!(
+        "could not execute cue with the invocation: '{}' ({}). \
+        Install cue before attempting to run this program.",
+        cue_bin.display(),
+        e
+    ))
+}
+
+// policy_error reports a conflict with the policy that can't be waived
+fn policy_error(conflict: &str) -> Box<dyn std::error::Error> {
+    eprintln!("{}", conflict);
+    errorf("policy error occurred")
+}
+
+// policy_violations finds the class policies the components conflict with, by applying the
+// policy of one class at a time. The conflicting components are found by their reference in
+// the paths cue reports (e.g. "schematic.components.R1.attributes.tolerance.value"). Conflicts
+// with #SheetPolicy or #RootPolicy are returned as an error, as they can't be waived.
+fn policy_violations(
+    run: &PolicyRun,
+    policies: &[(&Component, &str)],
+    skipped: &Skipped,
+    severities: &BTreeMap<String, Severity>,
+) -> DynamicResult<Vec<Violation>> {
+    let applied: Vec<(&Component, &str)> = policies
         .iter()
-        .filter_map(|classifier| {
-            // Require that both all labels and attribute requirements match
-            if !classifier.labels.matches(&comp.labels.to_map()) {
-                return None;
-            }
-            if !classifier.attributes.matches(&comp.attributes) {
-                return None;
-            }
+        .filter(|(c, class)| !is_skipped(skipped, c, class))
+        .copied()
+        .collect();
+    // Skip the policies of all classes but the given one, if any
+    let applying_only = |only: Option<&str>| {
+        let mut skipped = skipped.clone();
+        for (c, class) in applied.iter().filter(|(_, class)| Some(*class) != only) {
+            skip(&mut skipped, c, class);
+        }
+        skipped
+    };
 
-            // If we get all the way here, we have "matched" with this class.
-            return Some(classifier.class.clone());
-        })
+    run.apply(&applying_only(None))?
+        .map_err(|conflict| policy_error(&conflict))?;
+
+    let classes: BTreeSet<&str> = applied.iter().map(|(_, class)| *class).collect();
+    let mut violations = vec![];
+    for class in classes {
+        let conflict = match run.apply(&applying_only(Some(class)))? {
+            Ok(_) => continue,
+            Err(conflict) => conflict,
+        };
+        let concerned: Vec<&str> = applied
+            .iter()
+            .filter(|(_, c)| *c == class)
+            .map(|(c, _)| c.labels.reference.as_str())
+            .collect();
+        let references = conflicting_references(&conflict, &concerned);
+        violations.push(Violation {
+            constraint: class.into(),
+            severity: severities.get(class).copied().unwrap_or_default(),
+            message: format!(
+                "{} conflict(s) with the policy: {}",
+                references.join(", "),
+                conflict.lines().next().unwrap_or_default().trim()
+            ),
+            references,
+            waived: false,
+        });
+    }
+    Ok(violations)
+}
+
+// conflicting_references returns the references of the given components that the conflict
+// reported by cue concerns, or all of them if it doesn't name any
+fn conflicting_references(conflict: &str, concerned: &[&str]) -> Vec<String> {
+    let mut references: Vec<String> = concerned
+        .iter()
+        .filter(|r| conflict.contains(&format!("components.{}.", r)))
+        .map(|r| r.to_string())
         .collect();
+    if references.is_empty() {
+        references = concerned.iter().map(|r| r.to_string()).collect();
+    }
+    references.sort();
+    references.dedup();
+    references
+}
+
+// collect_components lists the components of the schematic hierarchy
+fn collect_components<'s>(sch: &'s Schematic, components: &mut Vec<&'s Component>) {
+    components.extend(sch.components.values());
+    for sub_schematic in sch.sub_schematics.values() {
+        collect_components(sub_schematic, components);
+    }
+}
+
+// order_classifiers orders the classifiers such that the classifiers of the classes another
+// classifier matches on come before it. Classes are otherwise kept in the order they're first
+// defined in, and the classifiers of each class in the order they're defined in.
+fn order_classifiers(
+    classifiers: &[ComponentClassifier],
+) -> DynamicResult<Vec<&ComponentClassifier>> {
+    // The classes in the order they're first defined in, with the classes they depend on
+    let mut classes: Vec<(&str, HashSet<&str>)> = vec![];
+    for classifier in classifiers.iter() {
+        let dependencies: HashSet<&str> =
+            classifier.classes.values().flat_map(|r| r.keys()).collect();
+        match classes.iter_mut().find(|(c, _)| *c == classifier.class) {
+            Some((_, deps)) => deps.extend(dependencies),
+            None => classes.push((&classifier.class, dependencies)),
+        }
+    }
+    for (class, dependencies) in classes.iter() {
+        if let Some(d) = dependencies
+            .iter()
+            .find(|d| !classes.iter().any(|(c, _)| c == *d))
+        {
+            return Err(errorf(&format!(
+                "classifier {}: unknown class {}",
+                class, d
+            )));
+        }
+    }
+
+    let mut ordered: Vec<&str> = vec![];
+    while ordered.len() < classes.len() {
+        let (next, _) = classes
+            .iter()
+            .find(|(c, deps)| !ordered.contains(c) && deps.iter().all(|d| ordered.contains(d)))
+            .ok_or_else(|| {
+                let remaining: Vec<&str> = classes
+                    .iter()
+                    .map(|(c, _)| *c)
+                    .filter(|c| !ordered.contains(c))
+                    .collect();
+                errorf(&format!(
+                    "class dependency loop between {}",
+                    remaining.join(", ")
+                ))
+            })?;
+        ordered.push(next);
+    }
+
+    Ok(ordered
+        .iter()
+        .flat_map(|class| classifiers.iter().filter(move |c| c.class == *class))
+        .collect())
+}
+
+// classify_components recursively walks through a Schematic, and assigns the Component.classes
+// field. The prefix holds the names of the sheets traversed to reach the schematic.
+fn classify_components(
+    sch: &mut Schematic,
+    classifiers: &[&ComponentClassifier],
+    prefix: &mut Vec<String>,
+) {
+    let path = format!("/{}", prefix.join("/"));
+    let filename = sch
+        .meta
+        .filename
+        .as_ref()
+        .and_then(|f| Path::new(f).file_name())
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut sheet = BTreeMap::new();
+    sheet.insert("path", path.as_str());
+    sheet.insert("filename", filename.as_str());
+
+    for (_, comp) in sch.components.iter_mut() {
+        comp.classes = classify_component(comp, classifiers, &sheet);
+    }
+    for (name, sch) in sch.sub_schematics.iter_mut() {
+        prefix.push(name.clone());
+        classify_components(sch, classifiers, prefix);
+        prefix.pop();
+    }
+}
+
+// classify_component returns a list of classes for a given component, given the set of
+// classifiers in dependency order and the labels of the sheet the component is in
+fn classify_component(
+    comp: &Component,
+    classifiers: &[&ComponentClassifier],
+    sheet: &BTreeMap<&str, &str>,
+) -> Vec<String> {
+    let mut classes: Vec<String> = vec![];
+    for classifier in classifiers.iter() {
+        // As there might be many classifiers of the same name that match the component,
+        // the class is only assigned once, in the order its classifiers are defined
+        if classes.contains(&classifier.class) {
+            continue;
+        }
 
-    // As there might be many classifiers of the same name that have matched with a component,
-    // filter all duplicates
-    filter_duplicates(&matched_classes)
+        // Require that all label, attribute, sheet and class requirements match
+        if classifier.labels.matches(&comp.labels.to_map())
+            && classifier.attributes.matches(&comp.attributes)
+            && classifier.sheet.matches(sheet)
+            && classifier.classes.matches(&ClassLabels(&classes))
+        {
+            classes.push(classifier.class.clone());
+        }
+    }
+    classes
 }
 
-// filter_duplicates inserts all items into a HashSet, and builds a new vector without any duplicates
-fn filter_duplicates<T: Eq + Clone + Hash>(list: &Vec<T>) -> Vec<T> {
-    let f: HashSet<&T> = HashSet::from_iter(list.iter());
-    f.iter().map(|s| s.clone().to_owned()).collect()
+// run_cached runs a CUE step, or returns its output from the cache if one is given and the
+// step was run successfully with the same inputs before
+fn run_cached<F>(cache: Option<&Cache>, inputs: &[&[u8]], run: F) -> DynamicResult<Vec<u8>>
+where
+    F: FnOnce() -> DynamicResult<Vec<u8>>,
+{
+    match cache {
+        Some(cache) => cache.get_or_insert_with(inputs, run),
+        None => run(),
+    }
 }
 
 fn write_temp_files(
@@ -192,3 +610,73 @@ fn exec_shell_pipe(cmd: &str, stdin_data: Vec<u8>) -> DynamicResult<process::Out
 
     Ok(child.wait_with_output()?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{attribute, component, schematic};
+    use crate::types::Attribute;
+
+    #[test]
+    fn classify_by_sheet_and_class() {
+        let classifiers: Vec<ComponentClassifier> = serde_yaml::from_str(
+            "
+- class: small_capacitor
+  classes: {cap: {op: Exists, key: capacitor}}
+  attributes: {lt_100n: {op: Lt, key: Value, values: ['100n']}}
+- class: capacitor
+  labels: {c: {op: In, key: symbolName, values: [C]}}
+- class: power_stage
+  sheet: {power: {op: StartsWith, key: path, values: [/Power]}}
+",
+        )
+        .unwrap();
+        let capacitor = |reference: &str, value: &str, expression: &str| {
+            let mut c = component(
+                reference,
+                vec![(
+                    "Value",
+                    Attribute {
+                        value: value.into(),
+                        ..attribute(expression, None)
+                    },
+                )],
+            );
+            c.labels.symbol_name = "C".into();
+            c
+        };
+        let mut sch = schematic(vec![capacitor("C1", "10 nF", "10e-9")]);
+        sch.sub_schematics.insert(
+            "Power".into(),
+            schematic(vec![capacitor("C2", "1 uF", "1e-6")]),
+        );
+
+        classify_components(
+            &mut sch,
+            &order_classifiers(&classifiers).unwrap(),
+            &mut vec![],
+        );
+        assert_eq!(
+            sch.components["C1"].classes,
+            vec!["capacitor", "small_capacitor"]
+        );
+        assert_eq!(
+            sch.sub_schematics["Power"].components["C2"].classes,
+            vec!["capacitor", "power_stage"]
+        );
+    }
+
+    #[test]
+    fn conflicts_by_reference() {
+        let conflict = "schematic.subSchematics.Power.components.R12.attributes.tolerance.value: \
+            conflicting values 5 and <1.1 (mismatched types int and number):";
+        assert_eq!(
+            conflicting_references(conflict, &["R1", "R12", "R2"]),
+            vec!["R12"]
+        );
+        assert_eq!(
+            conflicting_references("incomplete value string", &["R2", "R1"]),
+            vec!["R1", "R2"]
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use crate::codec;
+use crate::error::{errorf, DynamicResult};
+use crate::eval::{Arithmetic, Format};
+use crate::parser::is_global_name;
+use crate::types::{Attribute, Schematic};
+use kicad_functions::FunctionRegistry;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+// The project file is looked up from the directory of the root schematic,
+// i.e. it lives next to the KiCad .pro file
+pub const PROJECT_FILE_NAME: &str = "kicad-rs.yaml";
+
+// Project holds project-level definitions that apply to the whole schematic
+// hierarchy. Globals are merged into the root schematic, functions are
+// expression macros like "fn led_r(vcc, vf, i) = (vcc - vf) / i", and
+// arithmetic selects between float (default) and integer evaluation.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct Project {
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    #[serde(default)]
+    pub globals: HashMap<String, GlobalDefinition>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub functions: Vec<String>,
+    #[serde(default)]
+    pub arithmetic: Arithmetic,
+    // Format specs (see eval::Format) by unit, for the attributes that don't specify one
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    #[serde(default)]
+    pub formats: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct GlobalDefinition {
+    pub expression: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub unit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub comment: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub format: Option<String>,
+}
+
+impl Project {
+    // Load the project from the given project file
+    pub fn load(path: &Path) -> DynamicResult<Self> {
+        let f = File::open(path).map_err(|e| errorf(&format!("{}: {}", path.display(), e)))?;
+        codec::unmarshal_yaml(f).map_err(|e| errorf(&format!("{}: {}", path.display(), e)))
+    }
+
+    // The path of the project file next to the given root schematic
+    pub fn default_path(schematic_path: &Path) -> PathBuf {
+        schematic_path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(PROJECT_FILE_NAME)
+    }
+
+    // Look up the project file next to the given root schematic, and load it if it exists
+    pub fn discover(schematic_path: &Path) -> DynamicResult<Option<Self>> {
+        let path = Self::default_path(schematic_path);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        Self::load(&path).map(Some)
+    }
+
+    // Merge the project globals into the globals of the given (root) schematic.
+    // Defining the same global both in the project and in the schematic is an error.
+    pub fn merge_globals(&self, sch: &mut Schematic) -> DynamicResult<()> {
+        for (name, def) in self.globals.iter() {
+            if !is_global_name(name) {
+                return Err(errorf(&format!("invalid global name: {}", name)));
+            }
+            if sch.globals.contains_key(name) {
+                return Err(errorf(&format!("duplicate global definition: {}", name)));
+            }
+
+            sch.globals.insert(
+                name.into(),
+                Attribute {
+                    value: String::new().into(),
+                    expression: def.expression.clone(),
+                    unit: def.unit.clone(),
+                    comment: def.comment.clone(),
+                    format: def.format.clone(),
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    // Parse the format specs of the project by unit
+    pub fn formats(&self) -> DynamicResult<HashMap<String, Format>> {
+        self.formats
+            .iter()
+            .map(|(unit, spec)| {
+                Format::parse(spec)
+                    .map(|f| (unit.clone(), f))
+                    .map_err(|e| errorf(&format!("format for {}: {}", unit, e)))
+            })
+            .collect()
+    }
+
+    // Build a function registry containing the built-in functions
+    // and the expression macros defined in the project
+    pub fn function_registry(&self) -> DynamicResult<FunctionRegistry> {
+        let mut functions = FunctionRegistry::with_builtins();
+        for definition in self.functions.iter() {
+            functions.register_macro(definition)?;
+        }
+        Ok(functions)
+    }
+}
@@ -0,0 +1,108 @@
+use crate::error::DynamicResult;
+use crate::parser::VALUE_FIELD_KEY;
+use crate::types::{Component, Schematic};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+// ReportLine aggregates all components that would be bought as the same part,
+// i.e. that share the symbol, footprint, model and value
+#[derive(Debug, Default)]
+struct ReportLine {
+    references: Vec<String>,
+    classes: Vec<String>,
+}
+
+// write_report writes a bill of materials-like Markdown report of all the components
+// in the schematic hierarchy, grouping identical parts together
+pub fn write_report<W: Write>(schematic: &Schematic, w: &mut W) -> DynamicResult<()> {
+    let mut lines = BTreeMap::new();
+    collect_lines(schematic, &mut lines);
+
+    match schematic.meta.title.as_ref() {
+        Some(title) => writeln!(w, "# {}", title)?,
+        None => writeln!(w, "# Bill of materials")?,
+    }
+    writeln!(w)?;
+    if let Some(variant) = schematic.meta.variant.as_ref() {
+        writeln!(w, "Assembly variant: {}", variant)?;
+        writeln!(w)?;
+    }
+    writeln!(
+        w,
+        "| Quantity | References | Value | Symbol | Footprint | Model | Classes |"
+    )?;
+    writeln!(w, "| --- | --- | --- | --- | --- | --- | --- |")?;
+    for ((symbol, footprint, model, value), line) in lines.iter() {
+        writeln!(
+            w,
+            "| {} | {} | {} | {} | {} | {} | {} |",
+            line.references.len(),
+            line.references.join(", "),
+            value,
+            symbol,
+            footprint,
+            model,
+            line.classes.join(", ")
+        )?;
+    }
+
+    // List the class policies and constraints waived for components, with the reasons given
+    let mut waivers = Vec::new();
+    collect_waivers(schematic, &mut waivers);
+    if !waivers.is_empty() {
+        writeln!(w)?;
+        writeln!(w, "## Policy waivers")?;
+        writeln!(w)?;
+        writeln!(w, "| Reference | Policy or constraint | Reason |")?;
+        writeln!(w, "| --- | --- | --- |")?;
+        for (reference, constraint, reason) in waivers.iter() {
+            writeln!(w, "| {} | {} | {} |", reference, constraint, reason)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn collect_waivers<'s>(schematic: &'s Schematic, waivers: &mut Vec<(&'s str, &'s str, &'s str)>) {
+    for component in schematic.components.values() {
+        for (constraint, reason) in component.labels.waivers.iter() {
+            waivers.push((&component.labels.reference, constraint, reason));
+        }
+    }
+
+    for sub_schematic in schematic.sub_schematics.values() {
+        collect_waivers(sub_schematic, waivers);
+    }
+}
+
+fn collect_lines(
+    schematic: &Schematic,
+    lines: &mut BTreeMap<(String, String, String, String), ReportLine>,
+) {
+    for component in schematic.components.values() {
+        let line = lines.entry(line_key(component)).or_default();
+        line.references.push(component.labels.reference.clone());
+        for class in component.classes.iter() {
+            if !line.classes.contains(class) {
+                line.classes.push(class.clone());
+            }
+        }
+    }
+
+    for sub_schematic in schematic.sub_schematics.values() {
+        collect_lines(sub_schematic, lines);
+    }
+}
+
+fn line_key(c: &Component) -> (String, String, String, String) {
+    let labels = &c.labels;
+    (
+        format!("{}:{}", labels.symbol_library, labels.symbol_name),
+        format!("{}:{}", labels.footprint_library, labels.footprint_name),
+        labels.model.clone().unwrap_or_default(),
+        c.attributes
+            .get(VALUE_FIELD_KEY)
+            .map(|a| a.value.to_string())
+            .unwrap_or_default(),
+    )
+}
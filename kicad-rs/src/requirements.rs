@@ -1,5 +1,8 @@
+use crate::error::{errorf, DynamicResult};
+use crate::eval::parse_quantity;
 use crate::labels::{Labels, LabelsMatch};
-use serde::{Deserialize, Serialize};
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
 
 // Requirement specifies a requirement for a Labels key-value set.
@@ -12,18 +15,87 @@ pub enum Requirement {
     // At least one value
     In { key: String, values: Vec<String> },
     NotIn { key: String, values: Vec<String> },
+    StartsWith { key: String, values: Vec<String> },
+    EndsWith { key: String, values: Vec<String> },
 
     // One value
     Equals { key: String, values: [String; 1] },
     NotEquals { key: String, values: [String; 1] },
+    // A regular expression, e.g. "^R_0[46]02"
+    Matches { key: String, values: [Pattern; 1] },
 
     // No values
     Exists { key: String },
     DoesNotExist { key: String },
 
     // One numeric value
-    Gt { key: String, values: [f64; 1] },
-    Lt { key: String, values: [f64; 1] },
+    Gt { key: String, values: [Threshold; 1] },
+    Lt { key: String, values: [Threshold; 1] },
+    Gte { key: String, values: [Threshold; 1] },
+    Lte { key: String, values: [Threshold; 1] },
+
+    // Two numeric values, the inclusive lower and upper bound
+    Between { key: String, values: [Threshold; 2] },
+
+    // Groups of requirements, which match if any, all or none of them match
+    AnyOf { requirements: Vec<Requirement> },
+    AllOf { requirements: Vec<Requirement> },
+    Not { requirements: Vec<Requirement> },
+}
+
+// Threshold is the value of a numeric requirement, given either as a number, or as a
+// string with an SI prefix and unit like "100 nF" (see eval::parse_quantity)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Threshold {
+    Number(f64),
+    Quantity(String),
+}
+
+impl Threshold {
+    pub fn value(&self) -> Option<f64> {
+        match self {
+            Threshold::Number(n) => Some(*n),
+            Threshold::Quantity(s) => parse_quantity(s),
+        }
+    }
+
+    // Check that the threshold is numeric, as a non-numeric one would never match
+    pub fn validate(&self) -> DynamicResult<()> {
+        match (self.value(), self) {
+            (None, Threshold::Quantity(s)) => Err(errorf(&format!("not a numeric value: {}", s))),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Pattern is the regular expression of a Matches requirement. It is compiled once when the
+// requirement is decoded; an invalid expression never matches and is reported by validate
+#[derive(Debug)]
+pub struct Pattern {
+    source: String,
+    regex: Result<Regex, regex::Error>,
+}
+
+impl Pattern {
+    pub fn new(source: &str) -> Self {
+        Pattern {
+            source: source.to_owned(),
+            regex: Regex::new(source),
+        }
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|s| Pattern::new(&s))
+    }
 }
 
 // Implement the LabelsMatch trait for a HashMap of requirements ANDed together
@@ -39,20 +111,101 @@ impl LabelsMatch for Requirement {
         match self {
             Requirement::In { key, values } => Requirement::match_in(labels, key, values),
             Requirement::NotIn { key, values } => !Requirement::match_in(labels, key, values),
+            Requirement::StartsWith { key, values } => {
+                Requirement::match_str(labels, key, |s| values.iter().any(|v| s.starts_with(v)))
+            }
+            Requirement::EndsWith { key, values } => {
+                Requirement::match_str(labels, key, |s| values.iter().any(|v| s.ends_with(v)))
+            }
             Requirement::Equals { key, values } => Requirement::match_in(labels, key, values),
             Requirement::NotEquals { key, values } => !Requirement::match_in(labels, key, values),
+            Requirement::Matches { key, values } => match &values[0].regex {
+                Ok(re) => Requirement::match_str(labels, key, |s| re.is_match(s)),
+                Err(_) => false, // Reported by validate
+            },
             Requirement::Exists { key } => labels.get_label(key).is_some(),
             Requirement::DoesNotExist { key } => labels.get_label(key).is_none(),
             Requirement::Gt { key, values } => {
-                Requirement::match_numeric(labels, key, |n| n > values[0])
+                Requirement::match_numeric(labels, key, values, |n, t| n > t[0])
             }
             Requirement::Lt { key, values } => {
-                Requirement::match_numeric(labels, key, |n| n < values[0])
+                Requirement::match_numeric(labels, key, values, |n, t| n < t[0])
+            }
+            Requirement::Gte { key, values } => {
+                Requirement::match_numeric(labels, key, values, |n, t| n >= t[0])
+            }
+            Requirement::Lte { key, values } => {
+                Requirement::match_numeric(labels, key, values, |n, t| n <= t[0])
+            }
+            Requirement::Between { key, values } => {
+                Requirement::match_numeric(labels, key, values, |n, t| t[0] <= n && n <= t[1])
+            }
+            Requirement::AnyOf { requirements } => requirements.iter().any(|r| r.matches(labels)),
+            Requirement::AllOf { requirements } => requirements.iter().all(|r| r.matches(labels)),
+            Requirement::Not { requirements } => !requirements.iter().any(|r| r.matches(labels)),
+        }
+    }
+}
+
+impl Requirement {
+    // Check that the regular expressions and thresholds of the requirement (and the
+    // requirements nested in it) are valid, as invalid ones would never match
+    pub fn validate(&self) -> DynamicResult<()> {
+        match self {
+            Requirement::Matches { values, .. } => {
+                if let Err(e) = &values[0].regex {
+                    return Err(errorf(&e.to_string()));
+                }
+            }
+            Requirement::Gt { values, .. }
+            | Requirement::Lt { values, .. }
+            | Requirement::Gte { values, .. }
+            | Requirement::Lte { values, .. } => validate_thresholds(values)?,
+            Requirement::Between { values, .. } => validate_thresholds(values)?,
+            Requirement::AnyOf { requirements }
+            | Requirement::AllOf { requirements }
+            | Requirement::Not { requirements } => {
+                for r in requirements.iter() {
+                    r.validate()?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+impl Requirement {
+    // List the keys the requirement (and the requirements nested in it) match on
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            Requirement::AnyOf { requirements }
+            | Requirement::AllOf { requirements }
+            | Requirement::Not { requirements } => {
+                requirements.iter().flat_map(|r| r.keys()).collect()
             }
+            Requirement::In { key, .. }
+            | Requirement::NotIn { key, .. }
+            | Requirement::StartsWith { key, .. }
+            | Requirement::EndsWith { key, .. }
+            | Requirement::Equals { key, .. }
+            | Requirement::NotEquals { key, .. }
+            | Requirement::Matches { key, .. }
+            | Requirement::Exists { key }
+            | Requirement::DoesNotExist { key }
+            | Requirement::Gt { key, .. }
+            | Requirement::Lt { key, .. }
+            | Requirement::Gte { key, .. }
+            | Requirement::Lte { key, .. }
+            | Requirement::Between { key, .. } => vec![key.as_str()],
         }
     }
 }
 
+fn validate_thresholds(thresholds: &[Threshold]) -> DynamicResult<()> {
+    thresholds.iter().try_for_each(|t| t.validate())
+}
+
 // Helper functions for the above matches function
 impl Requirement {
     fn match_in<L>(labels: &L, key: &String, values: &[String]) -> bool
@@ -64,16 +217,73 @@ impl Requirement {
             .map(|val| values.contains(&val.to_owned()))
             .unwrap_or(false)
     }
-    fn match_numeric<L, P>(labels: &L, key: &String, p: P) -> bool
+    fn match_str<L, P>(labels: &L, key: &str, p: P) -> bool
     where
         L: Labels,
-        P: FnOnce(f64) -> bool,
+        P: FnOnce(&str) -> bool,
     {
-        labels
-            .get_label(key)
-            .map(|s| s.parse::<f64>().ok())
-            .flatten()
-            .map(|num| p(num))
-            .unwrap_or(false)
+        labels.get_label(key).map(|s| p(&s)).unwrap_or(false)
+    }
+    // match_numeric compares the value of the label as a number, which may have an
+    // SI prefix and unit (e.g. "100 nF"), against the numeric values of the requirement
+    fn match_numeric<L, P>(labels: &L, key: &str, thresholds: &[Threshold], p: P) -> bool
+    where
+        L: Labels,
+        P: FnOnce(f64, &[f64]) -> bool,
+    {
+        let thresholds: Option<Vec<f64>> = thresholds.iter().map(|t| t.value()).collect();
+        match (
+            labels.get_label(key).and_then(|s| parse_quantity(&s)),
+            thresholds,
+        ) {
+            (Some(num), Some(t)) => p(num, &t),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn requirement(yaml: &str) -> Requirement {
+        let r: Requirement = serde_yaml::from_str(yaml).unwrap();
+        r.validate().unwrap();
+        r
+    }
+
+    #[test]
+    fn operators() {
+        let labels: BTreeMap<&str, &str> =
+            vec![("footprintName", "R_0402_1005Metric"), ("Value", "100 nF")]
+                .into_iter()
+                .collect();
+        let matches = |yaml: &str| requirement(yaml).matches(&labels);
+
+        assert!(matches("{op: Lt, key: Value, values: ['1u']}"));
+        assert!(matches("{op: Gt, key: Value, values: [1e-8]}"));
+        assert!(matches("{op: Gte, key: Value, values: ['100n']}"));
+        assert!(!matches("{op: Lte, key: Value, values: ['99n']}"));
+        assert!(matches("{op: Between, key: Value, values: ['10n', '1u']}"));
+        assert!(matches(
+            "{op: Matches, key: footprintName, values: ['^R_0[46]02']}"
+        ));
+        assert!(matches(
+            "{op: StartsWith, key: footprintName, values: [C_, R_]}"
+        ));
+        assert!(!matches(
+            "{op: EndsWith, key: footprintName, values: [Imperial]}"
+        ));
+        assert!(matches(
+            "{op: AnyOf, requirements: [{op: Exists, key: model}, {op: Lt, key: Value, values: [1]}]}"
+        ));
+        assert!(!matches(
+            "{op: Not, requirements: [{op: AllOf, requirements: [{op: Exists, key: Value}]}]}"
+        ));
+
+        let invalid: Requirement =
+            serde_yaml::from_str("{op: Matches, key: Value, values: ['(']}").unwrap();
+        assert!(invalid.validate().is_err());
     }
 }
@@ -0,0 +1,324 @@
+use crate::types::{API_VERSION, KIND};
+use serde_json::{json, Value};
+
+// schematic_schema generates the JSON Schema of the YAML document output by the parser and
+// classifier, i.e. the types in types.rs. It must be kept in sync with the serde attributes
+// of those types, which the tests below check by validating a fully populated Schematic.
+// schema/schematic.schema.json is generated from this using the schema binary.
+pub fn schematic_schema() -> Value {
+    let string_map = |value: Value| {
+        json!({
+            "type": "object",
+            "additionalProperties": value
+        })
+    };
+
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": format!("{} {}", API_VERSION, KIND),
+        "description": "A KiCad schematic hierarchy as parsed (and optionally classified) by kicad-rs",
+        "allOf": [
+            { "$ref": "#/definitions/Schematic" },
+            {
+                "required": ["apiVersion", "kind"],
+                "properties": {
+                    "apiVersion": { "const": API_VERSION },
+                    "kind": { "const": KIND }
+                }
+            }
+        ],
+        "definitions": {
+            "Schematic": {
+                "type": "object",
+                "additionalProperties": false,
+                "required": ["meta"],
+                "properties": {
+                    "apiVersion": { "type": "string" },
+                    "kind": { "type": "string" },
+                    "meta": { "$ref": "#/definitions/SchematicMeta" },
+                    "globals": string_map(json!({ "$ref": "#/definitions/Attribute" })),
+                    "components": string_map(json!({ "$ref": "#/definitions/Component" })),
+                    "subSchematics": string_map(json!({ "$ref": "#/definitions/Schematic" }))
+                }
+            },
+            "SchematicMeta": {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "filename": { "type": "string" },
+                    "title": { "type": "string" },
+                    "date": { "type": "string" },
+                    "revision": { "type": "string" },
+                    "company": { "type": "string" },
+                    "comments": { "type": "array", "items": { "type": "string" } },
+                    "variant": { "type": "string" }
+                }
+            },
+            "Component": {
+                "type": "object",
+                "additionalProperties": false,
+                "required": ["labels"],
+                "properties": {
+                    "labels": { "$ref": "#/definitions/ComponentLabels" },
+                    "classes": { "type": "array", "items": { "type": "string" } },
+                    "attributes": string_map(json!({ "$ref": "#/definitions/Attribute" })),
+                    "checks": string_map(json!({ "$ref": "#/definitions/Attribute" })),
+                    "units": string_map(json!({ "$ref": "#/definitions/ComponentUnit" })),
+                    "generated": {
+                        "description": "Intermediate data of the policy engine, never output"
+                    }
+                }
+            },
+            "ComponentUnit": {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "expressions": { "type": "array", "items": { "type": "string" } }
+                }
+            },
+            "ComponentLabels": {
+                "type": "object",
+                "additionalProperties": false,
+                "required": [
+                    "reference",
+                    "footprintName",
+                    "footprintLibrary",
+                    "symbolName",
+                    "symbolLibrary"
+                ],
+                "properties": {
+                    "reference": { "type": "string" },
+                    "footprintName": { "type": "string" },
+                    "footprintLibrary": { "type": "string" },
+                    "symbolName": { "type": "string" },
+                    "symbolLibrary": { "type": "string" },
+                    "model": { "type": "string" },
+                    "datasheet": { "type": "string" },
+                    "dnp": { "type": "boolean" },
+                    "variants": string_map(json!({ "$ref": "#/definitions/ComponentVariant" })),
+                    "waivers": string_map(json!({ "type": "string" })),
+                    "extra": string_map(json!({ "type": "string" }))
+                }
+            },
+            "ComponentVariant": {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "dnp": { "type": "boolean" },
+                    "expressions": string_map(json!({ "type": "string" }))
+                }
+            },
+            "Attribute": {
+                "type": "object",
+                "additionalProperties": false,
+                "required": ["type", "value", "expression"],
+                "properties": {
+                    "type": { "enum": ["String", "Float"] },
+                    "value": { "type": ["string", "number"] },
+                    "expression": { "type": "string" },
+                    "unit": { "type": "string" },
+                    "comment": { "type": "string" },
+                    "format": { "type": "string" }
+                },
+                "oneOf": [
+                    {
+                        "properties": {
+                            "type": { "const": "String" },
+                            "value": { "type": "string" }
+                        }
+                    },
+                    {
+                        "properties": {
+                            "type": { "const": "Float" },
+                            "value": { "type": "number" }
+                        }
+                    }
+                ]
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{
+        self, Attribute, Component, ComponentLabels, ComponentUnit, ComponentVariant, Schematic,
+        SchematicMeta,
+    };
+    use std::collections::BTreeMap;
+    use std::iter::FromIterator;
+
+    // validate checks the value against the subset of JSON Schema used by schematic_schema,
+    // returning the path of the first mismatch
+    fn validate(root: &Value, schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+        let fail = |what: &str| -> Result<(), String> { Err(format!("{}: {}", path, what)) };
+
+        if let Some(r) = schema["$ref"].as_str() {
+            let name = r.trim_start_matches("#/definitions/");
+            return validate(root, &root["definitions"][name], value, path);
+        }
+        for s in schema["allOf"].as_array().into_iter().flatten() {
+            validate(root, s, value, path)?;
+        }
+        if let Some(one_of) = schema["oneOf"].as_array() {
+            let matching = one_of
+                .iter()
+                .filter(|s| validate(root, s, value, path).is_ok())
+                .count();
+            if matching != 1 {
+                return fail(&format!("matches {} of the oneOf schemas", matching));
+            }
+        }
+        if !schema["const"].is_null() && schema["const"] != *value {
+            return fail("doesn't match const");
+        }
+        if let Some(e) = schema["enum"].as_array() {
+            if !e.contains(value) {
+                return fail("not in enum");
+            }
+        }
+
+        let types: Vec<&str> = match &schema["type"] {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(ts) => ts.iter().filter_map(|t| t.as_str()).collect(),
+            _ => vec![],
+        };
+        let type_matches = |t: &&str| match *t {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "boolean" => value.is_boolean(),
+            _ => false,
+        };
+        if !types.is_empty() && !types.iter().any(type_matches) {
+            return fail(&format!("not of type {:?}", types));
+        }
+
+        if let Some(items) = value.as_array() {
+            for (i, item) in items.iter().enumerate() {
+                validate(root, &schema["items"], item, &format!("{}/{}", path, i))?;
+            }
+        }
+        if let Some(object) = value.as_object() {
+            for key in schema["required"].as_array().into_iter().flatten() {
+                if !object.contains_key(key.as_str().unwrap()) {
+                    return fail(&format!("missing required property {}", key));
+                }
+            }
+            for (key, v) in object {
+                let p = format!("{}/{}", path, key);
+                match (&schema["properties"][key], &schema["additionalProperties"]) {
+                    (Value::Null, Value::Bool(false)) if schema["properties"].is_object() => {
+                        return fail(&format!("unknown property {}", key))
+                    }
+                    (Value::Null, Value::Null) | (Value::Null, Value::Bool(_)) => (),
+                    (Value::Null, additional) => validate(root, additional, v, &p)?,
+                    (property, _) => validate(root, property, v, &p)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn attribute(value: types::Value) -> Attribute {
+        Attribute {
+            value,
+            expression: "1 + 2".into(),
+            unit: Some("V".into()),
+            comment: Some("comment".into()),
+            format: Some("rkm".into()),
+        }
+    }
+
+    // A schematic with all fields of all types set, built with struct literals so that adding
+    // a field to types.rs requires updating this test, and the schema along with it
+    fn populated_schematic() -> Schematic {
+        let map = |k: &str, v: &str| BTreeMap::from_iter(vec![(k.to_string(), v.to_string())]);
+        let component = Component {
+            labels: ComponentLabels {
+                reference: "R1".into(),
+                footprint_name: "R_0603".into(),
+                footprint_library: "Resistor_SMD".into(),
+                symbol_name: "R".into(),
+                symbol_library: "Device".into(),
+                model: Some("RC0603".into()),
+                datasheet: Some("https://example.com".into()),
+                dnp: true,
+                variants: BTreeMap::from_iter(vec![(
+                    "lite".to_string(),
+                    ComponentVariant {
+                        dnp: Some(false),
+                        expressions: map("Value", "2 * 3"),
+                    },
+                )]),
+                waivers: map("lt_5", "reason"),
+                extra: map("manufacturer", "Yageo"),
+            },
+            classes: vec!["resistor".into()],
+            attributes: BTreeMap::from_iter(vec![
+                ("Value".to_string(), attribute(1.5.into())),
+                ("tolerance".to_string(), attribute("1%".into())),
+            ]),
+            checks: BTreeMap::from_iter(vec![("Check".to_string(), attribute("true".into()))]),
+            units: BTreeMap::from_iter(vec![(
+                "A".to_string(),
+                ComponentUnit {
+                    expressions: vec!["Value".into()],
+                },
+            )]),
+            generated: serde_json::Value::Null,
+        };
+
+        let meta = |filename: &str| SchematicMeta {
+            filename: Some(filename.into()),
+            title: Some("title".into()),
+            date: Some("2021-01-01".into()),
+            revision: Some("1".into()),
+            company: Some("company".into()),
+            comments: vec!["comment".into()],
+            variant: Some("lite".into()),
+        };
+
+        let mut sch = Schematic {
+            api_version: None,
+            kind: None,
+            meta: meta("test.sch"),
+            globals: BTreeMap::from_iter(vec![("Vin".to_string(), attribute(12.0.into()))]),
+            components: BTreeMap::from_iter(vec![("R1".to_string(), component)]),
+            sub_schematics: BTreeMap::from_iter(vec![(
+                "sub".to_string(),
+                Schematic {
+                    api_version: None,
+                    kind: None,
+                    meta: meta("sub.sch"),
+                    globals: BTreeMap::new(),
+                    components: BTreeMap::new(),
+                    sub_schematics: BTreeMap::new(),
+                },
+            )]),
+        };
+        sch.set_type_meta();
+        sch
+    }
+
+    #[test]
+    fn schema_matches_types() {
+        let schema = schematic_schema();
+        let value = serde_json::to_value(populated_schematic()).unwrap();
+        assert_eq!(validate(&schema, &schema, &value, ""), Ok(()));
+    }
+
+    #[test]
+    fn schema_rejects_unknown_fields() {
+        let schema = schematic_schema();
+        let mut value = serde_json::to_value(populated_schematic()).unwrap();
+        value["components"]["R1"]["labels"]["unknown"] = "x".into();
+        assert!(validate(&schema, &schema, &value, "").is_err());
+        let mut value = serde_json::to_value(populated_schematic()).unwrap();
+        value["globals"]["Vin"]["type"] = "String".into();
+        assert!(validate(&schema, &schema, &value, "").is_err());
+    }
+}
@@ -0,0 +1,67 @@
+use crate::types::{Attribute, Component, ComponentLabels, Schematic, SchematicMeta};
+use std::collections::BTreeMap;
+
+// Helpers for building schematics in tests. Fields that a test cares about (e.g. the value
+// of an attribute, or the footprint of a component) are set on the returned structs.
+
+// schematic returns a sheet containing the given components, keyed by their reference
+pub fn schematic(components: Vec<Component>) -> Schematic {
+    Schematic {
+        api_version: None,
+        kind: None,
+        meta: SchematicMeta {
+            filename: None,
+            title: None,
+            date: None,
+            revision: None,
+            company: None,
+            comments: vec![],
+            variant: None,
+        },
+        globals: BTreeMap::new(),
+        components: components
+            .into_iter()
+            .map(|c| (c.labels.reference.clone(), c))
+            .collect(),
+        sub_schematics: BTreeMap::new(),
+    }
+}
+
+// component returns a component with the given reference and attributes, and placeholder
+// footprint and symbol labels that don't match any particular kind of part
+pub fn component(reference: &str, attributes: Vec<(&str, Attribute)>) -> Component {
+    Component {
+        labels: ComponentLabels {
+            reference: reference.into(),
+            footprint_name: "Footprint".into(),
+            footprint_library: "Footprints".into(),
+            symbol_name: "Symbol".into(),
+            symbol_library: "Symbols".into(),
+            model: None,
+            datasheet: None,
+            dnp: false,
+            variants: BTreeMap::new(),
+            waivers: BTreeMap::new(),
+            extra: BTreeMap::new(),
+        },
+        classes: vec![],
+        attributes: attributes
+            .into_iter()
+            .map(|(name, a)| (name.into(), a))
+            .collect(),
+        checks: BTreeMap::new(),
+        units: BTreeMap::new(),
+        generated: Default::default(),
+    }
+}
+
+// attribute returns a not yet evaluated attribute with the given expression and unit
+pub fn attribute(expression: &str, unit: Option<&str>) -> Attribute {
+    Attribute {
+        value: "".into(),
+        expression: expression.into(),
+        unit: unit.map(|u| u.into()),
+        comment: None,
+        format: None,
+    }
+}
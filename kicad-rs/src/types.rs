@@ -1,25 +1,39 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::iter::FromIterator;
 
+use crate::error::{errorf, DynamicResult};
 use crate::labels::Labels;
 
-// These types are used to structure the YAML-formatted output
+// These types are used to structure the YAML-formatted output. All maps are ordered by
+// key, so that the output is deterministic and diffs between revisions stay minimal.
+
+// The version of the YAML schema, and the kind of the top-level object in the YAML
+// document. The JSON Schema for the document is published in schema/.
+pub const API_VERSION: &str = "kicad.racklet.io/v1alpha1";
+pub const KIND: &str = "Schematic";
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 #[serde(deny_unknown_fields)]
 pub struct Schematic {
+    // apiVersion and kind are only set for the top-level schematic of a document
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub api_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub kind: Option<String>,
     pub meta: SchematicMeta,
-    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
     #[serde(default)]
-    pub globals: HashMap<String, Attribute>,
-    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub globals: BTreeMap<String, Attribute>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
     #[serde(default)]
-    pub components: HashMap<String, Component>,
-    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub components: BTreeMap<String, Component>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
     #[serde(default)]
-    pub sub_schematics: HashMap<String, Schematic>,
+    pub sub_schematics: BTreeMap<String, Schematic>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -44,6 +58,10 @@ pub struct SchematicMeta {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
     pub comments: Vec<String>,
+    // The assembly variant the schematic was narrowed down to, if any (see variant.rs)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub variant: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -54,9 +72,18 @@ pub struct Component {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
     pub classes: Vec<String>,
-    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[serde(default)]
+    pub attributes: BTreeMap<String, Attribute>,
+    // Design checks are boolean assertions like "C3.voltagerating > 1.5 * Globals.Vin"
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
     #[serde(default)]
-    pub attributes: HashMap<String, Attribute>,
+    pub checks: BTreeMap<String, Attribute>,
+    // The units of a multi-unit symbol (e.g. "A" to "D" of a quad op-amp), keyed by unit name.
+    // Only set for symbols with several units, which are merged into one component.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[serde(default)]
+    pub units: BTreeMap<String, ComponentUnit>,
 
     // Disregard everything in this field by never serializing it, but allowing
     // to deserialize (to avoid an "unknown fields" error).
@@ -65,6 +92,17 @@ pub struct Component {
     pub generated: serde_json::Value,
 }
 
+// ComponentUnit lists the attributes and design checks whose expression fields are defined
+// in a single unit of a multi-unit symbol. Values are written to all units of the symbol.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct ComponentUnit {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub expressions: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 #[serde(deny_unknown_fields)]
@@ -80,14 +118,70 @@ pub struct ComponentLabels {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     pub datasheet: Option<String>,
-    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    // Do-not-populate, the component is left out of all variants that don't fit it explicitly
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub dnp: bool,
+    // The assembly variants that differ from the default for this component, keyed by name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[serde(default)]
+    pub variants: BTreeMap<String, ComponentVariant>,
+    // The class policies and constraints waived for this component, with the reason for each waiver
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[serde(default)]
+    pub waivers: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[serde(default)]
+    pub extra: BTreeMap<String, String>,
+}
+
+// ComponentVariant describes how a component differs in an assembly variant: whether it's
+// populated (overriding the DNP label), and the expressions of attributes and checks it overrides
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct ComponentVariant {
+    #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
-    pub extra: HashMap<String, String>,
+    pub dnp: Option<bool>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[serde(default)]
+    pub expressions: BTreeMap<String, String>,
+}
+
+impl Schematic {
+    // Set the apiVersion and kind of the document to the current ones
+    pub fn set_type_meta(&mut self) {
+        self.api_version = Some(API_VERSION.into());
+        self.kind = Some(KIND.into());
+    }
+
+    // Check that the apiVersion and kind of a decoded document are supported. Documents
+    // without them (e.g. written by hand) are assumed to be of the current version.
+    pub fn check_type_meta(&self) -> DynamicResult<()> {
+        if let Some(v) = self
+            .api_version
+            .as_ref()
+            .filter(|v| v.as_str() != API_VERSION)
+        {
+            return Err(errorf(&format!(
+                "unsupported apiVersion {}, expected {}",
+                v, API_VERSION
+            )));
+        }
+        if let Some(k) = self.kind.as_ref().filter(|k| k.as_str() != KIND) {
+            return Err(errorf(&format!(
+                "unsupported kind {}, expected {}",
+                k, KIND
+            )));
+        }
+        Ok(())
+    }
 }
 
 impl ComponentLabels {
-    pub fn to_map(&self) -> HashMap<&str, &str> {
-        let mut m = HashMap::from_iter(self.extra.iter().map(|s| (s.0.as_str(), s.1.as_str())));
+    pub fn to_map(&self) -> BTreeMap<&str, &str> {
+        let mut m = BTreeMap::from_iter(self.extra.iter().map(|s| (s.0.as_str(), s.1.as_str())));
         m.insert("reference", self.reference.as_str());
         m.insert("footprintLibrary", self.footprint_library.as_str());
         m.insert("footprintName", self.footprint_name.as_str());
@@ -110,6 +204,10 @@ pub struct Attribute {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     pub comment: Option<String>,
+    // How the value is written, e.g. "rkm" or "digits=4" (see eval::Format)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub format: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -156,8 +254,18 @@ impl From<f64> for Value {
     }
 }
 
-// A vector of Attributes implements the Labels trait
-impl Labels for HashMap<String, Attribute> {
+// A Component implements the Labels trait by looking up the key in its attributes,
+// and then in its labels
+impl Labels for Component {
+    fn get_label(&self, key: &str) -> Option<String> {
+        self.attributes
+            .get_label(key)
+            .or_else(|| self.labels.to_map().get_label(key))
+    }
+}
+
+// A map of Attributes implements the Labels trait
+impl Labels for BTreeMap<String, Attribute> {
     fn get_label(&self, key: &str) -> Option<String> {
         self.get(key).map(|a| a.value.to_string())
     }
@@ -0,0 +1,196 @@
+use crate::error::{errorf, DynamicResult};
+use crate::eval::sheet_name;
+use crate::parser::is_check;
+use crate::types::{Component, ComponentVariant, Schematic};
+use std::collections::BTreeSet;
+
+// The variant with all components that aren't marked DNP, and no expression overrides
+pub const DEFAULT_VARIANT: &str = "default";
+
+impl Schematic {
+    // List the names of all variants defined by the components in the schematic hierarchy
+    pub fn variants(&self) -> BTreeSet<String> {
+        let mut variants = BTreeSet::new();
+        self.collect_variants(&mut variants);
+        variants
+    }
+
+    fn collect_variants(&self, variants: &mut BTreeSet<String>) {
+        for component in self.components.values() {
+            variants.extend(component.labels.variants.keys().cloned());
+        }
+        for sub_schematic in self.sub_schematics.values() {
+            sub_schematic.collect_variants(variants);
+        }
+    }
+
+    // Select the given assembly variant: the expressions of the components are replaced with
+    // those the variant overrides, and the components not populated in it are removed
+    pub fn apply_variant(&mut self, variant: &str) -> DynamicResult<()> {
+        self.override_expressions(variant)?;
+        self.remove_unpopulated(variant);
+        Ok(())
+    }
+
+    // Replace the expressions of attributes and checks with those the given variant
+    // overrides them with. The name of the variant is recorded in the meta of the
+    // schematic, so that the result isn't mistaken for the schematic itself.
+    pub fn override_expressions(&mut self, variant: &str) -> DynamicResult<()> {
+        if !variant.eq_ignore_ascii_case(DEFAULT_VARIANT)
+            && !self
+                .variants()
+                .iter()
+                .any(|v| v.eq_ignore_ascii_case(variant))
+        {
+            return Err(errorf(&format!("unknown variant: {}", variant)));
+        }
+
+        self.override_expressions_in(variant)?;
+        self.meta.variant = Some(variant.into());
+        Ok(())
+    }
+
+    fn override_expressions_in(&mut self, variant: &str) -> DynamicResult<()> {
+        for component in self.components.values_mut() {
+            let overrides = match component_variant(component, variant) {
+                Some(v) => v.expressions.clone(),
+                None => continue,
+            };
+            for (name, expression) in overrides {
+                let target = if is_check(&name) {
+                    &mut component.checks
+                } else {
+                    &mut component.attributes
+                };
+                match target.get_mut(&name) {
+                    Some(attribute) => attribute.expression = expression,
+                    None => {
+                        return Err(errorf(&format!(
+                            "{}: variant {} overrides unknown attribute {}",
+                            component.labels.reference, variant, name
+                        )))
+                    }
+                }
+            }
+        }
+
+        for sub_schematic in self.sub_schematics.values_mut() {
+            sub_schematic.override_expressions_in(variant)?;
+        }
+        Ok(())
+    }
+
+    // Remove the components that aren't populated in the given variant. Returns the paths of
+    // the removed components (e.g. "PowerSupply.R5"), in the same form as evaluation paths.
+    pub fn remove_unpopulated(&mut self, variant: &str) -> Vec<String> {
+        let mut removed = Vec::new();
+        self.remove_unpopulated_in(variant, &mut vec![], &mut removed);
+        removed
+    }
+
+    fn remove_unpopulated_in(
+        &mut self,
+        variant: &str,
+        prefix: &mut Vec<String>,
+        removed: &mut Vec<String>,
+    ) {
+        let unpopulated: Vec<String> = self
+            .components
+            .iter()
+            .filter(|(_, c)| !is_populated(c, variant))
+            .map(|(r, _)| r.clone())
+            .collect();
+        for reference in unpopulated {
+            self.components.remove(&reference);
+            prefix.push(reference);
+            removed.push(prefix.join("."));
+            prefix.pop();
+        }
+
+        for (id, sub_schematic) in self.sub_schematics.iter_mut() {
+            prefix.push(sheet_name(id, sub_schematic).into());
+            sub_schematic.remove_unpopulated_in(variant, prefix, removed);
+            prefix.pop();
+        }
+    }
+}
+
+// is_populated returns true if the component is fitted in the given variant. The DNP
+// label applies to all variants, unless a variant explicitly marks the component fitted.
+pub fn is_populated(component: &Component, variant: &str) -> bool {
+    let dnp = component_variant(component, variant)
+        .and_then(|v| v.dnp)
+        .unwrap_or(component.labels.dnp);
+    !dnp
+}
+
+fn component_variant<'c>(component: &'c Component, variant: &str) -> Option<&'c ComponentVariant> {
+    component
+        .labels
+        .variants
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(variant))
+        .map(|(_, v)| v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::evaluate_variant;
+    use crate::project::Project;
+    use crate::testutil::{attribute, component};
+
+    fn schematic() -> Schematic {
+        let mut r1 = component("R1", vec![("Value", attribute("0", Some("Ohm")))]);
+        r1.labels.dnp = true;
+        r1.labels.variants.insert(
+            "Shunt".into(),
+            ComponentVariant {
+                dnp: Some(false),
+                expressions: vec![("Value".into(), "0.01".into())].into_iter().collect(),
+            },
+        );
+        let mut r2 = component("R2", vec![("Value", attribute("0", Some("Ohm")))]);
+        r2.labels.variants.insert(
+            "Shunt".into(),
+            ComponentVariant {
+                dnp: Some(true),
+                ..Default::default()
+            },
+        );
+        crate::testutil::schematic(vec![r1, r2])
+    }
+
+    #[test]
+    fn select_variant() {
+        let mut sch = schematic();
+        sch.apply_variant("shunt").unwrap();
+        assert_eq!(sch.components.keys().collect::<Vec<_>>(), vec!["R1"]);
+        assert_eq!(sch.components["R1"].attributes["Value"].expression, "0.01");
+        assert_eq!(sch.meta.variant.as_deref(), Some("shunt"));
+
+        let mut sch = schematic();
+        assert_eq!(sch.remove_unpopulated(DEFAULT_VARIANT), vec!["R1"]);
+        assert_eq!(sch.components["R2"].attributes["Value"].expression, "0");
+
+        let err = schematic().apply_variant("Bypass").err().unwrap();
+        assert_eq!(err.to_string(), "unknown variant: Bypass");
+    }
+
+    #[test]
+    fn unpopulated_sub_sheet() {
+        // Paths name the sub-sheet after its file, not after the sheet
+        let mut r3 = component("R3", vec![("Value", attribute("0", Some("Ohm")))]);
+        r3.labels.dnp = true;
+        r3.checks
+            .insert("Check".into(), attribute("R3.Value > 1", None));
+        let mut sub_sch = crate::testutil::schematic(vec![r3]);
+        sub_sch.meta.filename = Some("supply.kicad_sch".into());
+        let mut sch = schematic();
+        sch.sub_schematics.insert("Power".into(), sub_sch);
+
+        let failed = evaluate_variant(&mut sch, &Project::default(), DEFAULT_VARIANT).unwrap();
+        assert!(failed.is_empty(), "{:?}", failed);
+        assert!(sch.sub_schematics["Power"].components.is_empty());
+    }
+}
@@ -0,0 +1,86 @@
+use crate::error::DynamicResult;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+
+// How often the watched files are polled for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// How long the watched files need to stay unchanged after a change before they're
+// considered saved. KiCad writes the schematic files one by one, and a file
+// might be read while it's only partially written otherwise.
+const SETTLE_INTERVAL: Duration = Duration::from_millis(300);
+
+// Snapshot records the modification time and size of a set of files.
+// Files that don't exist (yet) are recorded as None.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot(BTreeMap<PathBuf, Option<(SystemTime, u64)>>);
+
+impl Snapshot {
+    pub fn take(paths: &[PathBuf]) -> Self {
+        Self(
+            paths
+                .iter()
+                .map(|p| {
+                    let state = fs::metadata(p)
+                        .and_then(|m| Ok((m.modified()?, m.len())))
+                        .ok();
+                    (p.clone(), state)
+                })
+                .collect(),
+        )
+    }
+
+    // Take a new snapshot of the same files
+    pub fn retake(&self) -> Self {
+        Self::take(&self.0.keys().cloned().collect::<Vec<_>>())
+    }
+
+    // Wait until any of the files changes, and then until all of them have settled
+    pub fn wait_for_change(&self) -> Self {
+        let mut current = self.retake();
+        while &current == self {
+            sleep(POLL_INTERVAL);
+            current = self.retake();
+        }
+
+        loop {
+            sleep(SETTLE_INTERVAL);
+            let next = current.retake();
+            if next == current {
+                return next;
+            }
+            current = next;
+        }
+    }
+}
+
+// watch calls run, which returns the files it read, and then calls it again each time any of
+// those files (or the given extra files) changes. Errors are reported without stopping, as the
+// files may be e.g. temporarily invalid. If run fails, it's retried on the next change of the
+// files as they were before the failed run, so that changes made during a run aren't lost.
+pub fn watch<F>(extra_paths: &[PathBuf], mut run: F) -> DynamicResult<()>
+where
+    F: FnMut() -> DynamicResult<Vec<PathBuf>>,
+{
+    let mut paths = extra_paths.to_vec();
+    loop {
+        let before = Snapshot::take(&paths);
+        let snapshot = match run() {
+            Ok(read_paths) => {
+                paths = extra_paths.iter().cloned().chain(read_paths).collect();
+                paths.sort();
+                paths.dedup();
+                Snapshot::take(&paths)
+            }
+            Err(e) => {
+                eprintln!("error: {}", e);
+                before
+            }
+        };
+
+        eprintln!("watching {} file(s) for changes...", paths.len());
+        snapshot.wait_for_change();
+    }
+}